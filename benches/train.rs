@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use logu::drain::Drain;
+
+/// Status-like logs where most lines consist of a single token.
+fn single_token_logs(n: usize, distinct: usize) -> Vec<String> {
+    (0..n).map(|i| format!("STATUS_{}", i % distinct)).collect()
+}
+
+fn bench_single_token(c: &mut Criterion) {
+    let mut group = c.benchmark_group("train/single_token");
+    for distinct in [10, 100, 1000] {
+        let logs = single_token_logs(10_000, distinct);
+        group.bench_with_input(BenchmarkId::from_parameter(distinct), &logs, |b, logs| {
+            b.iter(|| {
+                let mut drain = Drain::default();
+                for log in logs {
+                    drain.train(log);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Wide logs sharing their first token, so that all clusters
+/// end up as candidates in the same node of the prefix tree.
+fn wide_logs(n: usize, distinct: usize, width: usize) -> Vec<String> {
+    (0..n)
+        .map(|i| {
+            let k = i % distinct;
+            std::iter::once("request".to_string())
+                .chain((1..width).map(|j| format!("k{}w{}", k, j)))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect()
+}
+
+fn bench_wide(c: &mut Criterion) {
+    let mut group = c.benchmark_group("train/wide");
+    for distinct in [10, 100] {
+        let logs = wide_logs(2_000, distinct, 40);
+        group.bench_with_input(BenchmarkId::from_parameter(distinct), &logs, |b, logs| {
+            b.iter(|| {
+                let mut drain = Drain::default();
+                for log in logs {
+                    drain.train(log);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_single_token, bench_wide);
+criterion_main!(benches);
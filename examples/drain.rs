@@ -1,4 +1,4 @@
-use logu::drain::Drain;
+use logu::Drain;
 
 fn main() {
     let logs = vec![
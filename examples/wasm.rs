@@ -0,0 +1,15 @@
+use logu::wasm::WasmDrain;
+
+fn main() {
+    let logs = vec![
+        "connected to 10.0.0.1",
+        "connected to 10.0.0.2",
+        "user davidoh logged in",
+        "user eranr logged in",
+    ];
+    let mut drain = WasmDrain::new(None, 2, 0.4, 100, "<*>".to_string()).unwrap();
+    for log in logs {
+        println!("{}", drain.train(log));
+    }
+    println!("{}", drain.clusters_json());
+}
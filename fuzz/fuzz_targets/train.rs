@@ -0,0 +1,85 @@
+//! Train a `Drain` on arbitrary lines and check that it neither panics
+//! nor loses track of its clusters.
+//!
+//! The first two bytes pick the settings; the rest is split into lines
+//! decoded lossily from UTF-8.
+//!
+//! ```bash
+//! cargo +nightly fuzz run train fuzz/corpus/train
+//! ```
+
+#![no_main]
+
+use std::collections::HashSet;
+
+use libfuzzer_sys::fuzz_target;
+use logu::drain::{Drain, PunctuationPolicy, TokenClass};
+
+fn drain(flags: u8, sizes: u8) -> Drain {
+    let max_clusters = (flags & 1 != 0).then_some(usize::from(sizes & 3) + 1);
+    let punctuation = if flags & 2 != 0 {
+        PunctuationPolicy::Strip
+    } else {
+        PunctuationPolicy::Keep
+    };
+    let Ok(mut drain) = Drain::new(
+        max_clusters,
+        usize::from(sizes >> 2 & 7),
+        f32::from(sizes >> 5) / 7.0,
+        usize::from(sizes & 3) + 1,
+        String::from("<*>"),
+    ) else {
+        return Drain::default();
+    };
+    drain = drain
+        .with_max_tokens((flags & 4 != 0).then_some(8))
+        .with_min_distinct_values((flags & 8 != 0).then_some(2))
+        .with_typed_params(flags & 16 != 0)
+        .with_tab_delimited(flags & 32 != 0)
+        .with_punctuation(punctuation)
+        .with_mixed_lengths(flags & 64 != 0)
+        .with_dedup_tokens(flags & 128 != 0);
+    if flags & 64 != 0 && sizes & 1 != 0 {
+        drain = drain.with_trailing_penalty(0.5).unwrap();
+    }
+    if sizes & 2 != 0 {
+        for class in [TokenClass::Decimal, TokenClass::Hex, TokenClass::Id] {
+            drain = drain
+                .with_token_class(class, class.default_wildcard())
+                .unwrap();
+        }
+    }
+    drain
+}
+
+fuzz_target!(|data: &[u8]| {
+    let [flags, sizes, lines @ ..] = data else {
+        return;
+    };
+    let mut drain = drain(*flags, *sizes);
+    for line in lines.split(|b| *b == b'\n') {
+        let line = String::from_utf8_lossy(line);
+        let cluster = drain.train(&line);
+        assert!(
+            drain
+                .clusters()
+                .iter()
+                .any(|c| c.cluster_id == cluster.cluster_id),
+            "trained cluster {} is missing",
+            cluster.cluster_id
+        );
+        drain.match_all(&line, 0.0);
+        drain.explain_match(&line);
+    }
+
+    // Evicted clusters may leave stale ids in the tree; otherwise every id must exist.
+    if drain.config().max_clusters.is_none() {
+        let ids: HashSet<usize> = drain.clusters().iter().map(|c| c.cluster_id).collect();
+        drain.walk_tree(|_, _, cluster_ids| {
+            for cluster_id in cluster_ids {
+                assert!(ids.contains(cluster_id), "tree has unknown cluster {}", cluster_id);
+            }
+        });
+    }
+    drain.to_dot();
+});
@@ -1,33 +1,569 @@
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::{Debug, Display},
     num::NonZeroUsize,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use lru::LruCache;
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+mod builder;
+pub use builder::DrainBuilder;
+mod config;
+pub use config::DrainConfig;
+mod error;
+pub use error::DrainError;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "async")]
+mod shared;
+#[cfg(feature = "async")]
+pub use shared::SharedDrain;
+
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LogCluster {
     log_template_tokens: Vec<String>,
     pub cluster_id: usize,
     pub size: usize,
+    /// Distinct literal values seen at each position of the template,
+    /// tracked only when `min_distinct_values` is set.
+    /// Positions already replaced with `param_str` are no longer tracked.
+    #[cfg_attr(feature = "serde", serde(default))]
+    position_values: Vec<HashSet<String>>,
+    /// Lines per second as of `last_arrival`, see `rate`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    rate: f64,
+    /// Time of the last line recorded with `Drain::record_arrival`, in seconds.
+    #[cfg_attr(feature = "serde", serde(default))]
+    last_arrival: Option<f64>,
+    /// Number of recorded lines per time bucket, oldest first, with a window set.
+    #[cfg_attr(feature = "serde", serde(default))]
+    window_counts: VecDeque<(u64, usize)>,
+    /// Shape forced by a rule of `Drain::with_shape_rule`, see `shape`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    shape: Option<String>,
+    /// Note of the user, see `label`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    label: Option<String>,
+}
+
+impl LogCluster {
+    /// Id of the cluster, see `IdStrategy`.
+    pub fn id(&self) -> usize {
+        self.cluster_id
+    }
+
+    /// Number of log messages of the cluster.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Tokens of the template, with `param_str` where they vary.
+    pub fn tokens(&self) -> &[String] {
+        &self.log_template_tokens
+    }
+
+    /// Join the template tokens with `separator`.
+    /// `Display` joins them with a single space.
+    pub fn template(&self, separator: &str) -> String {
+        self.log_template_tokens.join(separator)
+    }
+
+    /// Recent lines per second, as of the last line recorded with `Drain::train_at`
+    /// or `Drain::record_arrival`. Each line counts for `1 / RATE_TIME_CONSTANT_SECS`
+    /// and decays exponentially with that time constant, so bursts stand out
+    /// even for clusters of modest size. `0.0` if no arrival was recorded.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Shape of the log messages of this cluster, if a rule of `Drain::with_shape_rule`
+    /// matched them: the index of the rule and the matched text, e.g. `0:req-get`.
+    pub fn shape(&self) -> Option<&str> {
+        self.shape.as_deref()
+    }
+
+    /// Label given by the user for triage, e.g. `known noise`.
+    /// It is kept with the model but never affects clustering.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Set or clear the label, see `label`. For clusters of a `Drain`,
+    /// use `Drain::set_label` since `clusters` only lends them.
+    pub fn set_label(&mut self, label: Option<String>) {
+        self.label = label;
+    }
+
+    /// `rate` decayed until `now`, in the same seconds as the recorded arrivals,
+    /// so that clusters which stopped receiving lines slow down.
+    pub fn rate_at(&self, now: f64) -> f64 {
+        match self.last_arrival {
+            Some(last_arrival) => {
+                self.rate * (-(now - last_arrival).max(0.0) / RATE_TIME_CONSTANT_SECS).exp()
+            }
+            None => 0.0,
+        }
+    }
+
+    fn record_arrival(&mut self, at: f64, bucket: Option<u64>) {
+        self.rate = self.rate_at(at) + 1.0 / RATE_TIME_CONSTANT_SECS;
+        self.last_arrival = Some(self.last_arrival.map_or(at, |last| last.max(at)));
+        if let Some(bucket) = bucket {
+            // A line older than the last bucket is counted in it.
+            match self.window_counts.back_mut() {
+                Some((last, count)) if *last >= bucket => *count += 1,
+                _ => self.window_counts.push_back((bucket, 1)),
+            }
+        }
+    }
+
+    /// Add the arrivals of `other` merged into this cluster, and its label if this has none.
+    fn absorb(&mut self, other: &LogCluster) {
+        match (self.last_arrival, other.last_arrival) {
+            (_, None) => {}
+            (None, Some(_)) => {
+                self.rate = other.rate;
+                self.last_arrival = other.last_arrival;
+            }
+            (Some(last), Some(other_last)) => {
+                let at = last.max(other_last);
+                self.rate = self.rate_at(at) + other.rate_at(at);
+                self.last_arrival = Some(at);
+            }
+        }
+        let mut window_counts: BTreeMap<u64, usize> = self.window_counts.drain(..).collect();
+        for &(bucket, count) in &other.window_counts {
+            *window_counts.entry(bucket).or_default() += count;
+        }
+        self.window_counts = window_counts.into_iter().collect();
+        if self.label.is_none() {
+            self.label = other.label.clone();
+        }
+    }
+
+    /// Whether any token of the template is `param_str`,
+    /// i.e. the cluster has generalized rather than stayed a literal line.
+    /// See `Drain::is_wildcarded` to count typed and token class wildcards too.
+    pub fn is_wildcarded(&self, param_str: &str) -> bool {
+        self.log_template_tokens
+            .iter()
+            .any(|token| token == param_str)
+    }
 }
 
 impl Display for LogCluster {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.log_template_tokens.join(" "))
+        write!(f, "{}", self.template(" "))
+    }
+}
+
+/// How punctuation such as `(`, `,` or `:` in tokens is treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PunctuationPolicy {
+    /// Leave tokens as they are.
+    #[default]
+    Keep,
+    /// Remove punctuation surrounding each token, e.g. `(id=5),` becomes `id=5`.
+    Strip,
+    /// Make each punctuation a token of its own, e.g. `error(42)` becomes `error ( 42 )`.
+    Split,
+}
+
+/// Part of a log message a rule of `Drain::with_shape_rule` is matched against.
+#[cfg(feature = "regex")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShapeScope {
+    /// The first token.
+    FirstToken,
+    /// The whole log message.
+    Line,
+}
+
+/// Cleanup of raw log lines, see `Drain::with_normalization`.
+/// The default leaves lines untouched.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Normalization {
+    /// Remove ANSI escape sequences such as colors.
+    /// Requires the `ansi` feature and is ignored without it.
+    pub strip_ansi: bool,
+    /// Replace control characters such as `\r`, `\n` or `\t` with a space.
+    /// Tabs are kept when `keep_tabs` is passed to `apply`.
+    pub replace_controls: bool,
+}
+
+impl Normalization {
+    /// Strip ANSI escapes and replace control characters everywhere, as the CLI does.
+    pub const ALL: Self = Self {
+        strip_ansi: true,
+        replace_controls: true,
+    };
+
+    /// Clean up `line`. With `keep_tabs`, tabs are left in place for tab-delimited logs.
+    pub fn apply(&self, line: &str, keep_tabs: bool) -> String {
+        self.apply_cow(line, keep_tabs).into_owned()
+    }
+
+    /// `apply` without copying `line` when nothing is to be done.
+    fn apply_cow<'a>(&self, line: &'a str, keep_tabs: bool) -> Cow<'a, str> {
+        // Escapes are left to `strip_ansi`, which needs them to find the sequences.
+        let strip_ansi = cfg!(feature = "ansi") && self.strip_ansi;
+        let is_replaced =
+            |c: char| c.is_control() && !(keep_tabs && c == '\t') && !(strip_ansi && c == '\x1b');
+        let mut line = Cow::Borrowed(line);
+        if self.replace_controls && line.contains(is_replaced) {
+            line = Cow::Owned(line.replace(is_replaced, " "));
+        }
+        #[cfg(feature = "ansi")]
+        if strip_ansi {
+            // Strip each column on its own, since `strip_str` drops tabs as well.
+            line = Cow::Owned(
+                line.split('\t')
+                    .map(strip_ansi_escapes::strip_str)
+                    .collect::<Vec<_>>()
+                    .join("\t"),
+            );
+        }
+        line
+    }
+}
+
+impl FromStr for PunctuationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "strip" => Ok(Self::Strip),
+            "split" => Ok(Self::Split),
+            _ => Err(format!(
+                "expected one of keep, strip, split, but got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// How ids of new clusters are assigned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IdStrategy {
+    /// Number clusters 1, 2, 3, ... in order of creation.
+    #[default]
+    Counter,
+    /// Derive the id from a hash of the tokens the cluster is created from,
+    /// so that the same logs get the same ids across runs.
+    /// On a collision with a live cluster the next free id is taken,
+    /// so ids are only reproducible as long as no collision occurred.
+    TemplateHash,
+}
+
+impl FromStr for IdStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "counter" => Ok(Self::Counter),
+            "template-hash" => Ok(Self::TemplateHash),
+            _ => Err(format!(
+                "expected one of counter, template-hash, but got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Which cluster `train` picks among candidates with the same similarity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TieBreak {
+    /// The template with more wildcards, i.e. the more general one.
+    #[default]
+    MoreParams,
+    /// The template with fewer wildcards, i.e. the more specific one.
+    FewerParams,
+    /// The cluster with more log messages.
+    LargerCluster,
+    /// The cluster created first (with `IdStrategy::Counter`).
+    SmallerId,
+}
+
+impl TieBreak {
+    /// Whether `cluster` with `param_count` wildcards is preferred over `best`.
+    fn prefers(
+        self,
+        (cluster, param_count): (&LogCluster, isize),
+        (best, best_param_count): (&LogCluster, isize),
+    ) -> bool {
+        match self {
+            Self::MoreParams => param_count > best_param_count,
+            Self::FewerParams => param_count < best_param_count,
+            Self::LargerCluster => cluster.size > best.size,
+            Self::SmallerId => cluster.cluster_id < best.cluster_id,
+        }
+    }
+}
+
+impl FromStr for TieBreak {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "more-params" => Ok(Self::MoreParams),
+            "fewer-params" => Ok(Self::FewerParams),
+            "larger-cluster" => Ok(Self::LargerCluster),
+            "smaller-id" => Ok(Self::SmallerId),
+            _ => Err(format!(
+                "expected one of more-params, fewer-params, larger-cluster, smaller-id, but got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// How `Drain::template` shows runs of consecutive `param_str` tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WildcardRuns {
+    /// Show every token, e.g. `a <*> <*> b`.
+    #[default]
+    Keep,
+    /// Show a run as a single wildcard, e.g. `a <*> b`.
+    Collapse,
+    /// Show a run as a single wildcard with its length, e.g. `a <*>{2} b`.
+    Count,
+}
+
+impl FromStr for WildcardRuns {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "collapse" => Ok(Self::Collapse),
+            "count" => Ok(Self::Count),
+            _ => Err(format!(
+                "expected one of keep, collapse, count, but got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Kind of token given a wildcard of its own by `Drain::with_token_class`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TokenClass {
+    /// Decimal numbers, e.g. `12345` or `-1.5`.
+    Decimal,
+    /// Hexadecimal numbers prefixed with `0x`, e.g. `0xDEAD`.
+    Hex,
+    /// Other tokens mixing letters and digits, e.g. `req-ab12`.
+    Id,
+}
+
+impl TokenClass {
+    /// Classify the token, or return `None` if it is none of the classes,
+    /// e.g. a word or an IP address.
+    pub fn of(token: &str) -> Option<Self> {
+        let hex_digits = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"));
+        if hex_digits.is_some_and(|digits| {
+            !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit())
+        }) {
+            Some(Self::Hex)
+        } else if is_number(token) {
+            Some(Self::Decimal)
+        } else if token.chars().any(|c| c.is_ascii_digit())
+            && token.chars().any(char::is_alphabetic)
+        {
+            Some(Self::Id)
+        } else {
+            None
+        }
+    }
+
+    /// Wildcard suggested for the class: `<NUM>`, `<HEX>` or `<ID>`.
+    pub fn default_wildcard(self) -> &'static str {
+        match self {
+            Self::Decimal => NUM_PARAM_STR,
+            Self::Hex => "<HEX>",
+            Self::Id => "<ID>",
+        }
+    }
+}
+
+impl FromStr for TokenClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "decimal" => Ok(Self::Decimal),
+            "hex" => Ok(Self::Hex),
+            "id" => Ok(Self::Id),
+            _ => Err(format!("expected one of decimal, hex, id, but got {:?}", s)),
+        }
+    }
+}
+
+/// Wildcard of the class of `token` among `token_classes`, if any.
+fn class_wildcard<'a>(token_classes: &'a [(TokenClass, String)], token: &str) -> Option<&'a str> {
+    let class = TokenClass::of(token)?;
+    token_classes
+        .iter()
+        .find(|(c, _)| *c == class)
+        .map(|(_, wildcard)| wildcard.as_str())
+}
+
+/// 64-bit FNV-1a hash of the tokens.
+/// Unlike `DefaultHasher`, the result is the same across Rust versions and platforms.
+fn hash_tokens(tokens: &[String]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let mut hash = OFFSET_BASIS;
+    for token in tokens {
+        // 0xff never appears in UTF-8, so it separates tokens unambiguously.
+        for byte in token.bytes().chain([0xff]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    hash
+}
+
+/// What `train` did with a log message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrainOutcome {
+    /// A new cluster was created.
+    Created,
+    /// An existing cluster matched and its template changed.
+    TemplateChanged,
+    /// An existing cluster matched as is.
+    Matched,
+}
+
+/// Owned result of `train_with_info`.
+/// It does not borrow `Drain`, so it can be sent over a channel
+/// while the drain keeps being trained.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrainInfo {
+    pub cluster_id: usize,
+    pub outcome: TrainOutcome,
+    pub template: String,
+    pub size: usize,
+    /// Template before this message, if `outcome` is `TemplateChanged`.
+    pub previous_template: Option<String>,
+}
+
+/// How well the templates cover a set of log messages, returned by `coverage`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CoverageReport {
+    /// Number of log messages matching a template.
+    pub matched: usize,
+    /// Number of log messages matching none.
+    pub unmatched: usize,
+    /// Number of log messages matched per cluster id, for the clusters matched at least once.
+    pub matches: BTreeMap<usize, usize>,
+}
+
+impl CoverageReport {
+    /// Fraction of the log messages matching a template, `None` without log messages.
+    pub fn ratio(&self) -> Option<f64> {
+        let total = self.matched + self.unmatched;
+        (total > 0).then(|| self.matched as f64 / total as f64)
     }
 }
 
+/// Route of a log message through the prefix tree, returned by `explain_match`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MatchExplanation {
+    /// Tokens of the log message, as trained.
+    pub tokens: Vec<String>,
+    /// Key of the first level of the tree: the token count, or `MIXED_LENGTHS_KEY`.
+    pub bucket: String,
+    /// Key followed at each depth below the token-count level:
+    /// the token itself, or `param_str` where no child had it.
+    pub path: Vec<String>,
+    /// Whether the route reached a leaf. If not, it stopped after `path`
+    /// since neither the token nor `param_str` had a child there.
+    pub reached_leaf: bool,
+    /// Ids and similarities of the clusters compared at the leaf.
+    /// Clusters of another facility are left out with `with_facility`.
+    pub candidates: Vec<(usize, f32)>,
+    /// Id and similarity of the matching cluster, `None` if none reached `sim_th`.
+    pub matched: Option<(usize, f32)>,
+}
+
 #[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Node {
     key_to_child_node: HashMap<String, Node>,
     cluster_ids: Vec<usize>,
+    /// Whether a token had to go to the `param_str` child
+    /// because the node reached `max_children`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    saturated: bool,
+}
+
+impl Node {
+    /// Mark the node as saturated, returning whether it was not yet.
+    fn saturate(&mut self) -> bool {
+        !std::mem::replace(&mut self.saturated, true)
+    }
+
+    /// Drop the cluster ids rejected by `keep` from this subtree, and the children left
+    /// without clusters, counting the saturated ones in `removed_saturated`.
+    /// Return whether this node is left without clusters.
+    fn scrub(&mut self, keep: &impl Fn(&usize) -> bool, removed_saturated: &mut usize) -> bool {
+        self.cluster_ids.retain(keep);
+        self.key_to_child_node.retain(|_, child| {
+            let empty = child.scrub(keep, removed_saturated);
+            if empty && child.saturated {
+                *removed_saturated += 1;
+            }
+            !empty
+        });
+        self.cluster_ids.is_empty() && self.key_to_child_node.is_empty()
+    }
+
+    /// Append the cluster ids of this subtree to `ids`.
+    fn collect_cluster_ids(&self, ids: &mut Vec<usize>) {
+        ids.extend(&self.cluster_ids);
+        for child in self.key_to_child_node.values() {
+            child.collect_cluster_ids(ids);
+        }
+    }
+
+    /// Count the saturated nodes in this subtree.
+    #[cfg(feature = "serde")]
+    fn count_saturated(&self) -> usize {
+        self.saturated as usize
+            + self
+                .key_to_child_node
+                .values()
+                .map(Node::count_saturated)
+                .sum::<usize>()
+    }
 }
 
 pub struct Drain {
     id_to_cluster: LruCache<usize, LogCluster>,
 
+    /// Depth of the prefix tree below the token-count level, counting the leaf.
+    /// The first `max_node_depth - 1` tokens are used as keys,
+    /// so `0` and `1` both match on the token count only, and `2` adds the first token.
+    /// This is Drain3's `depth - 2`, where `depth` also counts the root and the leaf.
     max_node_depth: usize,
 
     /// Similarity threshold.
@@ -35,6 +571,11 @@ pub struct Drain {
     /// if the similarity of tokens for log message is below this.
     sim_th: f32,
 
+    /// Similarity required for a matching log message to update the template.
+    /// Messages matching below this only add to the size of the cluster.
+    /// If `None`, every match may update the template.
+    update_th: Option<f32>,
+
     /// Maximum number of children within a node.
     max_children: usize,
 
@@ -42,7 +583,117 @@ pub struct Drain {
 
     root: Node,
 
+    /// Cluster id by token for clusters of single-token logs,
+    /// to find them without scanning the whole `"1"` bucket.
+    single_token_clusters: HashMap<String, usize>,
+
     param_str: String,
+
+    /// Maximum number of tokens taken from a log message.
+    /// Longer messages are truncated and suffixed with `TRUNCATED_STR`.
+    max_tokens: Option<usize>,
+
+    /// Number of distinct values required at a position
+    /// before it is replaced with `param_str`.
+    /// If `None`, any single differing value wildcards the position.
+    min_distinct_values: Option<usize>,
+
+    /// Use `NUM_PARAM_STR` instead of `param_str`
+    /// for positions where only numbers vary.
+    typed_params: bool,
+
+    /// Split messages containing tabs by tabs only, for TSV-style logs.
+    tab_delimited: bool,
+
+    /// How punctuation in tokens is treated.
+    punctuation: PunctuationPolicy,
+
+    /// Maximum sum of template token counts over all clusters.
+    /// The least recently used clusters are evicted when it is exceeded.
+    max_total_tokens: Option<usize>,
+
+    /// Sum of template token counts over all clusters.
+    total_tokens: usize,
+
+    /// Number of nodes that reached `max_children`.
+    saturated_nodes: usize,
+
+    /// Number of log messages trained, including those of evicted clusters.
+    total_lines: usize,
+
+    /// Number of log messages for which `match_log` found no cluster.
+    /// Atomic since `match_log` only borrows the drain.
+    unmatched_count: AtomicUsize,
+
+    /// Tokens for which this returns `true` are compared case-insensitively.
+    case_insensitive: Option<TokenPredicate>,
+
+    /// Separator joining template tokens in `template`, `summaries` and `TrainInfo`.
+    separator: String,
+
+    /// How ids of new clusters are assigned.
+    id_strategy: IdStrategy,
+
+    /// Which cluster is picked among equally similar candidates.
+    tie_break: TieBreak,
+
+    /// How `template` shows runs of wildcards.
+    wildcard_runs: WildcardRuns,
+
+    /// Keep a leading facility token such as `sshd:` literal,
+    /// so that log messages of different facilities never share a cluster.
+    facility: bool,
+
+    /// Length of the sliding window in seconds, see `with_window`.
+    window_secs: Option<u64>,
+
+    /// Split log messages by this instead of whitespace, see `with_delimiter_regex`.
+    #[cfg(feature = "regex")]
+    delimiter: Option<regex::Regex>,
+
+    /// Split log messages after any of these characters instead of whitespace,
+    /// see `with_delimiters`.
+    delimiters: Option<Vec<char>>,
+
+    /// Cleanup applied to log messages before tokenizing, see `with_normalization`.
+    normalization: Normalization,
+
+    /// Maximum length of log messages in bytes, see `with_max_line_bytes`.
+    max_line_bytes: Option<usize>,
+
+    /// Put log messages of any number of tokens under the same first level
+    /// of the prefix tree, see `with_mixed_lengths`.
+    mixed_lengths: bool,
+
+    /// Weight of the trailing tokens beyond the shorter sequence in the similarity,
+    /// see `with_trailing_penalty`. `None` counts them as fully dissimilar.
+    trailing_penalty: Option<f32>,
+
+    /// Collapse runs of identical consecutive tokens, see `with_dedup_tokens`.
+    dedup_tokens: bool,
+
+    /// Wildcards of the token classes, see `with_token_class`.
+    token_classes: Vec<(TokenClass, String)>,
+
+    /// Rules keeping log messages of distinct shapes apart, see `with_shape_rule`.
+    #[cfg(feature = "regex")]
+    shape_rules: Vec<(regex::Regex, ShapeScope)>,
+
+    /// Replacements applied to log messages before tokenizing, see `with_masks`.
+    #[cfg(feature = "regex")]
+    masks: Vec<(regex::Regex, String)>,
+}
+
+/// Predicate on a token, see `Drain::with_case_insensitive`.
+/// Shared so that clones of a `Drain` keep the same predicate.
+type TokenPredicate = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Key of `token` for comparison: lowercased if `case_insensitive` says so.
+fn case_key<'a>(case_insensitive: &Option<TokenPredicate>, token: &'a str) -> Cow<'a, str> {
+    match case_insensitive {
+        Some(predicate) if predicate(token) => Cow::Owned(token.to_lowercase()),
+        _ => Cow::Borrowed(token),
+    }
 }
 
 impl Debug for Drain {
@@ -91,16 +742,117 @@ impl Debug for Drain {
     }
 }
 
+impl Clone for Drain {
+    fn clone(&self) -> Self {
+        // `LruCache::clone` preallocates its capacity, which fails when unbounded,
+        // so rebuild it from the least to the most recently used cluster instead.
+        let mut id_to_cluster = if self.id_to_cluster.cap() == NonZeroUsize::MAX {
+            LruCache::unbounded()
+        } else {
+            LruCache::new(self.id_to_cluster.cap())
+        };
+        for (id, cluster) in self.id_to_cluster.iter().rev() {
+            id_to_cluster.put(*id, cluster.clone());
+        }
+
+        Self {
+            id_to_cluster,
+            max_node_depth: self.max_node_depth,
+            sim_th: self.sim_th,
+            update_th: self.update_th,
+            max_children: self.max_children,
+            cluster_counter: self.cluster_counter,
+            root: self.root.clone(),
+            single_token_clusters: self.single_token_clusters.clone(),
+            param_str: self.param_str.clone(),
+            max_tokens: self.max_tokens,
+            min_distinct_values: self.min_distinct_values,
+            typed_params: self.typed_params,
+            tab_delimited: self.tab_delimited,
+            punctuation: self.punctuation,
+            max_total_tokens: self.max_total_tokens,
+            total_tokens: self.total_tokens,
+            saturated_nodes: self.saturated_nodes,
+            total_lines: self.total_lines,
+            unmatched_count: AtomicUsize::new(self.unmatched_count()),
+            case_insensitive: self.case_insensitive.clone(),
+            separator: self.separator.clone(),
+            id_strategy: self.id_strategy,
+            tie_break: self.tie_break,
+            wildcard_runs: self.wildcard_runs,
+            facility: self.facility,
+            window_secs: self.window_secs,
+            #[cfg(feature = "regex")]
+            delimiter: self.delimiter.clone(),
+            normalization: self.normalization,
+            max_line_bytes: self.max_line_bytes,
+            mixed_lengths: self.mixed_lengths,
+            trailing_penalty: self.trailing_penalty,
+            dedup_tokens: self.dedup_tokens,
+            delimiters: self.delimiters.clone(),
+            token_classes: self.token_classes.clone(),
+            #[cfg(feature = "regex")]
+            shape_rules: self.shape_rules.clone(),
+            #[cfg(feature = "regex")]
+            masks: self.masks.clone(),
+        }
+    }
+}
+
+/// Default of `max_node_depth` used by `Drain::default` and the CLI.
+pub const DEFAULT_MAX_NODE_DEPTH: usize = 2;
+/// Default of `sim_th` used by `Drain::default` and the CLI.
+pub const DEFAULT_SIM_TH: f32 = 0.4;
+/// Default of `max_children` used by `Drain::default` and the CLI.
+pub const DEFAULT_MAX_CHILDREN: usize = 100;
+/// Default of `param_str` used by `Drain::default` and the CLI.
+pub const DEFAULT_PARAM_STR: &str = "<*>";
+/// Pattern of a wildcard for `Drain::to_regex`, a single token.
+#[cfg(feature = "regex")]
+pub const DEFAULT_PARAM_PATTERN: &str = r"\S+";
+
 impl Default for Drain {
     fn default() -> Self {
         Self {
             id_to_cluster: LruCache::unbounded(),
-            max_node_depth: 2,
-            sim_th: 0.4,
-            max_children: 100,
+            max_node_depth: DEFAULT_MAX_NODE_DEPTH,
+            sim_th: DEFAULT_SIM_TH,
+            max_children: DEFAULT_MAX_CHILDREN,
             cluster_counter: 0,
             root: Node::default(),
-            param_str: "<*>".to_string(),
+            single_token_clusters: HashMap::new(),
+            param_str: DEFAULT_PARAM_STR.to_string(),
+            max_tokens: None,
+            min_distinct_values: None,
+            typed_params: false,
+            tab_delimited: false,
+            punctuation: PunctuationPolicy::Keep,
+            max_total_tokens: None,
+            total_tokens: 0,
+            saturated_nodes: 0,
+            total_lines: 0,
+            unmatched_count: AtomicUsize::new(0),
+            case_insensitive: None,
+            separator: String::from(" "),
+            id_strategy: IdStrategy::Counter,
+            tie_break: TieBreak::MoreParams,
+            wildcard_runs: WildcardRuns::Keep,
+            update_th: None,
+            facility: false,
+            window_secs: None,
+            #[cfg(feature = "regex")]
+            delimiter: None,
+            normalization: Normalization::default(),
+            max_line_bytes: None,
+            mixed_lengths: false,
+            trailing_penalty: None,
+            dedup_tokens: false,
+            delimiters: None,
+            token_classes: Vec::new(),
+            #[cfg(feature = "regex")]
+            shape_rules: Vec::new(),
+            #[cfg(feature = "regex")]
+            masks: Vec::new(),
         }
     }
 }
@@ -112,9 +864,20 @@ impl Drain {
         sim_th: f32,
         max_children: usize,
         param_str: String,
-    ) -> anyhow::Result<Self> {
+    ) -> Result<Self, DrainError> {
+        if !(0.0..=1.0).contains(&sim_th) {
+            return Err(DrainError::InvalidSimThreshold(sim_th));
+        }
+        if param_str.is_empty() || param_str.contains(char::is_whitespace) {
+            return Err(DrainError::InvalidParamStr(param_str));
+        }
+        if max_children == 0 {
+            return Err(DrainError::ZeroMaxChildren);
+        }
         let id_to_cluster = match max_clusters {
-            Some(max_clusters) => LruCache::new(NonZeroUsize::new(max_clusters).unwrap()),
+            Some(max_clusters) => {
+                LruCache::new(NonZeroUsize::new(max_clusters).ok_or(DrainError::ZeroMaxClusters)?)
+            }
             None => LruCache::unbounded(),
         };
 
@@ -123,285 +886,3805 @@ impl Drain {
             max_node_depth,
             sim_th,
             max_children,
-            cluster_counter: 0,
-            root: Node::default(),
             param_str,
+            ..Self::default()
         })
     }
 
-    pub fn clusters(&self) -> Vec<&LogCluster> {
-        self.id_to_cluster.iter().map(|(_, v)| v).collect()
+    /// Truncate log messages longer than `max_tokens` tokens, see `TRUNCATED_STR`.
+    pub fn with_max_tokens(mut self, max_tokens: Option<usize>) -> Self {
+        self.max_tokens = max_tokens;
+        self
     }
 
-    pub fn train<T: AsRef<str>>(&mut self, log_message: T) -> LogCluster {
-        let tokens = tokenize(log_message.as_ref());
-        match self.tree_search(&tokens, self.sim_th, false) {
-            Some(mut match_cluster) => {
-                match_cluster.log_template_tokens =
-                    self.create_template(&tokens, &match_cluster.log_template_tokens);
-                match_cluster.size += 1;
-                self.id_to_cluster
-                    .put(match_cluster.cluster_id, match_cluster.clone());
-                match_cluster
-            }
-            None => {
-                self.cluster_counter += 1;
-                let mut match_cluster = LogCluster {
-                    log_template_tokens: tokens,
-                    cluster_id: self.cluster_counter,
-                    size: 1,
-                };
-                self.id_to_cluster
-                    .put(match_cluster.cluster_id, match_cluster.clone());
-                self.add_seq_to_prefix_tree(&mut match_cluster);
-                match_cluster
-            }
-        }
+    /// Replace a position of a template with `param_str` only once it has seen
+    /// `min_distinct_values` distinct values, rather than at the first differing one.
+    pub fn with_min_distinct_values(mut self, min_distinct_values: Option<usize>) -> Self {
+        self.min_distinct_values = min_distinct_values;
+        self
     }
 
-    fn tree_search(
-        &mut self,
-        tokens: &[String],
-        sim_th: f32,
-        include_params: bool,
-    ) -> Option<LogCluster> {
-        let token_count = tokens.len();
+    /// Use `NUM_PARAM_STR` instead of `param_str` for positions where only numbers vary.
+    pub fn with_typed_params(mut self, typed_params: bool) -> Self {
+        self.typed_params = typed_params;
+        self
+    }
 
-        let mut cur_node = self.root.key_to_child_node.get(&token_count.to_string())?;
-        if token_count == 0 {
-            return self.id_to_cluster.get(&cur_node.cluster_ids[0]).cloned();
-        }
+    /// Split log messages containing tabs by tabs only, for TSV-style logs.
+    pub fn with_tab_delimited(mut self, tab_delimited: bool) -> Self {
+        self.tab_delimited = tab_delimited;
+        self
+    }
 
-        let mut cur_node_depth = 1;
-        for token in tokens {
-            // At max depth.
-            if cur_node_depth == self.max_node_depth {
-                break;
-            }
+    /// Treat punctuation in tokens according to `punctuation`.
+    /// Set it before training, since it changes the tokens.
+    pub fn with_punctuation(mut self, punctuation: PunctuationPolicy) -> Self {
+        self.punctuation = punctuation;
+        self
+    }
 
-            // At last token.
-            if cur_node_depth == token_count {
-                break;
-            }
+    /// Evict the least recently used clusters while the templates total
+    /// more than `max_total_tokens` tokens.
+    pub fn with_max_total_tokens(mut self, max_total_tokens: Option<usize>) -> Self {
+        self.max_total_tokens = max_total_tokens;
+        self.evict_over_token_budget();
+        self
+    }
 
-            cur_node = cur_node
-                .key_to_child_node
-                .get(token)
-                .or_else(|| cur_node.key_to_child_node.get(&self.param_str))?;
+    /// Compare the tokens matching `predicate` case-insensitively,
+    /// e.g. hex numbers or log levels, while templates keep their original casing.
+    /// Set it before training, since it changes how the prefix tree is keyed.
+    /// It is not serialized and has to be set again after loading.
+    pub fn with_case_insensitive<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.case_insensitive = Some(Arc::new(predicate));
+        self.index_single_token_clusters();
+        self
+    }
 
-            cur_node_depth += 1;
-        }
-        self.fast_match(
-            &cur_node.cluster_ids.clone(),
-            tokens,
-            sim_th,
-            include_params,
-        )
+    /// Key the clusters of a single token by their first token as `train` does,
+    /// e.g. after loading or when `case_insensitive` changes.
+    fn index_single_token_clusters(&mut self) {
+        self.single_token_clusters = self
+            .id_to_cluster
+            .iter()
+            .filter(|(_, cluster)| cluster.log_template_tokens.len() == 1)
+            .map(|(cluster_id, cluster)| {
+                let key = case_key(&self.case_insensitive, &cluster.log_template_tokens[0]);
+                (key.into_owned(), *cluster_id)
+            })
+            .collect();
     }
 
-    fn fast_match(
-        &mut self,
-        cluster_ids: &[usize],
-        tokens: &[String],
-        sim_th: f32,
-        include_params: bool,
-    ) -> Option<LogCluster> {
-        let mut match_cluster = None;
-        let mut max_cluster = None;
+    /// Join templates with `separator` instead of a single space,
+    /// e.g. to feed them into tools expecting a specific delimiter.
+    pub fn with_separator<S: Into<String>>(mut self, separator: S) -> Self {
+        self.separator = separator.into();
+        self
+    }
 
-        let mut max_sim = -1.0;
-        let mut max_param_count = -1;
-        for id in cluster_ids {
-            let cluster = self.id_to_cluster.get(id).cloned();
-            if let Some(cluster) = cluster {
-                let (cur_sim, param_count) =
-                    self.get_seq_distance(tokens, &cluster.log_template_tokens, include_params);
-                if cur_sim > max_sim || (cur_sim == max_sim && param_count > max_param_count) {
-                    max_sim = cur_sim;
-                    max_param_count = param_count;
-                    max_cluster = Some(cluster);
-                }
-            }
+    /// Assign cluster ids according to `id_strategy`.
+    /// Set it before training, since existing ids are kept.
+    pub fn with_id_strategy(mut self, id_strategy: IdStrategy) -> Self {
+        self.id_strategy = id_strategy;
+        self
+    }
+
+    /// Pick among equally similar candidates according to `tie_break`.
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Show runs of consecutive `param_str` tokens in `template` according to `wildcard_runs`.
+    /// The clusters keep every token, so this does not affect matching,
+    /// and `LogCluster`'s `Display` still shows them all.
+    pub fn with_wildcard_runs(mut self, wildcard_runs: WildcardRuns) -> Self {
+        self.wildcard_runs = wildcard_runs;
+        self
+    }
+
+    /// Update templates only from matches at least `update_th` similar,
+    /// so that borderline matches join a cluster without generalizing its template.
+    /// `update_th` must be within `sim_th..=1.0`.
+    pub fn with_update_th(mut self, update_th: f32) -> Result<Self, DrainError> {
+        if !(self.sim_th..=1.0).contains(&update_th) {
+            return Err(DrainError::InvalidUpdateThreshold(update_th));
         }
-        if max_sim >= sim_th {
-            match_cluster = max_cluster;
+        self.update_th = Some(update_th);
+        Ok(self)
+    }
+
+    /// Treat a leading `name:` token, e.g. `sshd:` or `kernel:`, as the facility:
+    /// it is kept as a token whatever `punctuation` is, and messages only match clusters
+    /// of the same facility (or both without one), regardless of `sim_th`.
+    /// The name consists of ASCII alphanumerics, `_`, `-` and `.`, starting with a letter,
+    /// so `sshd[42]:` is not a facility.
+    pub fn with_facility(mut self, facility: bool) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    /// Split log messages into tokens by the matches of `delimiter`, e.g. `[\s,;]+`,
+    /// instead of whitespace (or tabs with `tab_delimited`). Empty tokens are dropped.
+    /// A regex matching the empty string is rejected.
+    /// It is not serialized and has to be set again after loading.
+    #[cfg(feature = "regex")]
+    pub fn with_delimiter_regex(mut self, delimiter: regex::Regex) -> Result<Self, DrainError> {
+        if delimiter.is_match("") {
+            return Err(DrainError::EmptyDelimiter(delimiter.as_str().to_string()));
         }
-        match_cluster
+        self.delimiter = Some(delimiter);
+        Ok(self)
     }
 
-    fn get_seq_distance(
-        &self,
-        seq1: &[String],
+    /// Replace the matches of each regex with its replacement, in order,
+    /// before splitting log messages into tokens, e.g. `\d+\.\d+\.\d+\.\d+` with `<IP>`,
+    /// so that lines differing only in such values share a template
+    /// regardless of `sim_th`. Replaces the masks set before.
+    /// They are not serialized and have to be set again after loading.
+    #[cfg(feature = "regex")]
+    pub fn with_masks(mut self, masks: Vec<(regex::Regex, String)>) -> Self {
+        self.masks = masks;
+        self
+    }
+
+    /// Apply the masks of `with_masks`.
+    #[cfg(feature = "regex")]
+    fn mask<'a>(&self, log_message: Cow<'a, str>) -> Cow<'a, str> {
+        self.masks
+            .iter()
+            .fold(log_message, |log_message, (regex, replacement)| {
+                if regex.is_match(&log_message) {
+                    let masked = regex.replace_all(&log_message, regex::NoExpand(replacement));
+                    Cow::Owned(masked.into_owned())
+                } else {
+                    log_message
+                }
+            })
+    }
+
+    /// Clean up log messages with `normalization` before tokenizing them
+    /// in `train` and the matching methods, e.g. `Normalization::ALL` for colored output.
+    /// Tabs are kept with `tab_delimited`. See `normalize_line` to apply it beforehand,
+    /// e.g. to mask the cleaned-up line.
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Return `line` cleaned up as it is before tokenizing, see `with_normalization`.
+    pub fn normalize_line(&self, line: &str) -> String {
+        self.normalization.apply(line, self.tab_delimited)
+    }
+
+    /// Cut log messages longer than `max_line_bytes` before any other processing,
+    /// so that a pathological line such as a huge JSON blob cannot stall tokenizing.
+    /// The cut falls back to the previous UTF-8 character boundary.
+    pub fn with_max_line_bytes(mut self, max_line_bytes: usize) -> Self {
+        self.max_line_bytes = Some(max_line_bytes);
+        self
+    }
+
+    /// Let log messages of different numbers of tokens share a cluster,
+    /// for logs whose length varies while their structure does not.
+    /// The first level of the prefix tree then has the single key `MIXED_LENGTHS_KEY`
+    /// instead of one per token count, so matching starts at the first token.
+    /// Similarity is taken over the longer sequence, and a template grows
+    /// with `param_str` to the length of a longer matching message.
+    /// Clusters too short for the route of a message through the prefix tree,
+    /// e.g. of a single token, are stored above it and still compared with it,
+    /// as are longer clusters with messages too short for their route.
+    /// Set it before training, since it changes how the prefix tree is keyed.
+    pub fn with_mixed_lengths(mut self, mixed_lengths: bool) -> Self {
+        self.mixed_lengths = mixed_lengths;
+        self
+    }
+
+    /// Treat the trailing tokens one sequence has beyond the other as optional,
+    /// e.g. `now` in `connected to server now`, when `with_mixed_lengths` compares them:
+    /// they are matched like wildcards and weigh `penalty` each in the similarity
+    /// instead of counting as dissimilar positions. With 0.0 they are ignored,
+    /// and with 1.0 the similarity is the same as without this setting.
+    /// `penalty` must be within `0.0..=1.0`.
+    pub fn with_trailing_penalty(mut self, penalty: f32) -> Result<Self, DrainError> {
+        if !(0.0..=1.0).contains(&penalty) {
+            return Err(DrainError::InvalidTrailingPenalty(penalty));
+        }
+        self.trailing_penalty = Some(penalty);
+        Ok(self)
+    }
+
+    /// Split log messages into tokens after any of `delimiters` instead of whitespace,
+    /// e.g. `key1=a,key2=b` into `key1=`, `a,`, `key2=` and `b` with `=` and `,`.
+    /// Delimiters other than whitespace end the token before them, so that templates
+    /// such as `key1= <*> key2= <*>` keep the structure of the line; whitespace
+    /// delimiters are dropped. No `delimiters` restores the whitespace default.
+    /// `with_delimiter_regex` takes precedence.
+    pub fn with_delimiters(mut self, delimiters: &[char]) -> Self {
+        self.delimiters = (!delimiters.is_empty()).then(|| delimiters.to_vec());
+        self
+    }
+
+    /// Collapse runs of identical consecutive tokens into one when tokenizing,
+    /// e.g. `error error connection` into `error connection`, for logs that stutter.
+    /// Runs are collapsed before `max_tokens` applies.
+    pub fn with_dedup_tokens(mut self, dedup_tokens: bool) -> Self {
+        self.dedup_tokens = dedup_tokens;
+        self
+    }
+
+    /// Replace varying tokens of `class` with `wildcard` rather than `param_str`,
+    /// e.g. `0xDEAD` and `0xBEEF` with `<HEX>`, see `TokenClass::default_wildcard`.
+    /// Such tokens are also routed apart from other variable tokens in the prefix tree,
+    /// and a position mixing classes falls back to `param_str`.
+    /// Giving a class again replaces its wildcard. Set it before training.
+    pub fn with_token_class(
+        mut self,
+        class: TokenClass,
+        wildcard: impl Into<String>,
+    ) -> Result<Self, DrainError> {
+        let wildcard = wildcard.into();
+        if wildcard.is_empty()
+            || wildcard.contains(char::is_whitespace)
+            || wildcard == self.param_str
+        {
+            return Err(DrainError::InvalidClassWildcard(wildcard));
+        }
+        self.token_classes.retain(|(c, _)| *c != class);
+        self.token_classes.push((class, wildcard));
+        Ok(self)
+    }
+
+    /// Wildcards of the token classes given by `with_token_class`.
+    pub fn token_classes(&self) -> &[(TokenClass, String)] {
+        &self.token_classes
+    }
+
+    /// Key of the child of `node` that `token` follows when searching:
+    /// its own key, else the wildcard of its class, else `param_str`.
+    fn route<'a>(&'a self, node: &Node, token: &'a str) -> Option<Cow<'a, str>> {
+        let key = case_key(&self.case_insensitive, token);
+        if node.key_to_child_node.contains_key(key.as_ref()) {
+            return Some(key);
+        }
+        class_wildcard(&self.token_classes, token)
+            .filter(|wildcard| node.key_to_child_node.contains_key(*wildcard))
+            .or_else(|| {
+                node.key_to_child_node
+                    .contains_key(&self.param_str)
+                    .then_some(self.param_str.as_str())
+            })
+            .map(Cow::Borrowed)
+    }
+
+    /// Key of the first level of the prefix tree for messages of `token_count` tokens.
+    fn length_key(&self, token_count: usize) -> String {
+        if self.mixed_lengths {
+            MIXED_LENGTHS_KEY.to_string()
+        } else {
+            token_count.to_string()
+        }
+    }
+
+    /// Keep log messages matching `regex` in clusters of their own shape:
+    /// the index of the rule with the text it matched, e.g. `req-get` and `req-put`
+    /// for `^req-\w+`. Messages then only match clusters of the same shape,
+    /// however similar other clusters are, and messages matching no rule
+    /// only match clusters without a shape. The first matching rule wins.
+    /// `scope` tells whether the first token or the whole message is matched.
+    /// Rules are not serialized and have to be set again after loading.
+    #[cfg(feature = "regex")]
+    pub fn with_shape_rule(mut self, regex: regex::Regex, scope: ShapeScope) -> Self {
+        self.shape_rules.push((regex, scope));
+        self
+    }
+
+    /// Shape of a log message and its tokens according to `with_shape_rule`.
+    #[allow(unused_variables)]
+    fn shape_of(&self, log_message: &str, tokens: &[String]) -> Option<String> {
+        #[cfg(feature = "regex")]
+        for (i, (regex, scope)) in self.shape_rules.iter().enumerate() {
+            let haystack = match scope {
+                ShapeScope::FirstToken => tokens.first().map_or("", String::as_str),
+                ShapeScope::Line => log_message,
+            };
+            if let Some(m) = regex.find(haystack) {
+                return Some(format!("{}:{}", i, m.as_str()));
+            }
+        }
+        None
+    }
+
+    /// Return `line` cut to `max_line_bytes` as it is before tokenizing,
+    /// see `with_max_line_bytes`.
+    pub fn truncate_line<'a>(&self, line: &'a str) -> &'a str {
+        match self.max_line_bytes {
+            Some(max_line_bytes) if line.len() > max_line_bytes => {
+                let mut end = max_line_bytes;
+                while !line.is_char_boundary(end) {
+                    end -= 1;
+                }
+                &line[..end]
+            }
+            _ => line,
+        }
+    }
+
+    /// Count only the lines of the last `window_secs` seconds in the size of clusters,
+    /// for live monitoring rather than cumulative counts. `0` disables the window.
+    ///
+    /// Lines must be trained with `train_at` or recorded with `record_arrival`
+    /// to age out, and `expire` must be called as time passes; clusters left empty
+    /// are then evicted. Lines age out per bucket of `window_secs / WINDOW_BUCKETS`.
+    pub fn with_window(mut self, window_secs: u64) -> Self {
+        self.window_secs = (window_secs > 0).then_some(window_secs);
+        self
+    }
+
+    /// Bucket of the window which `at` seconds falls in.
+    fn window_bucket(&self, at: f64) -> Option<u64> {
+        self.window_secs
+            .map(|window_secs| (at.max(0.0) / (window_secs as f64 / WINDOW_BUCKETS as f64)) as u64)
+    }
+
+    /// Remove the lines older than the window as of `now` from the size of clusters,
+    /// and evict the clusters without lines left in it.
+    /// Return the ids of the evicted clusters.
+    pub fn expire(&mut self, now: f64) -> Vec<usize> {
+        let Some(bucket) = self.window_bucket(now) else {
+            return Vec::new();
+        };
+        let oldest = bucket.saturating_sub(WINDOW_BUCKETS - 1);
+        let mut expired = Vec::new();
+        for (cluster_id, cluster) in self.id_to_cluster.iter_mut() {
+            while let Some(&(bucket, count)) = cluster.window_counts.front() {
+                if bucket >= oldest {
+                    break;
+                }
+                cluster.window_counts.pop_front();
+                cluster.size = cluster.size.saturating_sub(count);
+            }
+            if cluster.size == 0 {
+                expired.push(*cluster_id);
+            }
+        }
+        for cluster_id in &expired {
+            if let Some(evicted) = self.id_to_cluster.pop(cluster_id) {
+                self.total_tokens -= evicted.log_template_tokens.len();
+            }
+        }
+        expired
+    }
+
+    /// Wildcard replacing the tokens that vary, see `LogCluster::is_wildcarded`.
+    pub fn param_str(&self) -> &str {
+        &self.param_str
+    }
+
+    /// Return the template of the cluster joined with the separator of this drain,
+    /// with runs of wildcards shown according to `with_wildcard_runs`.
+    pub fn template(&self, cluster: &LogCluster) -> String {
+        self.join_template(&cluster.log_template_tokens)
+    }
+
+    fn join_template(&self, tokens: &[String]) -> String {
+        if self.wildcard_runs == WildcardRuns::Keep {
+            return tokens.join(&self.separator);
+        }
+        let runs: Vec<Cow<str>> = tokens
+            .chunk_by(|token1, token2| *token1 == self.param_str && *token2 == self.param_str)
+            .map(|run| match (self.wildcard_runs, run.len()) {
+                (WildcardRuns::Count, len) if len > 1 => format!("{}{{{}}}", run[0], len).into(),
+                _ => Cow::from(run[0].as_str()),
+            })
+            .collect();
+        runs.join(&self.separator)
+    }
+
+    fn same_token(&self, token1: &str, token2: &str) -> bool {
+        token1 == token2
+            || case_key(&self.case_insensitive, token1) == case_key(&self.case_insensitive, token2)
+    }
+
+    /// Whether the template and the tokens have the same facility, or both none.
+    fn same_facility(&self, template: &[String], tokens: &[String]) -> bool {
+        match (
+            template.first().filter(|token| is_facility(token)),
+            tokens.first().filter(|token| is_facility(token)),
+        ) {
+            (Some(facility1), Some(facility2)) => self.same_token(facility1, facility2),
+            (facility1, facility2) => facility1.is_none() && facility2.is_none(),
+        }
+    }
+
+    /// Return the clusters from the most to the least recently used.
+    /// The order changes as lines are trained; see `clusters_by_id()` for a stable one.
+    pub fn clusters(&self) -> Vec<&LogCluster> {
+        self.id_to_cluster.iter().map(|(_, v)| v).collect()
+    }
+
+    /// Minimum and maximum number of template tokens over the clusters,
+    /// or `None` without clusters.
+    pub fn template_length_range(&self) -> Option<(usize, usize)> {
+        self.id_to_cluster
+            .iter()
+            .map(|(_, cluster)| cluster.log_template_tokens.len())
+            .fold(None, |range, len| match range {
+                Some((min, max)) => Some((len.min(min), len.max(max))),
+                None => Some((len, len)),
+            })
+    }
+
+    /// Number of clusters.
+    pub fn len(&self) -> usize {
+        self.id_to_cluster.len()
+    }
+
+    /// Whether no cluster has been created yet, or all were evicted.
+    pub fn is_empty(&self) -> bool {
+        self.id_to_cluster.is_empty()
+    }
+
+    /// Number of log messages trained so far,
+    /// including those of clusters evicted since.
+    pub fn total_lines(&self) -> usize {
+        self.total_lines
+    }
+
+    /// Number of log messages that matched no cluster in `match_log`,
+    /// i.e. the candidate anomalies against a frozen model.
+    pub fn unmatched_count(&self) -> usize {
+        self.unmatched_count.load(Ordering::Relaxed)
+    }
+
+    /// Average number of log messages per cluster, i.e. `total_lines / len`,
+    /// or `None` without clusters.
+    /// A ratio near 1 means templates hardly collapse anything.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (!self.is_empty()).then(|| self.total_lines as f64 / self.len() as f64)
+    }
+
+    /// Return the clusters in ascending order of `cluster_id`,
+    /// i.e. in the order they were created, regardless of their recency.
+    pub fn clusters_by_id(&self) -> Vec<&LogCluster> {
+        let mut clusters = self.clusters();
+        clusters.sort_by_key(|cluster| cluster.cluster_id);
+        clusters
+    }
+
+    /// Return the clusters whose template matches `pattern`,
+    /// in ascending order of `cluster_id`.
+    #[cfg(feature = "regex")]
+    pub fn find_clusters(&self, pattern: &regex::Regex) -> Vec<&LogCluster> {
+        self.clusters_by_id()
+            .into_iter()
+            .filter(|cluster| pattern.is_match(&self.template(cluster)))
+            .collect()
+    }
+
+    /// Walk the prefix tree in depth-first order,
+    /// calling `visitor` with the depth, key and cluster ids of each node.
+    /// The first level (depth 0) holds the token-count buckets,
+    /// and siblings are visited in the order of their keys.
+    pub fn walk_tree<F: FnMut(usize, &str, &[usize])>(&self, mut visitor: F) {
+        fn walk<F: FnMut(usize, &str, &[usize])>(node: &Node, depth: usize, visitor: &mut F) {
+            let mut keys: Vec<&String> = node.key_to_child_node.keys().collect();
+            keys.sort();
+            for key in keys {
+                let child = &node.key_to_child_node[key];
+                visitor(depth, key, &child.cluster_ids);
+                walk(child, depth + 1, visitor);
+            }
+        }
+        walk(&self.root, 0, &mut visitor);
+    }
+
+    /// Render the prefix tree as Graphviz DOT, e.g. for `dot -Tpng`:
+    /// the token-count buckets and the tokens below them as ellipses,
+    /// and the clusters of each leaf as boxes labeled `[cluster_id] template`.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph drain {\n    n0 [label=\"root\"];\n");
+        // Ids of the nodes from the root to the current one, by depth.
+        let mut path = vec![0];
+        let mut next_id = 1;
+        self.walk_tree(|depth, key, cluster_ids| {
+            path.truncate(depth + 1);
+            let id = next_id;
+            next_id += 1;
+            dot.push_str(&format!(
+                "    n{} [label=\"{}\"];\n    n{} -> n{};\n",
+                id,
+                dot_escape(key),
+                path[depth],
+                id
+            ));
+            for cluster in cluster_ids
+                .iter()
+                .filter_map(|cluster_id| self.id_to_cluster.peek(cluster_id))
+            {
+                dot.push_str(&format!(
+                    "    c{} [shape=box, label=\"[{}] {}\"];\n    n{} -> c{};\n",
+                    cluster.cluster_id,
+                    cluster.cluster_id,
+                    dot_escape(&self.template(cluster)),
+                    id,
+                    cluster.cluster_id
+                ));
+            }
+            path.push(id);
+        });
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Return `(template, size)` for each cluster.
+    /// The order is the same as `clusters()`, i.e. by recency.
+    pub fn summaries(&self) -> Vec<(String, usize)> {
+        self.id_to_cluster
+            .iter()
+            .map(|(_, cluster)| (self.template(cluster), cluster.size))
+            .collect()
+    }
+
+    /// Count clusters whose size is at least `min_size`
+    /// without collecting them.
+    pub fn significant_count(&self, min_size: usize) -> usize {
+        self.id_to_cluster
+            .iter()
+            .filter(|(_, cluster)| cluster.size >= min_size)
+            .count()
+    }
+
+    /// Number of nodes in the prefix tree that reached `max_children`,
+    /// so that further distinct tokens below them share the `param_str` child.
+    /// A non-zero count suggests `max_children` is too low for the logs
+    /// and templates may be over-generalized.
+    pub fn saturated_nodes(&self) -> usize {
+        self.saturated_nodes
+    }
+
+    /// Map each cluster size to the number of clusters of that size.
+    /// A long tail of size 1 suggests the logs need masking.
+    pub fn size_histogram(&self) -> BTreeMap<usize, usize> {
+        let mut histogram = BTreeMap::new();
+        for (_, cluster) in self.id_to_cluster.iter() {
+            *histogram.entry(cluster.size).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// Set the size of every cluster, `total_lines` and `unmatched_count` to 0,
+    /// keeping the templates, ids and prefix tree, e.g. to report fresh counts
+    /// per interval against stable clusters. Rates are kept.
+    /// With a window, the emptied clusters are evicted by the next `expire`.
+    pub fn reset_sizes(&mut self) {
+        for (_, cluster) in self.id_to_cluster.iter_mut() {
+            cluster.size = 0;
+            cluster.window_counts.clear();
+        }
+        self.total_lines = 0;
+        *self.unmatched_count.get_mut() = 0;
+    }
+
+    /// Change `max_clusters` while running, `None` lifting the limit.
+    /// When shrinking, the least recently used clusters are evicted right away.
+    /// `Some(0)` is rejected as by `Drain::new` and `apply_config`, leaving the limit unchanged.
+    pub fn set_max_clusters(&mut self, max_clusters: Option<usize>) -> Result<(), DrainError> {
+        let cap = match max_clusters {
+            Some(max_clusters) => {
+                NonZeroUsize::new(max_clusters).ok_or(DrainError::ZeroMaxClusters)?
+            }
+            None => NonZeroUsize::MAX,
+        };
+        while self.id_to_cluster.len() > cap.get() {
+            if let Some((_, evicted)) = self.id_to_cluster.pop_lru() {
+                self.total_tokens -= evicted.log_template_tokens.len();
+            }
+        }
+        self.id_to_cluster.resize(cap);
+        Ok(())
+    }
+
+    /// Remove the clusters with fewer than `min_size` log messages, e.g. singleton noise
+    /// of a long-running session, along with their ids and the nodes left empty
+    /// in the prefix tree. Return the number of clusters removed.
+    pub fn prune(&mut self, min_size: usize) -> usize {
+        let pruned: Vec<usize> = self
+            .id_to_cluster
+            .iter()
+            .filter(|(_, cluster)| cluster.size < min_size)
+            .map(|(cluster_id, _)| *cluster_id)
+            .collect();
+        if pruned.is_empty() {
+            return 0;
+        }
+        for cluster_id in &pruned {
+            if let Some(removed) = self.id_to_cluster.pop(cluster_id) {
+                self.total_tokens -= removed.log_template_tokens.len();
+            }
+        }
+
+        let id_to_cluster = &self.id_to_cluster;
+        let mut removed_saturated = 0;
+        self.root.scrub(
+            &|cluster_id| id_to_cluster.contains(cluster_id),
+            &mut removed_saturated,
+        );
+        self.saturated_nodes -= removed_saturated;
+        self.single_token_clusters
+            .retain(|_, cluster_id| id_to_cluster.contains(cluster_id));
+        pruned.len()
+    }
+
+    /// Rebuild the prefix tree from the current clusters
+    /// so that changes to its shape (e.g. `max_node_depth`) apply to the existing clusters.
+    pub fn rebuild(&mut self) {
+        self.root = Node::default();
+        self.single_token_clusters.clear();
+        self.saturated_nodes = 0;
+
+        let clusters: Vec<LogCluster> = self.clusters_by_id().into_iter().cloned().collect();
+        for mut cluster in clusters {
+            self.add_seq_to_prefix_tree(&mut cluster);
+        }
+    }
+
+    /// Add the clusters of `other`, e.g. trained on another shard of the same logs,
+    /// by training on each of its templates in id order as if it had been seen `size` times.
+    /// Return the ids of the clusters of `other` mapped to the ids of the clusters
+    /// they ended up in, which are assigned by this drain and do not collide.
+    ///
+    /// The merge is approximate since it works on templates rather than raw lines:
+    /// a template of `other` generalizes the lines it was trained on, so the result can
+    /// differ from training on both shards, and `other` should share the settings of this drain.
+    /// Sizes, rates and window counts of merged clusters add up,
+    /// and a label of `other` is kept where the cluster it ends up in has none.
+    pub fn merge(&mut self, other: Drain) -> BTreeMap<usize, usize> {
+        let mut id_map = BTreeMap::new();
+        for cluster in other.clusters_by_id() {
+            let (merged, _, _) = self.train_inner(
+                cluster.log_template_tokens.clone(),
+                cluster.shape.clone(),
+                cluster.size,
+            );
+            if let Some(merged) = self.id_to_cluster.peek_mut(&merged.cluster_id) {
+                merged.absorb(cluster);
+            }
+            id_map.insert(cluster.cluster_id, merged.cluster_id);
+        }
+        id_map
+    }
+
+    /// Set or clear the label of the cluster, without marking it as used.
+    /// Return `false` if there is no such cluster.
+    pub fn set_label(&mut self, cluster_id: usize, label: Option<String>) -> bool {
+        match self.id_to_cluster.peek_mut(&cluster_id) {
+            Some(cluster) => {
+                cluster.set_label(label);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Train on the log message received at `at` seconds, e.g. since the Unix epoch,
+    /// updating the `rate` of its cluster.
+    pub fn train_at<T: AsRef<str>>(&mut self, log_message: T, at: f64) -> LogCluster {
+        let mut cluster = self.train(log_message);
+        self.record_arrival(cluster.cluster_id, at);
+        cluster.record_arrival(at, self.window_bucket(at));
+        cluster
+    }
+
+    /// Count a line received at `at` seconds in the `rate` of the cluster,
+    /// for lines trained with `train` or `train_with_info`.
+    pub fn record_arrival(&mut self, cluster_id: usize, at: f64) {
+        let bucket = self.window_bucket(at);
+        if let Some(cluster) = self.id_to_cluster.peek_mut(&cluster_id) {
+            cluster.record_arrival(at, bucket);
+        }
+    }
+
+    pub fn train<T: AsRef<str>>(&mut self, log_message: T) -> LogCluster {
+        let tokens = self.tokenize(log_message.as_ref());
+        let shape = self.shape_of(log_message.as_ref(), &tokens);
+        self.train_inner(tokens, shape, 1).0
+    }
+
+    /// Train on already tokenized log message, bypassing the tokenizer
+    /// (and hence `max_tokens`, `tab_delimited` and `punctuation`).
+    /// Shape rules are not applied either.
+    pub fn train_tokens(&mut self, tokens: Vec<String>) -> LogCluster {
+        self.train_inner(tokens, None, 1).0
+    }
+
+    /// Find the cluster matching already tokenized log message as `match_log` does,
+    /// among the clusters without a shape.
+    pub fn match_tokens(&self, tokens: &[String]) -> Option<&LogCluster> {
+        self.match_inner(tokens, None)
+    }
+
+    /// Find the cluster matching the log message without updating the model,
+    /// counting it in `unmatched_count` if there is none.
+    /// Wildcards of the templates count as similar, so a template generalized
+    /// past `sim_th` still matches the log messages it was trained on.
+    /// Neither the clusters nor their recency change.
+    pub fn match_log<T: AsRef<str>>(&self, log_message: T) -> Option<&LogCluster> {
+        let tokens = self.tokenize(log_message.as_ref());
+        let shape = self.shape_of(log_message.as_ref(), &tokens);
+        self.match_inner(&tokens, shape.as_deref())
+    }
+
+    /// Search without updating the model for `match_log` and `match_tokens`.
+    fn match_inner(&self, tokens: &[String], shape: Option<&str>) -> Option<&LogCluster> {
+        let cluster = self.tree_search(tokens, shape, self.sim_th, true);
+        if cluster.is_none() {
+            self.unmatched_count.fetch_add(1, Ordering::Relaxed);
+        }
+        cluster
+    }
+
+    /// Return the tokens of the log message at the wildcard positions of the template
+    /// it matches as in `match_log`, e.g. `["10.0.0.1"]` for `connected to 10.0.0.1`
+    /// and `connected to <*>`, or an empty vector for a literal template.
+    /// Wildcards of `typed_params` and token classes count as well.
+    /// `None` if no template matches, or if the line has another number of tokens
+    /// than the template, as with `with_mixed_lengths`.
+    pub fn extract_params(&self, log_message: &str) -> Option<Vec<String>> {
+        let tokens = self.tokenize(log_message);
+        let shape = self.shape_of(log_message, &tokens);
+        let cluster = self.tree_search(&tokens, shape.as_deref(), self.sim_th, true)?;
+        if cluster.log_template_tokens.len() != tokens.len() {
+            return None;
+        }
+        let params = cluster
+            .log_template_tokens
+            .iter()
+            .zip(tokens)
+            .filter(|(template_token, _)| self.is_wildcard(template_token))
+            .map(|(_, token)| token)
+            .collect();
+        Some(params)
+    }
+
+    /// Whether any token of the template of `cluster` is a wildcard of this drain,
+    /// including `NUM_PARAM_STR` with `with_typed_params` and the token class wildcards.
+    pub fn is_wildcarded(&self, cluster: &LogCluster) -> bool {
+        cluster
+            .log_template_tokens
+            .iter()
+            .any(|token| self.is_wildcard(token))
+    }
+
+    /// Whether the template token is a wildcard of this drain.
+    fn is_wildcard(&self, template_token: &str) -> bool {
+        template_token == self.param_str
+            || (self.typed_params && template_token == NUM_PARAM_STR)
+            || self
+                .token_classes
+                .iter()
+                .any(|(_, wildcard)| wildcard == template_token)
+    }
+
+    /// Regex matching the log messages of the template of `cluster`,
+    /// e.g. `^connected to (\S+)$` for `connected to <*>` and `DEFAULT_PARAM_PATTERN`.
+    /// Literal tokens are escaped, and each wildcard as in `extract_params`
+    /// becomes a capture group of `param_pattern`.
+    /// Tokens are separated as the tokenizer splits them: by the delimiter regex,
+    /// by the whitespace among `delimiters`, which is optional after a literal token
+    /// ended by one of the other delimiters, or by a single space.
+    #[cfg(feature = "regex")]
+    pub fn to_regex(
+        &self,
+        cluster: &LogCluster,
+        param_pattern: &str,
+    ) -> Result<regex::Regex, regex::Error> {
+        let spaces: Option<String> = self.delimiters.as_ref().map(|delimiters| {
+            delimiters
+                .iter()
+                .filter(|c| c.is_whitespace())
+                .map(|c| regex::escape(&c.to_string()))
+                .collect()
+        });
+        let mut pattern = String::from("^");
+        // Whether the previous token is a literal ended by a delimiter, if any.
+        let mut delimited = None;
+        for token in &cluster.log_template_tokens {
+            if let Some(delimited) = delimited {
+                let separator = match (&self.delimiter, &spaces) {
+                    (Some(delimiter), _) => format!("(?:{})", delimiter.as_str()),
+                    (None, Some(spaces)) if spaces.is_empty() => String::new(),
+                    (None, Some(spaces)) if delimited => format!("[{}]*", spaces),
+                    (None, Some(spaces)) => format!("[{}]+", spaces),
+                    (None, None) => String::from(" "),
+                };
+                pattern.push_str(&separator);
+            }
+            if self.is_wildcard(token) {
+                pattern.push_str(&format!("({})", param_pattern));
+                delimited = Some(false);
+            } else {
+                pattern.push_str(&regex::escape(token));
+                delimited = Some(self.delimiters.as_ref().is_some_and(|delimiters| {
+                    token
+                        .chars()
+                        .last()
+                        .is_some_and(|c| !c.is_whitespace() && delimiters.contains(&c))
+                }));
+            }
+        }
+        pattern.push('$');
+        regex::Regex::new(&pattern)
+    }
+
+    /// Match each of `log_messages` as `match_log` does, without updating the model,
+    /// e.g. to evaluate the templates against held-out log messages.
+    pub fn coverage<I, S>(&self, log_messages: I) -> CoverageReport
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut report = CoverageReport::default();
+        for log_message in log_messages {
+            match self.match_log(log_message) {
+                Some(cluster) => {
+                    report.matched += 1;
+                    *report.matches.entry(cluster.cluster_id).or_default() += 1;
+                }
+                None => report.unmatched += 1,
+            }
+        }
+        report
+    }
+
+    /// Return every cluster `log_message` matches at least `sim_th` similar,
+    /// from the most similar, without updating the model.
+    /// Unlike `train`, which settles on the best candidate, this suits lines
+    /// relating to several templates. Candidates are those of the same prefix tree node.
+    pub fn match_all(&self, log_message: &str, sim_th: f32) -> Vec<(LogCluster, f32)> {
+        let tokens = self.tokenize(log_message);
+        let shape = self.shape_of(log_message, &tokens);
+        let Some(cluster_ids) = self.candidate_ids(&tokens) else {
+            return Vec::new();
+        };
+        let mut matches: Vec<(LogCluster, f32)> = cluster_ids
+            .iter()
+            .filter_map(|id| self.id_to_cluster.peek(id))
+            .filter(|cluster| cluster.shape == shape)
+            .filter(|cluster| {
+                !self.facility || self.same_facility(&cluster.log_template_tokens, &tokens)
+            })
+            .filter_map(|cluster| {
+                // Empty messages only share a cluster with each other.
+                let sim = if tokens.is_empty() {
+                    cluster.log_template_tokens.is_empty().then_some(1.0)
+                } else {
+                    self.get_seq_distance(&cluster.log_template_tokens, &tokens, false, sim_th)
+                        .map(|(sim, _)| sim)
+                }?;
+                (sim >= sim_th).then(|| (cluster.clone(), sim))
+            })
+            .collect();
+        matches.sort_by(|(_, sim1), (_, sim2)| sim2.total_cmp(sim1));
+        matches
+    }
+
+    /// Explain which cluster `log_message` would match and why, without updating the model:
+    /// the keys followed in the prefix tree, then the similarity of each candidate.
+    pub fn explain_match(&self, log_message: &str) -> MatchExplanation {
+        let tokens = self.tokenize(log_message);
+        let shape = self.shape_of(log_message, &tokens);
+        let mut explanation = MatchExplanation {
+            tokens: Vec::new(),
+            bucket: self.length_key(tokens.len()),
+            path: Vec::new(),
+            reached_leaf: false,
+            candidates: Vec::new(),
+            matched: None,
+        };
+
+        // Follow the same route as `tree_search_slow`.
+        let mut cur_node = self
+            .root
+            .key_to_child_node
+            .get(&self.length_key(tokens.len()));
+        for (cur_node_depth, token) in (1..).zip(&tokens) {
+            let Some(node) = cur_node else {
+                break;
+            };
+            if cur_node_depth >= self.max_node_depth || cur_node_depth == tokens.len() {
+                break;
+            }
+            cur_node = self.route(node, token).map(|key| {
+                let child = &node.key_to_child_node[key.as_ref()];
+                explanation.path.push(key.into_owned());
+                child
+            });
+        }
+
+        // Empty messages only share a cluster with each other.
+        let similarity = |template: &[String]| {
+            if template.is_empty() {
+                Some(1.0)
+            } else {
+                self.get_seq_distance(template, &tokens, false, 0.0)
+                    .map(|(sim, _)| sim)
+            }
+        };
+        explanation.reached_leaf = cur_node.is_some();
+        if let Some(cluster_ids) = self.candidate_ids(&tokens) {
+            for id in cluster_ids.iter() {
+                let Some(cluster) = self.id_to_cluster.peek(id) else {
+                    continue;
+                };
+                if self.facility && !self.same_facility(&cluster.log_template_tokens, &tokens) {
+                    continue;
+                }
+                if cluster.shape != shape {
+                    continue;
+                }
+                if let Some(sim) = similarity(&cluster.log_template_tokens) {
+                    explanation.candidates.push((*id, sim));
+                }
+            }
+        }
+        explanation.matched = self
+            .tree_search(&tokens, shape.as_deref(), self.sim_th, false)
+            .and_then(|cluster| {
+                similarity(&cluster.log_template_tokens).map(|sim| (cluster.cluster_id, sim))
+            });
+        explanation.tokens = tokens;
+        explanation
+    }
+
+    /// Like `train`, but also tells whether the cluster was created or updated.
+    pub fn train_with_info<T: AsRef<str>>(&mut self, log_message: T) -> TrainInfo {
+        let tokens = self.tokenize(log_message.as_ref());
+        let shape = self.shape_of(log_message.as_ref(), &tokens);
+        let (cluster, outcome, previous) = self.train_inner(tokens, shape, 1);
+        TrainInfo {
+            cluster_id: cluster.cluster_id,
+            outcome,
+            template: self.template(&cluster),
+            size: cluster.size,
+            previous_template: previous.map(|tokens| self.join_template(&tokens)),
+        }
+    }
+
+    fn tokenize(&self, log_message: &str) -> Vec<String> {
+        let log_message = self
+            .normalization
+            .apply_cow(self.truncate_line(log_message), self.tab_delimited);
+        #[cfg(feature = "regex")]
+        let log_message = self.mask(log_message);
+        let log_message = log_message.as_ref();
+        let facility = if self.facility {
+            split_facility(log_message)
+        } else {
+            None
+        };
+        match facility {
+            Some((facility, rest)) => {
+                let mut tokens = vec![facility.to_string()];
+                tokens.extend(
+                    self.split_tokens(
+                        rest,
+                        self.max_tokens
+                            .map(|max_tokens| max_tokens.saturating_sub(1)),
+                    ),
+                );
+                tokens
+            }
+            None => self.split_tokens(log_message, self.max_tokens),
+        }
+    }
+
+    fn split_tokens(&self, log_message: &str, max_tokens: Option<usize>) -> Vec<String> {
+        #[cfg(feature = "regex")]
+        if let Some(delimiter) = &self.delimiter {
+            let tokens = delimiter
+                .split(log_message)
+                .filter(|token| !token.is_empty());
+            return process_tokens(
+                Box::new(tokens),
+                max_tokens,
+                self.punctuation,
+                self.dedup_tokens,
+            );
+        }
+        if let Some(delimiters) = &self.delimiters {
+            let tokens = log_message
+                .split_inclusive(|c| delimiters.contains(&c))
+                .map(|token| {
+                    token.trim_end_matches(|c: char| c.is_whitespace() && delimiters.contains(&c))
+                })
+                .filter(|token| !token.is_empty());
+            return process_tokens(
+                Box::new(tokens),
+                max_tokens,
+                self.punctuation,
+                self.dedup_tokens,
+            );
+        }
+        tokenize(
+            log_message,
+            max_tokens,
+            self.tab_delimited,
+            self.punctuation,
+            self.dedup_tokens,
+        )
+    }
+
+    /// Train on the tokens counted as `weight` lines, e.g. `1` for a log message,
+    /// and return the cluster, what happened to it, and its previous template tokens
+    /// if they changed.
+    fn train_inner(
+        &mut self,
+        tokens: Vec<String>,
+        shape: Option<String>,
+        weight: usize,
+    ) -> (LogCluster, TrainOutcome, Option<Vec<String>>) {
+        self.total_lines += weight;
+        match self
+            .tree_search(&tokens, shape.as_deref(), self.sim_th, false)
+            .cloned()
+        {
+            Some(mut match_cluster) => {
+                let prev_template_tokens = match_cluster.log_template_tokens.clone();
+                let updates_template = self.update_th.is_none_or(|update_th| {
+                    self.get_seq_distance(&prev_template_tokens, &tokens, false, 0.0)
+                        .is_some_and(|(sim, _)| sim >= update_th)
+                });
+                if updates_template {
+                    let mut template_tokens = match self.min_distinct_values {
+                        Some(min_distinct_values) => self.create_template_with_counts(
+                            &tokens,
+                            &mut match_cluster,
+                            min_distinct_values,
+                        ),
+                        None => self.create_template(&tokens, &match_cluster.log_template_tokens),
+                    };
+                    // Only differs with `mixed_lengths`.
+                    template_tokens.resize(
+                        tokens.len().max(prev_template_tokens.len()),
+                        self.param_str.clone(),
+                    );
+                    self.total_tokens += template_tokens.len() - prev_template_tokens.len();
+                    match_cluster.log_template_tokens = template_tokens;
+                }
+                match_cluster.size += weight;
+                self.id_to_cluster
+                    .put(match_cluster.cluster_id, match_cluster.clone());
+                // Templates grow with `mixed_lengths`; the updated cluster is the last evicted.
+                self.evict_over_token_budget();
+                if match_cluster.log_template_tokens == prev_template_tokens {
+                    (match_cluster, TrainOutcome::Matched, None)
+                } else {
+                    (
+                        match_cluster,
+                        TrainOutcome::TemplateChanged,
+                        Some(prev_template_tokens),
+                    )
+                }
+            }
+            None => {
+                let cluster_id = self.next_cluster_id(&tokens);
+                let position_values = match self.min_distinct_values {
+                    Some(_) => tokens
+                        .iter()
+                        .map(|token| HashSet::from([token.clone()]))
+                        .collect(),
+                    None => Vec::new(),
+                };
+                let mut match_cluster = LogCluster {
+                    log_template_tokens: tokens,
+                    cluster_id,
+                    size: weight,
+                    position_values,
+                    rate: 0.0,
+                    last_arrival: None,
+                    window_counts: VecDeque::new(),
+                    shape,
+                    label: None,
+                };
+                self.total_tokens += match_cluster.log_template_tokens.len();
+                if let Some((_, evicted)) = self
+                    .id_to_cluster
+                    .push(match_cluster.cluster_id, match_cluster.clone())
+                {
+                    self.total_tokens -= evicted.log_template_tokens.len();
+                }
+                self.evict_over_token_budget();
+                self.add_seq_to_prefix_tree(&mut match_cluster);
+                (match_cluster, TrainOutcome::Created, None)
+            }
+        }
+    }
+
+    /// Pick the id of a new cluster created from `tokens`.
+    fn next_cluster_id(&mut self, tokens: &[String]) -> usize {
+        match self.id_strategy {
+            IdStrategy::Counter => {
+                self.cluster_counter += 1;
+                self.cluster_counter
+            }
+            IdStrategy::TemplateHash => {
+                // Truncated on 32-bit targets.
+                let mut cluster_id = hash_tokens(tokens) as usize;
+                // Probe linearly so that a collision never replaces a live cluster.
+                while self.id_to_cluster.contains(&cluster_id) {
+                    cluster_id = cluster_id.wrapping_add(1);
+                }
+                cluster_id
+            }
+        }
+    }
+
+    /// Evict the least recently used clusters until the total number of
+    /// template tokens fits in `max_total_tokens`.
+    /// The most recently used cluster is always kept, even if it alone exceeds the budget.
+    fn evict_over_token_budget(&mut self) {
+        let Some(max_total_tokens) = self.max_total_tokens else {
+            return;
+        };
+        while self.total_tokens > max_total_tokens && self.id_to_cluster.len() > 1 {
+            if let Some((_, evicted)) = self.id_to_cluster.pop_lru() {
+                self.total_tokens -= evicted.log_template_tokens.len();
+            }
+        }
+    }
+
+    /// Find the matching cluster without touching the LRU order;
+    /// only the cluster finally updated by `train` is marked as used.
+    fn tree_search(
+        &self,
+        tokens: &[String],
+        shape: Option<&str>,
+        sim_th: f32,
+        include_params: bool,
+    ) -> Option<&LogCluster> {
+        let token_count = tokens.len();
+
+        // Fast path for single-token logs: with a positive threshold
+        // only a cluster whose template is the very same token can match.
+        if token_count == 1
+            && !self.mixed_lengths
+            && sim_th > 0.0
+            && !include_params
+            && tokens[0] != self.param_str
+        {
+            // An entry for an evicted cluster is overwritten
+            // when a cluster for the same token is created again.
+            let cluster_id = self
+                .single_token_clusters
+                .get(case_key(&self.case_insensitive, &tokens[0]).as_ref())?;
+            return match self.id_to_cluster.peek(cluster_id) {
+                Some(cluster)
+                    if self.same_token(&cluster.log_template_tokens[0], &tokens[0])
+                        && cluster.shape.as_deref() == shape =>
+                {
+                    Some(cluster)
+                }
+                Some(_) => self.tree_search_slow(tokens, shape, sim_th, include_params),
+                None => None,
+            };
+        }
+        self.tree_search_slow(tokens, shape, sim_th, include_params)
+    }
+
+    fn tree_search_slow(
+        &self,
+        tokens: &[String],
+        shape: Option<&str>,
+        sim_th: f32,
+        include_params: bool,
+    ) -> Option<&LogCluster> {
+        let cluster_ids = self.candidate_ids(tokens)?;
+        if tokens.is_empty() {
+            // With `mixed_lengths`, single-token clusters share the node.
+            return cluster_ids
+                .iter()
+                .filter_map(|id| self.id_to_cluster.peek(id))
+                .find(|cluster| {
+                    cluster.log_template_tokens.is_empty() && cluster.shape.as_deref() == shape
+                });
+        }
+        self.fast_match(&cluster_ids, tokens, shape, sim_th, include_params)
+    }
+
+    /// Ids of the clusters to compare `tokens` with: those of the node the prefix tree
+    /// leads them to. With `mixed_lengths`, clusters of other lengths off that route
+    /// are added: shorter ones, e.g. of a single token, left on the nodes above it,
+    /// and, for messages of fewer tokens than `max_node_depth`, longer ones below
+    /// the key of their last token. `None` if no node is on the route.
+    fn candidate_ids(&self, tokens: &[String]) -> Option<Cow<'_, [usize]>> {
+        if !self.mixed_lengths {
+            return self
+                .leaf_node(tokens)
+                .map(|node| Cow::Borrowed(node.cluster_ids.as_slice()));
+        }
+        let mut cluster_ids = Vec::new();
+        let mut cur_node = self.root.key_to_child_node.get(MIXED_LENGTHS_KEY)?;
+        for (cur_node_depth, token) in (1..).zip(tokens) {
+            if cur_node_depth >= self.max_node_depth {
+                break;
+            }
+            if cur_node_depth == tokens.len() {
+                if let Some(key) = self.route(cur_node, token) {
+                    cur_node.key_to_child_node[key.as_ref()].collect_cluster_ids(&mut cluster_ids);
+                }
+                break;
+            }
+            // Empty messages only share a cluster with each other.
+            cluster_ids.extend(cur_node.cluster_ids.iter().filter(|id| {
+                self.id_to_cluster
+                    .peek(id)
+                    .is_some_and(|cluster| !cluster.log_template_tokens.is_empty())
+            }));
+            let Some(key) = self.route(cur_node, token) else {
+                return Some(Cow::Owned(cluster_ids));
+            };
+            cur_node = &cur_node.key_to_child_node[key.as_ref()];
+        }
+        cluster_ids.extend(&cur_node.cluster_ids);
+        Some(Cow::Owned(cluster_ids))
+    }
+
+    /// Follow the tokens down the prefix tree to the node holding their candidate clusters.
+    #[allow(clippy::explicit_counter_loop)]
+    fn leaf_node(&self, tokens: &[String]) -> Option<&Node> {
+        let token_count = tokens.len();
+
+        let mut cur_node = self
+            .root
+            .key_to_child_node
+            .get(&self.length_key(token_count))?;
+        let mut cur_node_depth = 1;
+        for token in tokens {
+            // At max depth.
+            if cur_node_depth >= self.max_node_depth {
+                break;
+            }
+
+            // At last token.
+            if cur_node_depth == token_count {
+                break;
+            }
+
+            let key = self.route(cur_node, token)?;
+            cur_node = &cur_node.key_to_child_node[key.as_ref()];
+
+            cur_node_depth += 1;
+        }
+        Some(cur_node)
+    }
+
+    fn fast_match(
+        &self,
+        cluster_ids: &[usize],
+        tokens: &[String],
+        shape: Option<&str>,
+        sim_th: f32,
+        include_params: bool,
+    ) -> Option<&LogCluster> {
+        let mut match_cluster = None;
+        let mut max_cluster = None;
+
+        let mut max_sim = -1.0;
+        let mut max_param_count = -1;
+        for id in cluster_ids {
+            if let Some(cluster) = self.id_to_cluster.peek(id) {
+                if self.facility && !self.same_facility(&cluster.log_template_tokens, tokens) {
+                    continue;
+                }
+                if cluster.shape.as_deref() != shape {
+                    continue;
+                }
+                // A candidate below `sim_th` or the best one so far can never be returned,
+                // so stop comparing it as soon as it cannot reach them.
+                let Some((cur_sim, param_count)) = self.get_seq_distance(
+                    &cluster.log_template_tokens,
+                    tokens,
+                    include_params,
+                    sim_th.max(max_sim),
+                ) else {
+                    continue;
+                };
+                let preferred = match max_cluster {
+                    Some(best) if cur_sim == max_sim => self
+                        .tie_break
+                        .prefers((cluster, param_count), (best, max_param_count)),
+                    _ => cur_sim > max_sim,
+                };
+                if preferred {
+                    max_sim = cur_sim;
+                    max_param_count = param_count;
+                    max_cluster = Some(cluster);
+                }
+            }
+        }
+        if max_sim >= sim_th {
+            match_cluster = max_cluster;
+        }
+        match_cluster
+    }
+
+    /// Return the similarity of the sequences and the number of parameters,
+    /// or `None` as soon as the similarity can no longer reach `min_sim`.
+    fn get_seq_distance(
+        &self,
+        seq1: &[String],
         seq2: &[String],
         include_params: bool,
-    ) -> (f32, isize) {
+        min_sim: f32,
+    ) -> Option<(f32, isize)> {
         let mut sim_tokens = 0;
         let mut param_count = 0;
+        // Wildcards at positions of both sequences.
+        let mut positional_params = 0;
+        // Similar tokens if all the remaining positions were similar.
+        let mut max_sim_tokens = seq1.len().min(seq2.len());
+        // Positions beyond the shorter sequence, only with `mixed_lengths`, are dissimilar
+        // unless `trailing_penalty` lowers their weight.
+        let trailing = seq1.len().abs_diff(seq2.len());
+        let (len, trailing_weight) = match self.trailing_penalty {
+            Some(penalty) => {
+                param_count += trailing as isize;
+                let trailing_weight = penalty * trailing as f32;
+                (
+                    (max_sim_tokens as f32 + trailing_weight).max(1.0),
+                    trailing_weight,
+                )
+            }
+            None => (seq1.len().max(seq2.len()) as f32, 0.0),
+        };
+
+        for (token1, token2) in seq1.iter().zip(seq2.iter()) {
+            if token1 == &self.param_str
+                || class_wildcard(&self.token_classes, token2) == Some(token1.as_str())
+            {
+                param_count += 1;
+                positional_params += 1;
+                if include_params {
+                    continue;
+                }
+            } else if self.same_token(token1, token2) {
+                sim_tokens += 1;
+                continue;
+            }
+            max_sim_tokens -= 1;
+            // With `include_params`, `max_sim_tokens` already counts the wildcards seen so far,
+            // and the trailing positions add their weight at the end.
+            let max_sim = if include_params {
+                max_sim_tokens as f32 + trailing_weight
+            } else {
+                max_sim_tokens as f32
+            };
+            if (max_sim / len) < min_sim {
+                return None;
+            }
+        }
+        // Trailing positions weigh what they add to `len`, so the similarity stays within 1.0.
+        let sim = if include_params {
+            (sim_tokens + positional_params) as f32 + trailing_weight
+        } else {
+            sim_tokens as f32
+        };
+        Some(((sim / len).min(1.0), param_count))
+    }
+
+    #[allow(clippy::explicit_counter_loop)]
+    fn add_seq_to_prefix_tree(&mut self, cluster: &mut LogCluster) {
+        let token_count = cluster.log_template_tokens.len();
+        let token_count_str = self.length_key(token_count);
+
+        if token_count == 1 {
+            let key = case_key(&self.case_insensitive, &cluster.log_template_tokens[0]);
+            self.single_token_clusters
+                .insert(key.into_owned(), cluster.cluster_id);
+        }
+
+        let mut cur_node: &mut Node = self
+            .root
+            .key_to_child_node
+            .entry(token_count_str)
+            .or_default();
+
+        if token_count == 0 {
+            cur_node.cluster_ids.push(cluster.cluster_id);
+            return;
+        }
+
+        let mut current_depth = 1;
+        for token in cluster.log_template_tokens.iter() {
+            if current_depth >= self.max_node_depth || current_depth >= token_count {
+                let mut new_cluster_ids = Vec::new();
+                for cluster_id in cur_node
+                    .cluster_ids
+                    .iter()
+                    // With `IdStrategy::TemplateHash`, a recreated cluster reuses its id.
+                    .filter(|cluster_id| {
+                        **cluster_id != cluster.cluster_id
+                            && self.id_to_cluster.contains(cluster_id)
+                    })
+                {
+                    new_cluster_ids.push(*cluster_id);
+                }
+                new_cluster_ids.push(cluster.cluster_id);
+                cur_node.cluster_ids = new_cluster_ids;
+                break;
+            }
+
+            let key = case_key(&self.case_insensitive, token);
+            if !cur_node.key_to_child_node.contains_key(key.as_ref()) {
+                if let Some(wildcard) = class_wildcard(&self.token_classes, token) {
+                    cur_node = cur_node
+                        .key_to_child_node
+                        .entry(wildcard.to_string())
+                        .or_default();
+                } else if !has_number(token) {
+                    if cur_node.key_to_child_node.contains_key(&self.param_str) {
+                        if cur_node.key_to_child_node.len() < self.max_children {
+                            let new_node = Node::default();
+                            cur_node.key_to_child_node.insert(key.to_string(), new_node);
+                            cur_node = cur_node.key_to_child_node.get_mut(key.as_ref()).unwrap();
+                        } else {
+                            self.saturated_nodes += cur_node.saturate() as usize;
+                            cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
+                        }
+                    } else if cur_node.key_to_child_node.len() + 1 < self.max_children {
+                        let new_node = Node::default();
+                        cur_node.key_to_child_node.insert(key.to_string(), new_node);
+                        cur_node = cur_node.key_to_child_node.get_mut(key.as_ref()).unwrap();
+                    } else if cur_node.key_to_child_node.len() + 1 == self.max_children {
+                        self.saturated_nodes += cur_node.saturate() as usize;
+                        let new_node = Node::default();
+                        cur_node
+                            .key_to_child_node
+                            .insert(self.param_str.clone(), new_node);
+                        cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
+                    } else {
+                        self.saturated_nodes += cur_node.saturate() as usize;
+                        // Class wildcard children may have filled the node before `param_str`.
+                        cur_node = cur_node
+                            .key_to_child_node
+                            .entry(self.param_str.clone())
+                            .or_default();
+                    }
+                } else if !cur_node.key_to_child_node.contains_key(&self.param_str) {
+                    let new_node = Node::default();
+                    cur_node
+                        .key_to_child_node
+                        .insert(self.param_str.clone(), new_node);
+                    cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
+                } else {
+                    cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
+                }
+            } else {
+                cur_node = cur_node.key_to_child_node.get_mut(key.as_ref()).unwrap();
+            }
+
+            current_depth += 1;
+        }
+    }
+
+    fn create_template(&self, seq1: &[String], seq2: &[String]) -> Vec<String> {
+        let mut new_template_tokens = Vec::new();
+        for (token1, token2) in seq1.iter().zip(seq2.iter()) {
+            if self.same_token(token1, token2) {
+                new_template_tokens.push(token2.as_str());
+            } else {
+                new_template_tokens.push(self.param_for(token1, token2));
+            }
+        }
+        new_template_tokens.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Like `create_template`, but a position is replaced with `param_str`
+    /// only after `min_distinct_values` distinct values have been seen there,
+    /// so that a single outlier does not wildcard an otherwise stable position.
+    fn create_template_with_counts(
+        &self,
+        tokens: &[String],
+        cluster: &mut LogCluster,
+        min_distinct_values: usize,
+    ) -> Vec<String> {
+        cluster
+            .position_values
+            .resize_with(cluster.log_template_tokens.len(), HashSet::new);
+
+        let mut new_template_tokens = Vec::new();
+        for ((token, template_token), values) in tokens
+            .iter()
+            .zip(cluster.log_template_tokens.iter())
+            .zip(cluster.position_values.iter_mut())
+        {
+            if self.same_token(token, template_token) {
+                new_template_tokens.push(template_token.clone());
+                continue;
+            }
+            if template_token == &self.param_str
+                || template_token == NUM_PARAM_STR
+                || self
+                    .token_classes
+                    .iter()
+                    .any(|(_, wildcard)| wildcard == template_token)
+            {
+                new_template_tokens.push(self.param_for(token, template_token).to_string());
+                continue;
+            }
+
+            if values.is_empty() {
+                values.insert(case_key(&self.case_insensitive, template_token).into_owned());
+            }
+            values.insert(case_key(&self.case_insensitive, token).into_owned());
+            if values.len() >= min_distinct_values {
+                values.clear();
+                new_template_tokens.push(self.param_for(token, template_token).to_string());
+            } else {
+                new_template_tokens.push(template_token.clone());
+            }
+        }
+        new_template_tokens
+    }
+
+    /// Choose the wildcard for a position where `token` differs from `template_token`.
+    /// Variations within a class of `with_token_class` become its wildcard,
+    /// and with `typed_params`, numeric variations become `NUM_PARAM_STR`.
+    fn param_for<'a>(&'a self, token: &str, template_token: &str) -> &'a str {
+        if let Some(wildcard) = class_wildcard(&self.token_classes, token) {
+            if template_token == wildcard
+                || class_wildcard(&self.token_classes, template_token) == Some(wildcard)
+            {
+                return wildcard;
+            }
+        }
+        if self.typed_params
+            && is_number(token)
+            && (is_number(template_token) || template_token == NUM_PARAM_STR)
+        {
+            NUM_PARAM_STR
+        } else {
+            &self.param_str
+        }
+    }
+}
+
+/// Wildcard for positions where only numbers have been seen, used with `typed_params`.
+pub const NUM_PARAM_STR: &str = "<NUM>";
+/// Key of the first level of the prefix tree with `Drain::with_mixed_lengths`.
+pub const MIXED_LENGTHS_KEY: &str = "*";
+/// Number of buckets the window of `Drain::with_window` is divided into.
+pub const WINDOW_BUCKETS: u64 = 60;
+/// Time constant of `LogCluster::rate` in seconds.
+pub const RATE_TIME_CONSTANT_SECS: f64 = 10.0;
+
+fn is_number(s: &str) -> bool {
+    s.chars().any(|c| c.is_ascii_digit()) && s.parse::<f64>().is_ok()
+}
+
+fn has_number(s: &str) -> bool {
+    s.chars().any(|c| c.is_numeric())
+}
+
+/// Marker appended to the tokens of a truncated log message.
+pub const TRUNCATED_STR: &str = "<...>";
+
+/// Characters handled by `PunctuationPolicy`.
+const PUNCTUATIONS: [char; 9] = ['(', ')', '[', ']', '{', '}', ',', ';', ':'];
+
+/// Split the token so that each of `PUNCTUATIONS` becomes a token of its own.
+fn split_punctuation(token: &str) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+    for (i, c) in token.char_indices() {
+        if PUNCTUATIONS.contains(&c) {
+            if start < i {
+                ret.push(&token[start..i]);
+            }
+            ret.push(&token[i..i + c.len_utf8()]);
+            start = i + c.len_utf8();
+        }
+    }
+    if start < token.len() {
+        ret.push(&token[start..]);
+    }
+    ret
+}
+
+/// Whether `token` is a facility such as `sshd:`, see `Drain::with_facility`.
+fn is_facility(token: &str) -> bool {
+    token.strip_suffix(':').is_some_and(|name| {
+        name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    })
+}
+
+/// Split the leading facility from the rest of the log message, if any.
+fn split_facility(log_message: &str) -> Option<(&str, &str)> {
+    let log_message = log_message.trim_start();
+    let end = log_message
+        .find(char::is_whitespace)
+        .unwrap_or(log_message.len());
+    let (facility, rest) = log_message.split_at(end);
+    is_facility(facility).then_some((facility, rest))
+}
+
+/// Split the log message into tokens by whitespace.
+/// With `tab_delimited`, a message containing tabs is split by tabs only,
+/// so that columns containing spaces (or empty columns) are kept as single tokens.
+/// Then each token is processed by `punctuation`.
+fn tokenize(
+    log_message: &str,
+    max_tokens: Option<usize>,
+    tab_delimited: bool,
+    punctuation: PunctuationPolicy,
+    dedup_tokens: bool,
+) -> Vec<String> {
+    let tokens: Box<dyn Iterator<Item = &str>> = if tab_delimited && log_message.contains('\t') {
+        Box::new(log_message.split('\t').map(str::trim))
+    } else {
+        Box::new(log_message.split_whitespace())
+    };
+    process_tokens(tokens, max_tokens, punctuation, dedup_tokens)
+}
+
+/// Escape `s` for a quoted Graphviz DOT string.
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Process the tokens split from a log message by `punctuation`,
+/// collapse runs of identical tokens with `dedup_tokens`,
+/// and truncate them to `max_tokens`.
+fn process_tokens<'a>(
+    tokens: Box<dyn Iterator<Item = &'a str> + 'a>,
+    max_tokens: Option<usize>,
+    punctuation: PunctuationPolicy,
+    dedup_tokens: bool,
+) -> Vec<String> {
+    let mut tokens: Box<dyn Iterator<Item = &str>> = match punctuation {
+        PunctuationPolicy::Keep => tokens,
+        PunctuationPolicy::Strip => Box::new(
+            tokens
+                .map(|token| token.trim_matches(|c| PUNCTUATIONS.contains(&c)))
+                .filter(|token| !token.is_empty()),
+        ),
+        PunctuationPolicy::Split => Box::new(tokens.flat_map(split_punctuation)),
+    };
+    if dedup_tokens {
+        let mut prev = None;
+        tokens = Box::new(tokens.filter(move |token| prev.replace(*token) != Some(*token)));
+    }
+    match max_tokens {
+        Some(max_tokens) => {
+            let mut ret: Vec<String> = tokens
+                .by_ref()
+                .take(max_tokens)
+                .map(|s| s.to_string())
+                .collect();
+            if tokens.next().is_some() {
+                ret.push(TRUNCATED_STR.to_string());
+            }
+            ret
+        }
+        None => tokens.map(|s| s.to_string()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    mod train {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let logs = vec![
+                "connected to 10.0.0.1",
+                "connected to 10.0.0.2",
+                "connected to 10.0.0.3",
+                "Hex number 0xDEADBEAF",
+                "Hex number 0x10000",
+                "user davidoh logged in",
+                "user eranr logged in",
+            ];
+            let mut drain = Drain::default();
+            for log in logs {
+                drain.train(log);
+            }
+            let mut clusters = drain.clusters();
+            clusters.sort_by_key(|c| c.cluster_id);
+            assert_eq!(
+                clusters,
+                vec![
+                    &LogCluster {
+                        log_template_tokens: vec![
+                            String::from("connected"),
+                            String::from("to"),
+                            String::from("<*>"),
+                        ],
+                        cluster_id: 1,
+                        size: 3,
+                        position_values: vec![],
+                        rate: 0.0,
+                        last_arrival: None,
+                        window_counts: VecDeque::new(),
+                        shape: None,
+                        label: None,
+                    },
+                    &LogCluster {
+                        log_template_tokens: vec![
+                            String::from("Hex"),
+                            String::from("number"),
+                            String::from("<*>"),
+                        ],
+                        cluster_id: 2,
+                        size: 2,
+                        position_values: vec![],
+                        rate: 0.0,
+                        last_arrival: None,
+                        window_counts: VecDeque::new(),
+                        shape: None,
+                        label: None,
+                    },
+                    &LogCluster {
+                        log_template_tokens: vec![
+                            String::from("user"),
+                            String::from("<*>"),
+                            String::from("logged"),
+                            String::from("in"),
+                        ],
+                        cluster_id: 3,
+                        size: 2,
+                        position_values: vec![],
+                        rate: 0.0,
+                        last_arrival: None,
+                        window_counts: VecDeque::new(),
+                        shape: None,
+                        label: None,
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_significant_count() {
+            let mut drain = Drain::default();
+            for log in [
+                "connected to 10.0.0.1",
+                "connected to 10.0.0.2",
+                "connected to 10.0.0.3",
+                "user davidoh logged in",
+                "user eranr logged in",
+                "disk is full",
+            ] {
+                drain.train(log);
+            }
+            assert_eq!(drain.significant_count(3), 1);
+            assert_eq!(drain.significant_count(2), 2);
+            assert_eq!(drain.significant_count(1), 3);
+        }
+
+        #[test]
+        fn test_summaries() {
+            let mut drain = Drain::default();
+            for log in [
+                "connected to 10.0.0.1",
+                "connected to 10.0.0.2",
+                "connected to 10.0.0.3",
+                "Hex number 0xDEADBEAF",
+                "Hex number 0x10000",
+                "user davidoh logged in",
+                "user eranr logged in",
+            ] {
+                drain.train(log);
+            }
+            let mut summaries = drain.summaries();
+            summaries.sort();
+            assert_eq!(
+                summaries,
+                vec![
+                    (String::from("Hex number <*>"), 2),
+                    (String::from("connected to <*>"), 3),
+                    (String::from("user <*> logged in"), 2),
+                ]
+            );
+        }
+    }
+
+    mod rebuild {
+        use super::*;
+
+        fn keys(drain: &Drain) -> Vec<(usize, String)> {
+            let mut keys = Vec::new();
+            drain.walk_tree(|depth, key, _| keys.push((depth, key.to_string())));
+            keys
+        }
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("alpha started ok");
+            drain.train("beta started ok");
+            assert_eq!(drain.clusters().len(), 2);
+
+            // The clusters stay under their first tokens until the tree is rebuilt.
+            drain.max_node_depth = 1;
+            assert!(drain.match_log("gamma started ok").is_none());
+            assert_eq!(
+                keys(&drain),
+                vec![
+                    (0, String::from("3")),
+                    (1, String::from("alpha")),
+                    (1, String::from("beta")),
+                ]
+            );
+
+            drain.rebuild();
+            assert_eq!(keys(&drain), vec![(0, String::from("3"))]);
+            let cluster = drain.match_log("gamma started ok").unwrap();
+            assert!(cluster.cluster_id <= 2);
+            assert_eq!(drain.clusters().len(), 2);
+        }
+    }
+
+    mod tokenize {
+        use super::*;
+
+        #[test]
+        fn test_truncate() {
+            let log = vec!["token"; 10_000].join(" ");
+            let tokens = tokenize(&log, Some(8), false, PunctuationPolicy::Keep, false);
+            assert_eq!(tokens.len(), 9);
+            assert_eq!(tokens.last().unwrap(), TRUNCATED_STR);
+
+            let mut drain = Drain {
+                max_tokens: Some(8),
+                ..Default::default()
+            };
+            let cluster = drain.train(&log);
+            assert_eq!(cluster.log_template_tokens.len(), 9);
+        }
+
+        #[test]
+        fn test_no_truncate() {
+            assert_eq!(
+                tokenize("a b c", Some(3), false, PunctuationPolicy::Keep, false),
+                vec!["a", "b", "c"]
+            );
+            assert_eq!(
+                tokenize("a b c", None, false, PunctuationPolicy::Keep, false),
+                vec!["a", "b", "c"]
+            );
+        }
+
+        #[test]
+        fn test_punctuation() {
+            assert_eq!(
+                tokenize(
+                    "(id=5), value;",
+                    None,
+                    false,
+                    PunctuationPolicy::Strip,
+                    false
+                ),
+                vec!["id=5", "value"]
+            );
+            assert_eq!(
+                tokenize(
+                    "error(42) [a,b]",
+                    None,
+                    false,
+                    PunctuationPolicy::Split,
+                    false
+                ),
+                vec!["error", "(", "42", ")", "[", "a", ",", "b", "]"]
+            );
+
+            let mut drain = Drain {
+                punctuation: PunctuationPolicy::Split,
+                ..Default::default()
+            };
+            drain.train("error(42)");
+            assert_eq!(drain.train("error(99)").to_string(), "error ( <*> )");
+        }
+
+        #[test]
+        fn test_dedup_tokens() {
+            assert_eq!(
+                tokenize("a a b", None, false, PunctuationPolicy::Keep, true),
+                vec!["a", "b"]
+            );
+            assert_eq!(
+                tokenize("a a b a", None, false, PunctuationPolicy::Keep, false),
+                vec!["a", "a", "b", "a"]
+            );
+            assert_eq!(
+                tokenize("a a a b b c", Some(2), false, PunctuationPolicy::Keep, true),
+                vec!["a", "b", TRUNCATED_STR]
+            );
+
+            let mut drain = Drain::default().with_dedup_tokens(true);
+            drain.train("error error connection reset");
+            let cluster = drain.train("error connection reset");
+            assert_eq!(cluster.size, 2);
+            assert_eq!(cluster.to_string(), "error connection reset");
+        }
+
+        #[test]
+        fn test_tab_delimited() {
+            assert_eq!(
+                tokenize(
+                    "GET\t\tnot found\t404",
+                    None,
+                    true,
+                    PunctuationPolicy::Keep,
+                    false
+                ),
+                vec!["GET", "", "not found", "404"]
+            );
+            assert_eq!(
+                tokenize(
+                    "GET\t\tnot found\t404",
+                    None,
+                    false,
+                    PunctuationPolicy::Keep,
+                    false
+                ),
+                vec!["GET", "not", "found", "404"]
+            );
+            assert_eq!(
+                tokenize("no tabs here", None, true, PunctuationPolicy::Keep, false),
+                vec!["no", "tabs", "here"]
+            );
+        }
+    }
+
+    mod create_template_with_counts {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain {
+                min_distinct_values: Some(3),
+                ..Default::default()
+            };
+            drain.train("request GET /users done");
+            drain.train("request GET /users done");
+            // A single anomalous method does not wildcard the position.
+            let cluster = drain.train("request POST /users done");
+            assert_eq!(cluster.to_string(), "request GET /users done");
+            // The third distinct value does.
+            let cluster = drain.train("request PUT /users done");
+            assert_eq!(cluster.to_string(), "request <*> /users done");
+            assert_eq!(cluster.size, 4);
+        }
+
+        #[test]
+        fn test_disabled() {
+            let mut drain = Drain::default();
+            drain.train("request GET /users done");
+            let cluster = drain.train("request POST /users done");
+            assert_eq!(cluster.to_string(), "request <*> /users done");
+            assert!(cluster.position_values.is_empty());
+        }
+    }
+
+    mod create_template {
+        use super::*;
+
+        #[test]
+        fn test_typed_params() {
+            let mut drain = Drain {
+                typed_params: true,
+                ..Default::default()
+            };
+            drain.train("x 1");
+            assert_eq!(drain.train("x 2").to_string(), "x <NUM>");
+            assert_eq!(drain.train("x 3").to_string(), "x <NUM>");
+            drain.train("y a");
+            assert_eq!(drain.train("y b").to_string(), "y <*>");
+        }
+
+        #[test]
+        fn test_untyped_params() {
+            let mut drain = Drain::default();
+            drain.train("x 1");
+            assert_eq!(drain.train("x 2").to_string(), "x <*>");
+        }
+    }
+
+    mod with_token_class {
+        use super::*;
+
+        #[test]
+        fn test_of() {
+            assert_eq!(TokenClass::of("0xDEAD"), Some(TokenClass::Hex));
+            assert_eq!(TokenClass::of("12345"), Some(TokenClass::Decimal));
+            assert_eq!(TokenClass::of("req-ab12"), Some(TokenClass::Id));
+            assert_eq!(TokenClass::of("0x"), Some(TokenClass::Id));
+            assert_eq!(TokenClass::of("10.0.0.1"), None);
+            assert_eq!(TokenClass::of("alice"), None);
+        }
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for class in [TokenClass::Decimal, TokenClass::Hex, TokenClass::Id] {
+                drain = drain
+                    .with_token_class(class, class.default_wildcard())
+                    .unwrap();
+            }
+            drain.train("fault at 0xDEAD");
+            assert_eq!(drain.train("fault at 0xBEEF").to_string(), "fault at <HEX>");
+            drain.train("read 12345 bytes");
+            assert_eq!(drain.train("read 42 bytes").to_string(), "read <NUM> bytes");
+            drain.train("request req-ab12 done");
+            assert_eq!(
+                drain.train("request req-cd34 done").to_string(),
+                "request <ID> done"
+            );
+            drain.train("value 0x1F now");
+            assert_eq!(drain.train("value 7 now").to_string(), "value <*> now");
+
+            // Tokens of a class are routed apart from the other variable tokens.
+            drain.train("0x10 mapped");
+            drain.train("512 mapped");
+            assert_eq!(drain.explain_match("0x20 mapped").path, vec!["<HEX>"]);
+            assert_eq!(drain.explain_match("64 mapped").path, vec!["<NUM>"]);
+        }
+
+        #[test]
+        fn test_max_children() {
+            // A class child alone fills the node; other tokens still need a `param_str` child.
+            let mut drain = Drain {
+                max_children: 1,
+                ..Default::default()
+            }
+            .with_token_class(TokenClass::Hex, "<HEX>")
+            .unwrap();
+            drain.train("0x1F mapped now");
+            assert_eq!(
+                drain.train("page mapped now").to_string(),
+                "page mapped now"
+            );
+            assert_eq!(drain.explain_match("page mapped now").path, vec!["<*>"]);
+        }
+
+        #[test]
+        fn test_invalid() {
+            for wildcard in ["", "<a b>", DEFAULT_PARAM_STR] {
+                assert_eq!(
+                    Drain::default()
+                        .with_token_class(TokenClass::Hex, wildcard)
+                        .err(),
+                    Some(DrainError::InvalidClassWildcard(wildcard.to_string()))
+                );
+            }
+        }
+    }
+
+    mod walk_tree {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("connected to 10.0.0.1");
+            drain.train("connected to 10.0.0.2");
+            drain.train("user davidoh logged in");
+
+            let mut visited = Vec::new();
+            drain.walk_tree(|depth, key, cluster_ids| {
+                visited.push((depth, key.to_string(), cluster_ids.to_vec()));
+            });
+            assert_eq!(
+                visited,
+                vec![
+                    (0, String::from("3"), vec![]),
+                    (1, String::from("connected"), vec![1]),
+                    (0, String::from("4"), vec![]),
+                    (1, String::from("user"), vec![2]),
+                ]
+            );
+        }
+    }
+
+    mod new {
+        use super::*;
+
+        fn new(
+            max_clusters: Option<usize>,
+            sim_th: f32,
+            max_children: usize,
+            param_str: &str,
+        ) -> Result<Drain, DrainError> {
+            Drain::new(max_clusters, 2, sim_th, max_children, param_str.to_string())
+        }
+
+        #[test]
+        fn test() {
+            assert!(new(Some(10), 0.4, 100, "<*>").is_ok());
+            assert_eq!(
+                new(None, 1.5, 100, "<*>").unwrap_err(),
+                DrainError::InvalidSimThreshold(1.5)
+            );
+            assert_eq!(
+                new(None, 0.4, 100, "<a b>").unwrap_err(),
+                DrainError::InvalidParamStr(String::from("<a b>"))
+            );
+            assert_eq!(
+                new(None, 0.4, 0, "<*>").unwrap_err(),
+                DrainError::ZeroMaxChildren
+            );
+            assert_eq!(
+                new(Some(0), 0.4, 100, "<*>").unwrap_err(),
+                DrainError::ZeroMaxClusters
+            );
+        }
+    }
+
+    mod tree_search {
+        use super::*;
+
+        #[test]
+        fn test_single_token() {
+            let logs = ["OK", "FAIL", "OK", "<*>", "TIMEOUT", "OK", "FAIL", "<*>"];
+
+            let mut drain = Drain::default();
+            for log in logs {
+                drain.train(log);
+            }
+            for log in ["OK", "FAIL", "TIMEOUT", "<*>", "UNKNOWN"] {
+                let tokens = tokenize(log, None, false, PunctuationPolicy::Keep, false);
+                assert_eq!(
+                    drain.tree_search(&tokens, None, drain.sim_th, false),
+                    drain.tree_search_slow(&tokens, None, drain.sim_th, false),
+                );
+            }
+
+            let mut summaries = drain.summaries();
+            summaries.sort();
+            assert_eq!(
+                summaries,
+                vec![
+                    (String::from("<*>"), 1),
+                    (String::from("<*>"), 1),
+                    (String::from("FAIL"), 2),
+                    (String::from("OK"), 3),
+                    (String::from("TIMEOUT"), 1),
+                ]
+            );
+        }
+
+        #[test]
+        fn test_single_token_evicted() {
+            let mut drain = Drain {
+                id_to_cluster: LruCache::new(NonZeroUsize::new(1).unwrap()),
+                ..Default::default()
+            };
+            assert_eq!(drain.train("OK").cluster_id, 1);
+            assert_eq!(drain.train("FAIL").cluster_id, 2);
+            let cluster = drain.train("OK");
+            assert_eq!(cluster.cluster_id, 3);
+            assert_eq!(cluster.size, 1);
+        }
+    }
+
+    mod evict_over_token_budget {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain {
+                max_total_tokens: Some(250),
+                ..Default::default()
+            };
+            let large = |prefix: &str| {
+                (0..100)
+                    .map(|i| format!("{}{}", prefix, i))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            drain.train(large("a"));
+            drain.train(large("b"));
+            assert_eq!(drain.total_tokens, 200);
+            assert_eq!(drain.clusters().len(), 2);
+
+            // The third cluster exceeds the budget and evicts the oldest one.
+            let cluster = drain.train(large("c"));
+            assert_eq!(drain.total_tokens, 200);
+            let ids: Vec<usize> = drain
+                .clusters_by_id()
+                .iter()
+                .map(|c| c.cluster_id)
+                .collect();
+            assert_eq!(ids, vec![2, cluster.cluster_id]);
+        }
+
+        #[test]
+        fn test_max_clusters() {
+            let mut drain = Drain {
+                id_to_cluster: LruCache::new(NonZeroUsize::new(1).unwrap()),
+                ..Default::default()
+            };
+            drain.train("a b c");
+            drain.train("d e");
+            assert_eq!(drain.total_tokens, 2);
+        }
+
+        #[test]
+        fn test_template_growth() {
+            let mut drain = Drain::default()
+                .with_mixed_lengths(true)
+                .with_max_total_tokens(Some(5));
+            drain.train("x y");
+            drain.train("a b");
+            assert_eq!(drain.total_tokens, 4);
+
+            // The grown template exceeds the budget and evicts the other cluster.
+            let cluster = drain.train("a b e f g");
+            assert_eq!(cluster.to_string(), "a b <*> <*> <*>");
+            assert_eq!(drain.total_tokens, 5);
+            assert_eq!(drain.clusters_by_id(), vec![&cluster]);
+        }
+    }
+
+    mod train_with_info {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            let info = drain.train_with_info("connected to 10.0.0.1");
+            assert_eq!(info.cluster_id, 1);
+            assert_eq!(info.outcome, TrainOutcome::Created);
+            assert_eq!(info.previous_template, None);
+            let info = drain.train_with_info("connected to 10.0.0.2");
+            assert_eq!(info.outcome, TrainOutcome::TemplateChanged);
+            assert_eq!(info.template, "connected to <*>");
+            assert_eq!(
+                info.previous_template.as_deref(),
+                Some("connected to 10.0.0.1")
+            );
+            let info = drain.train_with_info("connected to 10.0.0.3");
+            assert_eq!(info.outcome, TrainOutcome::Matched);
+            assert_eq!(info.size, 3);
+            assert_eq!(info.previous_template, None);
+        }
+
+        #[test]
+        fn test_send() {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let mut drain = Drain::default();
+            let handle = std::thread::spawn(move || rx.iter().collect::<Vec<TrainInfo>>());
+            for log in ["a 1", "a 2", "b c"] {
+                tx.send(drain.train_with_info(log)).unwrap();
+            }
+            drop(tx);
+            let infos = handle.join().unwrap();
+            assert_eq!(infos.len(), 3);
+            assert_eq!(drain.clusters().len(), 2);
+        }
+    }
+
+    mod train_tokens {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            let tokens = |tokens: &[&str]| tokens.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            drain.train_tokens(tokens(&["user", "John Smith", "logged in"]));
+            let cluster = drain.train_tokens(tokens(&["user", "Jane Doe", "logged in"]));
+            assert_eq!(
+                cluster.log_template_tokens,
+                tokens(&["user", "<*>", "logged in"])
+            );
+
+            let matched = drain
+                .match_tokens(&tokens(&["user", "Max Mustermann", "logged in"]))
+                .unwrap();
+            assert_eq!(matched.cluster_id, cluster.cluster_id);
+            assert_eq!(matched.size, 2);
+            assert!(drain.match_tokens(&tokens(&["disk", "full"])).is_none());
+        }
+    }
+
+    mod size_histogram {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            assert!(drain.size_histogram().is_empty());
+            drain.train("user alice logged in");
+            drain.train("user bob logged in");
+            drain.train("disk is full");
+            drain.train("connection reset by peer");
+            assert_eq!(drain.size_histogram(), BTreeMap::from([(1, 2), (2, 1)]));
+        }
+    }
+
+    mod saturated_nodes {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain {
+                max_children: 3,
+                ..Default::default()
+            };
+            drain.train("alpha started");
+            drain.train("beta started");
+            assert_eq!(drain.saturated_nodes(), 0);
+
+            // The third distinct first token fills the node with the wildcard child.
+            drain.train("gamma started");
+            assert_eq!(drain.saturated_nodes(), 1);
+            drain.train("delta started");
+            assert_eq!(drain.saturated_nodes(), 1);
+
+            drain.rebuild();
+            assert_eq!(drain.saturated_nodes(), 1);
+        }
+    }
+
+    mod get_seq_distance {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let drain = Drain::default();
+            let seq = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+            let tokens = seq("a b <*> d");
+            let template = seq("a x c d");
+
+            assert_eq!(
+                drain.get_seq_distance(&tokens, &template, false, 0.0),
+                Some((0.5, 1))
+            );
+            assert_eq!(
+                drain.get_seq_distance(&tokens, &template, true, 0.75),
+                Some((0.75, 1))
+            );
+            // `b` and `<*>` leave at most two of four similar tokens.
+            assert_eq!(drain.get_seq_distance(&tokens, &template, false, 0.6), None);
+        }
+    }
+
+    mod max_node_depth {
+        use super::*;
+
+        fn train(max_node_depth: usize) -> Drain {
+            let mut drain = Drain {
+                max_node_depth,
+                ..Default::default()
+            };
+            for log in ["alpha started ok", "beta started ok", "beta started ok"] {
+                drain.train(log);
+            }
+            drain
+        }
+
+        fn keys(drain: &Drain) -> Vec<(usize, String)> {
+            let mut keys = Vec::new();
+            drain.walk_tree(|depth, key, _| keys.push((depth, key.to_string())));
+            keys
+        }
+
+        #[test]
+        fn test_token_count_only() {
+            for max_node_depth in [0, 1] {
+                let drain = train(max_node_depth);
+                assert_eq!(keys(&drain), vec![(0, String::from("3"))]);
+                let summaries = drain.summaries();
+                assert_eq!(
+                    summaries,
+                    vec![(String::from("<*> started ok"), 3)],
+                    "max_node_depth = {}",
+                    max_node_depth
+                );
+            }
+        }
+
+        #[test]
+        fn test_first_token() {
+            let drain = train(2);
+            assert_eq!(
+                keys(&drain),
+                vec![
+                    (0, String::from("3")),
+                    (1, String::from("alpha")),
+                    (1, String::from("beta")),
+                ]
+            );
+            assert_eq!(drain.clusters().len(), 2);
+        }
+    }
+
+    mod with_case_insensitive {
+        use super::*;
+
+        fn is_hex(token: &str) -> bool {
+            token.len() > 2 && token[..2].eq_ignore_ascii_case("0x")
+        }
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default().with_case_insensitive(is_hex);
+            drain.train("0xAB");
+            let cluster = drain.train("0xab");
+            assert_eq!(cluster.to_string(), "0xAB");
+            assert_eq!(cluster.size, 2);
+
+            drain.train("Error disk full");
+            drain.train("error disk full");
+            assert_eq!(drain.clusters().len(), 3);
+        }
+
+        #[test]
+        fn test_routing() {
+            let mut drain =
+                Drain::default().with_case_insensitive(|token| token.eq_ignore_ascii_case("error"));
+            drain.train("Error disk full");
+            let cluster = drain.train("ERROR disk full");
+            assert_eq!(cluster.to_string(), "Error disk full");
+            assert_eq!(cluster.size, 2);
+            assert_eq!(drain.clusters().len(), 1);
+
+            drain.rebuild();
+            let cluster =
+                drain.match_tokens(&["error".to_string(), "disk".to_string(), "full".to_string()]);
+            assert_eq!(cluster.map(|cluster| cluster.size), Some(2));
+        }
+    }
+
+    mod with_wildcard_runs {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let logs = ["a 1 2 b 3", "a 4 5 b 6"];
+            let drain = Drain::default();
+            let mut collapsed = drain.clone().with_wildcard_runs(WildcardRuns::Collapse);
+            let mut counted = drain.with_wildcard_runs(WildcardRuns::Count);
+            for log in logs {
+                collapsed.train(log);
+                counted.train(log);
+            }
+            let cluster = collapsed.clusters()[0];
+            assert_eq!(collapsed.template(cluster), "a <*> b <*>");
+            assert_eq!(cluster.to_string(), "a <*> <*> b <*>");
+            assert_eq!(counted.template(counted.clusters()[0]), "a <*>{2} b <*>");
+
+            let info = counted.train_with_info("a 7 8 b 9");
+            assert_eq!(info.template, "a <*>{2} b <*>");
+        }
+
+        #[test]
+        fn test_from_str() {
+            assert_eq!("count".parse(), Ok(WildcardRuns::Count));
+            assert!("merge".parse::<WildcardRuns>().is_err());
+        }
+    }
+
+    mod with_separator {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default().with_separator("|");
+            drain.train("connected to 10.0.0.1");
+            let info = drain.train_with_info("connected to 10.0.0.2");
+            assert_eq!(info.template, "connected|to|<*>");
+            assert_eq!(
+                info.previous_template.as_deref(),
+                Some("connected|to|10.0.0.1")
+            );
+
+            let cluster = drain.clusters()[0];
+            assert_eq!(drain.template(cluster), "connected|to|<*>");
+            assert_eq!(cluster.to_string(), "connected to <*>");
+            assert_eq!(
+                drain.summaries(),
+                vec![(String::from("connected|to|<*>"), 2)]
+            );
+        }
+    }
+
+    mod with_id_strategy {
+        use super::*;
+
+        #[test]
+        fn test_template_hash() {
+            let train = || {
+                let mut drain = Drain::default().with_id_strategy(IdStrategy::TemplateHash);
+                let ids: Vec<usize> = ["disk full", "user alice logged in", "user bob logged in"]
+                    .into_iter()
+                    .map(|log| drain.train(log).cluster_id)
+                    .collect();
+                ids
+            };
+            let ids = train();
+            assert_eq!(ids, train());
+            assert_ne!(ids[0], ids[1]);
+            assert_eq!(ids[1], ids[2]);
+        }
+
+        #[test]
+        fn test_collision() {
+            let mut drain = Drain::default().with_id_strategy(IdStrategy::TemplateHash);
+            let tokens = vec![String::from("disk"), String::from("full")];
+            let cluster_id = hash_tokens(&tokens) as usize;
+            // Occupy the id with an unrelated cluster.
+            drain.id_to_cluster.put(
+                cluster_id,
+                LogCluster {
+                    log_template_tokens: vec![String::from("other")],
+                    cluster_id,
+                    size: 1,
+                    position_values: vec![],
+                    rate: 0.0,
+                    last_arrival: None,
+                    window_counts: VecDeque::new(),
+                    shape: None,
+                    label: None,
+                },
+            );
+            let cluster = drain.train_tokens(tokens);
+            assert_eq!(cluster.cluster_id, cluster_id.wrapping_add(1));
+            assert_eq!(drain.clusters().len(), 2);
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    mod find_clusters {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for log in [
+                "connection timeout after 30s",
+                "disk full",
+                "read timeout on socket 3",
+            ] {
+                drain.train(log);
+            }
+            let pattern = regex::Regex::new("timeout").unwrap();
+            let templates: Vec<String> = drain
+                .find_clusters(&pattern)
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect();
+            assert_eq!(
+                templates,
+                vec!["connection timeout after 30s", "read timeout on socket 3"]
+            );
+            assert!(drain
+                .find_clusters(&regex::Regex::new("^panic").unwrap())
+                .is_empty());
+        }
+    }
+
+    mod with_tie_break {
+        use super::*;
+
+        /// Train `a <*> c d` (2 messages) and `a b x d` (3 messages) as separate clusters,
+        /// which are equally similar (3 of 4 tokens) to `a b c d`.
+        fn tied(tie_break: TieBreak) -> Drain {
+            let mut drain = Drain {
+                sim_th: 0.7,
+                ..Default::default()
+            }
+            .with_tie_break(tie_break);
+            for log in ["a q c d", "a r c d", "a b x d", "a b x d", "a b x d"] {
+                drain.train(log);
+            }
+            assert_eq!(drain.clusters().len(), 2);
+            drain
+        }
+
+        fn matched(tie_break: TieBreak) -> String {
+            let drain = tied(tie_break);
+            let tokens: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+            // As `train` does, where wildcards do not count as similar.
+            drain
+                .tree_search(&tokens, None, drain.sim_th, false)
+                .unwrap()
+                .to_string()
+        }
+
+        #[test]
+        fn test() {
+            assert_eq!(matched(TieBreak::MoreParams), "a <*> c d");
+            assert_eq!(matched(TieBreak::FewerParams), "a b x d");
+            assert_eq!(matched(TieBreak::LargerCluster), "a b x d");
+            assert_eq!(matched(TieBreak::SmallerId), "a <*> c d");
+        }
+    }
+
+    mod with_update_th {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default().with_update_th(0.8).unwrap();
+            drain.train("a b c d e");
+            assert_eq!(drain.train("a b c d x").to_string(), "a b c d <*>");
+
+            // A borderline match joins the cluster but keeps its template.
+            let cluster = drain.train("a b y z w");
+            assert_eq!(cluster.to_string(), "a b c d <*>");
+            assert_eq!(cluster.size, 3);
+            assert_eq!(drain.clusters().len(), 1);
+        }
+
+        #[test]
+        fn test_invalid() {
+            assert_eq!(
+                Drain::default().with_update_th(0.1).unwrap_err(),
+                DrainError::InvalidUpdateThreshold(0.1)
+            );
+            assert!(Drain::default().with_update_th(1.5).is_err());
+        }
+    }
+
+    mod compression_ratio {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            assert!(drain.is_empty());
+            assert_eq!(drain.compression_ratio(), None);
+            drain.train("user alice logged in");
+            drain.train("user bob logged in");
+            drain.train("user carol logged in");
+            drain.train("disk is full");
+            assert_eq!(drain.len(), 2);
+            assert_eq!(drain.total_lines(), 4);
+            assert_eq!(drain.compression_ratio(), Some(2.0));
+        }
+    }
+
+    mod with_facility {
+        use super::*;
+
+        fn drain(punctuation: PunctuationPolicy) -> Drain {
+            Drain {
+                sim_th: 0.0,
+                punctuation,
+                ..Default::default()
+            }
+            .with_facility(true)
+        }
+
+        #[test]
+        fn test() {
+            let mut drain = drain(PunctuationPolicy::Keep);
+            for log in [
+                "sshd: accepted",
+                "kernel: oom",
+                "sshd: closed",
+                "disk: full",
+            ] {
+                drain.train(log);
+            }
+            // Even a line without facility does not join a facility's cluster.
+            drain.train("restarting now");
+            let templates: Vec<String> = drain
+                .clusters_by_id()
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect();
+            assert_eq!(
+                templates,
+                vec!["sshd: <*>", "kernel: oom", "disk: full", "restarting now"]
+            );
+        }
+
+        #[test]
+        fn test_punctuation() {
+            let mut drain = drain(PunctuationPolicy::Strip);
+            assert_eq!(
+                drain.train("sshd: accepted (key)").to_string(),
+                "sshd: accepted key"
+            );
+            assert_eq!(
+                drain.train("kernel: oom (key)").to_string(),
+                "kernel: oom key"
+            );
+        }
+
+        #[test]
+        fn test_is_facility() {
+            assert!(is_facility("sshd:"));
+            assert!(is_facility("systemd-logind:"));
+            assert!(!is_facility(":"));
+            assert!(!is_facility("sshd[42]:"));
+            assert!(!is_facility("12:"));
+            assert!(!is_facility("sshd"));
+        }
+    }
+
+    mod tokens {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("user alice logged in");
+            let cluster = drain.train("user bob logged in");
+            assert_eq!(cluster.id(), 1);
+            assert_eq!(cluster.size(), 2);
+            assert_eq!(cluster.tokens(), ["user", "<*>", "logged", "in"]);
+        }
+    }
+
+    mod is_wildcarded {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            let literal = drain.train("disk is full");
+            assert!(!literal.is_wildcarded(drain.param_str()));
+
+            drain.train("user alice logged in");
+            let wildcarded = drain.train("user bob logged in");
+            assert!(wildcarded.is_wildcarded(drain.param_str()));
+            assert!(drain.is_wildcarded(&wildcarded));
+        }
+
+        #[test]
+        fn test_typed_params() {
+            let mut drain = Drain::default();
+            let literal = drain.train("value <NUM>");
+            assert!(!literal.is_wildcarded(drain.param_str()));
+            assert!(!drain.is_wildcarded(&literal));
+
+            let mut drain = Drain::default().with_typed_params(true);
+            drain.train("retry 1");
+            let typed = drain.train("retry 2");
+            assert_eq!(typed.to_string(), "retry <NUM>");
+            assert!(drain.is_wildcarded(&typed));
+        }
+
+        #[test]
+        fn test_token_class() {
+            let mut drain = DrainBuilder::new()
+                .token_class(TokenClass::Hex, "<HEX>")
+                .build()
+                .unwrap();
+            drain.train("addr 0xDEAD");
+            let class = drain.train("addr 0xBEEF");
+            assert_eq!(class.to_string(), "addr <HEX>");
+            assert!(!class.is_wildcarded(drain.param_str()));
+            assert!(drain.is_wildcarded(&class));
+        }
+    }
+
+    mod train_at {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            let steady = drain.train("disk is full");
+            assert_eq!(steady.rate(), 0.0);
+
+            for at in 0..20 {
+                drain.train_at("user alice logged in", at as f64 * 10.0);
+            }
+            let mut bursting = drain.train_at("connection reset", 195.0);
+            for _ in 0..5 {
+                bursting = drain.train_at("connection reset", 196.0);
+            }
+            let user = drain.train_at("user alice logged in", 200.0);
+            assert!(user.size > bursting.size);
+            assert!(bursting.rate_at(200.0) > user.rate_at(200.0));
+
+            // The rate decays once lines stop arriving.
+            assert!(bursting.rate_at(260.0) < bursting.rate_at(200.0) / 100.0);
+            assert_eq!(
+                drain.clusters_by_id()[2].rate(),
+                bursting.rate(),
+                "the stored cluster is updated as well"
+            );
+        }
+    }
+
+    mod with_window {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default().with_window(60);
+            drain.train_at("disk is full", 0.0);
+            drain.train_at("user alice logged in", 0.0);
+            drain.train_at("user bob logged in", 30.0);
+            assert!(drain.expire(30.0).is_empty());
+            assert_eq!(drain.len(), 2);
+
+            let disk = drain.clusters_by_id()[0].cluster_id;
+            assert_eq!(drain.expire(61.0), vec![disk]);
+            let user = drain.clusters_by_id()[0];
+            assert_eq!(user.to_string(), "user <*> logged in");
+            assert_eq!(user.size, 1);
+
+            assert_eq!(drain.expire(100.0).len(), 1);
+            assert!(drain.is_empty());
+            assert_eq!(drain.total_tokens, 0);
+        }
+
+        #[test]
+        fn test_without_window() {
+            let mut drain = Drain::default();
+            drain.train_at("disk is full", 0.0);
+            assert!(drain.expire(1000.0).is_empty());
+            assert_eq!(drain.clusters()[0].size, 1);
+        }
+    }
+
+    mod reset_sizes {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("user alice logged in");
+            let before = drain.train("user bob logged in");
+            drain.train("disk is full");
+
+            drain.reset_sizes();
+            assert_eq!(drain.total_lines(), 0);
+            assert!(drain.clusters().iter().all(|cluster| cluster.size == 0));
+
+            let after = drain.train("user carol logged in");
+            assert_eq!(after.cluster_id, before.cluster_id);
+            assert_eq!(after.to_string(), "user <*> logged in");
+            assert_eq!(after.size, 1);
+            assert_eq!(drain.len(), 2);
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    mod with_masks {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let masks = vec![
+                (
+                    regex::Regex::new(r"\d+\.\d+\.\d+\.\d+").unwrap(),
+                    String::from("<IP>"),
+                ),
+                (
+                    regex::Regex::new(r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}Z").unwrap(),
+                    String::from("<TIME>"),
+                ),
+            ];
+            let mut drain = Drain {
+                sim_th: 0.9,
+                ..Default::default()
+            }
+            .with_masks(masks);
+            drain.train("2024-01-01T00:00:00Z connect from 10.0.0.1");
+            let cluster = drain.train("2024-01-02T12:30:00Z connect from 192.168.1.20");
+            assert_eq!(cluster.to_string(), "<TIME> connect from <IP>");
+            assert_eq!(cluster.size, 2);
+            // The replacement is literal, so `$` is not a group reference.
+            let masks = vec![(regex::Regex::new(r"\d+").unwrap(), String::from("$1"))];
+            let mut drain = Drain::default().with_masks(masks);
+            assert_eq!(drain.train("took 30 ms").to_string(), "took $1 ms");
+        }
+    }
+
+    mod with_delimiters {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default().with_delimiters(&[' ', '=', ',']);
+            drain.train("key1=a key2=b");
+            assert_eq!(
+                drain.train("key1=c key2=d").to_string(),
+                "key1= <*> key2= <*>"
+            );
+            assert_eq!(
+                drain.tokenize("user=alice,  id=1"),
+                vec!["user=", "alice,", "id=", "1"]
+            );
+
+            let drain = drain.with_delimiters(&[]);
+            assert_eq!(drain.tokenize("key1=a key2=b"), vec!["key1=a", "key2=b"]);
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    mod with_delimiter_regex {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let delimiter = regex::Regex::new(r"[\s,;]+").unwrap();
+            let mut drain = Drain::default().with_delimiter_regex(delimiter).unwrap();
+            assert_eq!(
+                drain.tokenize(";user=alice, action=login; status=ok"),
+                vec!["user=alice", "action=login", "status=ok"]
+            );
+            drain.train("action=login,status=ok;user=alice");
+            let cluster = drain.train("action=login, status=ok; user=bob");
+            assert_eq!(cluster.to_string(), "action=login status=ok <*>");
+        }
+
+        #[test]
+        fn test_empty_match() {
+            let delimiter = regex::Regex::new(r",*").unwrap();
+            assert_eq!(
+                Drain::default().with_delimiter_regex(delimiter).err(),
+                Some(DrainError::EmptyDelimiter(String::from(",*")))
+            );
+        }
+    }
+
+    mod normalize_line {
+        use super::*;
+
+        #[cfg(feature = "ansi")]
+        #[test]
+        fn test() {
+            let drain = Drain::default().with_normalization(Normalization::ALL);
+            assert_eq!(
+                drain.normalize_line("\x1b[31mERROR\x1b[0m disk\tis full\r\n"),
+                "ERROR disk is full  "
+            );
+
+            let drain = Drain::default().with_normalization(Normalization {
+                strip_ansi: true,
+                replace_controls: false,
+            });
+            assert_eq!(drain.normalize_line("\x1b[1mid\x1b[0m\tname"), "id\tname");
+        }
+
+        #[test]
+        fn test_controls() {
+            let drain = Drain::default();
+            assert_eq!(drain.normalize_line("a\tb\x07"), "a\tb\x07");
+
+            let normalization = Normalization {
+                strip_ansi: false,
+                replace_controls: true,
+            };
+            let drain = Drain::default().with_normalization(normalization);
+            assert_eq!(drain.normalize_line("a\tb\x07c\nd"), "a b c d");
+
+            let mut drain = Drain::default()
+                .with_tab_delimited(true)
+                .with_normalization(normalization);
+            assert_eq!(drain.normalize_line("a b\tc\r"), "a b\tc ");
+            let cluster = drain.train("user alice\tlogged in\n");
+            assert_eq!(cluster.to_string(), "user alice logged in");
+        }
+    }
+
+    mod clone {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default().with_case_insensitive(|token| token == "ERROR");
+            drain.train("ERROR disk is full");
+            drain.train("user alice logged in");
+
+            let mut cloned = drain.clone();
+            assert_eq!(cloned.clusters(), drain.clusters());
+
+            cloned.train("error disk is full");
+            cloned.train("connection reset");
+            let sizes = |drain: &Drain| -> Vec<usize> {
+                drain
+                    .clusters_by_id()
+                    .iter()
+                    .map(|cluster| cluster.size)
+                    .collect()
+            };
+            assert_eq!(sizes(&cloned), vec![2, 1, 1]);
+            assert_eq!(sizes(&drain), vec![1, 1]);
+            assert_eq!(drain.total_lines(), 2);
+
+            drain.train("user bob logged in");
+            assert_eq!(drain.clusters_by_id()[1].to_string(), "user <*> logged in");
+            assert_eq!(
+                cloned.clusters_by_id()[1].to_string(),
+                "user alice logged in"
+            );
+        }
+    }
+
+    mod explain_match {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("user alice logged in");
+            drain.train("user bob logged in");
+            drain.train("42 retries left");
+
+            assert_eq!(
+                drain.explain_match("user carol logged out"),
+                MatchExplanation {
+                    tokens: vec![
+                        "user".to_string(),
+                        "carol".to_string(),
+                        "logged".to_string(),
+                        "out".to_string(),
+                    ],
+                    bucket: "4".to_string(),
+                    path: vec!["user".to_string()],
+                    reached_leaf: true,
+                    candidates: vec![(1, 0.5)],
+                    matched: Some((1, 0.5)),
+                }
+            );
+
+            let explanation = drain.explain_match("7 retries left");
+            assert_eq!(explanation.path, vec!["<*>"]);
+            assert_eq!(explanation.matched, Some((2, 2.0 / 3.0)));
+
+            let explanation = drain.explain_match("admin carol logged in");
+            assert!(explanation.path.is_empty());
+            assert!(!explanation.reached_leaf);
+            assert_eq!(explanation.matched, None);
+            assert_eq!(drain.total_lines(), 3);
+        }
+    }
+
+    mod truncate_line {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let drain = Drain::default();
+            assert_eq!(drain.truncate_line("user alice"), "user alice");
+
+            let mut drain = Drain::default().with_max_line_bytes(8);
+            assert_eq!(drain.truncate_line("user alice"), "user ali");
+            assert_eq!(drain.truncate_line("user"), "user");
+            // `é` takes the 8th and 9th bytes, so it is dropped as a whole.
+            assert_eq!(drain.truncate_line("user jjé logged in"), "user jj");
+
+            let cluster = drain.train(format!("connect {}", "x".repeat(1 << 20)));
+            assert_eq!(cluster.to_string(), "connect");
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    mod with_shape_rule {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let regex = regex::Regex::new(r"^req-\w+").unwrap();
+            let mut drain = Drain::default().with_shape_rule(regex, ShapeScope::FirstToken);
+            let get = drain.train("req-get user alice ok");
+            let put = drain.train("req-put user alice ok");
+            assert_ne!(get.cluster_id, put.cluster_id);
+            assert_eq!(get.shape(), Some("0:req-get"));
+
+            let cluster = drain.train("req-get user bob ok");
+            assert_eq!(cluster.cluster_id, get.cluster_id);
+            assert_eq!(cluster.to_string(), "req-get user <*> ok");
+
+            let cluster = drain.train("res-get user alice ok");
+            assert_eq!(cluster.shape(), None);
+            assert_eq!(drain.len(), 3);
+        }
+
+        #[test]
+        fn test_line() {
+            let regex = regex::Regex::new(r"tenant=\w+").unwrap();
+            let mut drain = Drain::default().with_shape_rule(regex, ShapeScope::Line);
+            for log in [
+                "login ok tenant=a",
+                "login ok tenant=b",
+                "login ok tenant=a",
+            ] {
+                drain.train(log);
+            }
+            let sizes: Vec<usize> = drain
+                .clusters_by_id()
+                .iter()
+                .map(|cluster| cluster.size)
+                .collect();
+            assert_eq!(sizes, vec![2, 1]);
+        }
+    }
+
+    mod with_mixed_lengths {
+        use super::*;
+
+        fn templates(mut drain: Drain, logs: &[&str]) -> Vec<(String, usize)> {
+            for log in logs {
+                drain.train(log);
+            }
+            drain
+                .clusters_by_id()
+                .iter()
+                .map(|cluster| (cluster.to_string(), cluster.size))
+                .collect()
+        }
+
+        #[test]
+        fn test() {
+            let logs = [
+                "job started id=1",
+                "job started id=2 retry=1",
+                "job started id=3",
+                "",
+                "backup done",
+            ];
+            assert_eq!(
+                templates(Drain::default(), &logs),
+                vec![
+                    ("job started <*>".to_string(), 2),
+                    ("job started id=2 retry=1".to_string(), 1),
+                    ("".to_string(), 1),
+                    ("backup done".to_string(), 1),
+                ]
+            );
+
+            let drain = Drain::default().with_mixed_lengths(true);
+            assert_eq!(
+                templates(drain, &logs),
+                vec![
+                    ("job started <*> <*>".to_string(), 3),
+                    ("".to_string(), 1),
+                    ("backup done".to_string(), 1),
+                ]
+            );
+        }
+
+        #[test]
+        fn test_single_token() {
+            let drain = Drain {
+                sim_th: 0.3,
+                ..Default::default()
+            }
+            .with_mixed_lengths(true);
+            assert_eq!(
+                templates(drain.clone(), &["a", "a b c"]),
+                vec![("a <*> <*>".to_string(), 2)]
+            );
+            assert_eq!(
+                templates(drain.clone(), &["a b c", "a"]),
+                vec![("a <*> <*>".to_string(), 2)]
+            );
+
+            let mut drain = drain;
+            let cluster = drain.train("a");
+            let explanation = drain.explain_match("a b c");
+            assert_eq!(
+                explanation.candidates,
+                vec![(cluster.cluster_id, 1.0 / 3.0)]
+            );
+            assert_eq!(explanation.matched, Some((cluster.cluster_id, 1.0 / 3.0)));
+        }
+
+        #[test]
+        fn test_total_tokens() {
+            let mut drain = Drain::default().with_mixed_lengths(true);
+            drain.train("disk full");
+            drain.train("disk full on /dev/sda1");
+            assert_eq!(drain.clusters()[0].to_string(), "disk full <*> <*>");
+            assert_eq!(drain.total_tokens, 4);
+            assert_eq!(drain.explain_match("disk").bucket, MIXED_LENGTHS_KEY);
+        }
+
+        #[test]
+        fn test_trailing_penalty() {
+            let logs = ["connected to server", "connected to server now"];
+            let drain = Drain {
+                sim_th: 0.8,
+                ..Default::default()
+            }
+            .with_mixed_lengths(true);
+            assert_eq!(
+                templates(drain.clone(), &logs),
+                vec![
+                    ("connected to server".to_string(), 1),
+                    ("connected to server now".to_string(), 1),
+                ]
+            );
+
+            let drain = drain.with_trailing_penalty(0.25).unwrap();
+            assert_eq!(
+                templates(drain.clone(), &logs),
+                vec![("connected to server <*>".to_string(), 2)]
+            );
+
+            let tokens = |log: &str| drain.tokenize(log);
+            assert_eq!(
+                drain.get_seq_distance(&tokens("a b c"), &tokens("a b c d e"), false, 0.0),
+                Some((3.0 / 3.5, 2))
+            );
+            assert_eq!(
+                drain.get_seq_distance(&tokens(""), &tokens("a"), false, 0.0),
+                Some((0.0, 1))
+            );
+            assert_eq!(
+                drain.get_seq_distance(&tokens("a <*> c"), &tokens("a b c d e"), true, 0.0),
+                Some((1.0, 3))
+            );
+            assert_eq!(
+                Drain::default().with_trailing_penalty(1.5).err(),
+                Some(DrainError::InvalidTrailingPenalty(1.5))
+            );
+        }
+    }
+
+    mod to_dot {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("user alice logged in");
+            drain.train("user bob logged in");
+            drain.train(r#"open "a.txt""#);
+            assert_eq!(
+                drain.to_dot(),
+                r#"digraph drain {
+    n0 [label="root"];
+    n1 [label="2"];
+    n0 -> n1;
+    n2 [label="open"];
+    n1 -> n2;
+    c2 [shape=box, label="[2] open \"a.txt\""];
+    n2 -> c2;
+    n3 [label="4"];
+    n0 -> n3;
+    n4 [label="user"];
+    n3 -> n4;
+    c1 [shape=box, label="[1] user <*> logged in"];
+    n4 -> c1;
+}
+"#
+            );
+        }
+    }
+
+    mod set_max_clusters {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for log in ["disk is full", "user alice logged in", "connection reset"] {
+                drain.train(log);
+            }
+            // Using a cluster makes it the most recent one.
+            drain.train("disk is full");
+
+            drain.set_max_clusters(Some(2)).unwrap();
+            let templates: Vec<String> = drain
+                .clusters_by_id()
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect();
+            assert_eq!(templates, vec!["disk is full", "connection reset"]);
+            assert_eq!(drain.config().max_clusters, Some(2));
+            assert_eq!(drain.total_tokens, 5);
+
+            drain.train("kernel panic");
+            assert_eq!(drain.len(), 2);
+
+            drain.set_max_clusters(None).unwrap();
+            assert_eq!(drain.config().max_clusters, None);
+            drain.train("oops");
+            assert_eq!(drain.len(), 3);
+
+            assert_eq!(
+                drain.set_max_clusters(Some(0)),
+                Err(DrainError::ZeroMaxClusters)
+            );
+            assert_eq!(drain.len(), 3);
+            assert_eq!(drain.config().max_clusters, None);
+        }
+    }
 
-        for (token1, token2) in seq1.iter().zip(seq2.iter()) {
-            if token1 == &self.param_str {
-                param_count += 1;
-            } else if token1 == token2 {
-                sim_tokens += 1;
+    mod prune {
+        use super::*;
+
+        fn cluster_ids(node: &Node, ids: &mut Vec<usize>) {
+            ids.extend(&node.cluster_ids);
+            for child in node.key_to_child_node.values() {
+                cluster_ids(child, ids);
+            }
+        }
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for log in [
+                "user alice logged in",
+                "user bob logged in",
+                "user carol logged in",
+                "disk is full",
+                "disk is full",
+                "kernel panic",
+                "oops",
+            ] {
+                drain.train(log);
             }
+
+            assert_eq!(drain.prune(2), 2);
+            let templates: Vec<String> = drain
+                .clusters_by_id()
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect();
+            assert_eq!(templates, vec!["user <*> logged in", "disk is full"]);
+            assert_eq!(drain.total_tokens, 7);
+
+            let mut ids = Vec::new();
+            cluster_ids(&drain.root, &mut ids);
+            ids.sort();
+            assert_eq!(ids, vec![1, 2]);
+            assert!(!drain.root.key_to_child_node.contains_key("1"));
+            assert!(drain.single_token_clusters.is_empty());
+
+            assert_eq!(drain.train("kernel panic").size, 1);
+            assert_eq!(drain.train("user dave logged in").size, 4);
+            assert_eq!(drain.prune(0), 0);
         }
-        if include_params {
-            sim_tokens += param_count;
+
+        #[test]
+        fn test_retrain() {
+            let mut drain = Drain::default()
+                .with_id_strategy(IdStrategy::TemplateHash)
+                .with_mixed_lengths(true);
+            drain.train("disk is full");
+            drain.train("disk is full");
+            let pruned = drain.train("kernel panic on cpu 3");
+            assert_eq!(drain.prune(2), 1);
+            assert!(drain.match_log("kernel panic on cpu 3").is_none());
+
+            let retrained = drain.train("kernel panic on cpu 3");
+            assert_eq!(retrained.cluster_id, pruned.cluster_id);
+            assert_eq!(retrained.size, 1);
+            assert_eq!(drain.len(), 2);
+            assert_eq!(
+                drain
+                    .match_log("kernel panic on cpu 3")
+                    .map(|c| c.cluster_id),
+                Some(pruned.cluster_id)
+            );
+            let mut ids = Vec::new();
+            cluster_ids(&drain.root, &mut ids);
+            assert_eq!(ids.len(), 2);
         }
-        (sim_tokens as f32 / seq1.len() as f32, param_count)
     }
 
-    fn add_seq_to_prefix_tree(&mut self, cluster: &mut LogCluster) {
-        let token_count = cluster.log_template_tokens.len();
-        let token_count_str = token_count.to_string();
+    mod merge {
+        use super::*;
 
-        let mut cur_node: &mut Node = self
-            .root
-            .key_to_child_node
-            .entry(token_count_str)
-            .or_default();
+        fn templates(drain: &Drain) -> Vec<(String, usize)> {
+            drain
+                .clusters_by_id()
+                .iter()
+                .map(|cluster| (cluster.to_string(), cluster.size))
+                .collect()
+        }
 
-        if token_count == 0 {
-            cur_node.cluster_ids.push(cluster.cluster_id);
-            return;
+        #[test]
+        fn test() {
+            let mut first = Drain::default();
+            for log in ["user alice logged in", "user bob logged in", "disk is full"] {
+                first.train(log);
+            }
+            let mut second = Drain::default();
+            for log in [
+                "connected to 10.0.0.1",
+                "connected to 10.0.0.2",
+                "kernel panic",
+            ] {
+                second.train(log);
+            }
+            second.set_label(2, Some(String::from("panic")));
+
+            let id_map = first.merge(second);
+            assert_eq!(id_map, BTreeMap::from([(1, 3), (2, 4)]));
+            assert_eq!(
+                templates(&first),
+                vec![
+                    (String::from("user <*> logged in"), 2),
+                    (String::from("disk is full"), 1),
+                    (String::from("connected to <*>"), 2),
+                    (String::from("kernel panic"), 1),
+                ]
+            );
+            assert_eq!(first.clusters_by_id()[3].label(), Some("panic"));
+            assert_eq!(first.total_lines(), 6);
         }
 
-        let mut current_depth = 1;
-        for token in cluster.log_template_tokens.iter() {
-            if current_depth >= self.max_node_depth || current_depth >= token_count {
-                let mut new_cluster_ids = Vec::new();
-                for cluster_id in cur_node
-                    .cluster_ids
-                    .iter()
-                    .filter(|cluster_id| self.id_to_cluster.contains(cluster_id))
-                {
-                    new_cluster_ids.push(*cluster_id);
-                }
-                new_cluster_ids.push(cluster.cluster_id);
-                cur_node.cluster_ids = new_cluster_ids;
-                break;
+        #[test]
+        fn test_overlap() {
+            let mut first = Drain::default().with_window(60);
+            for (log, at) in [
+                ("user alice logged in", 0.0),
+                ("disk is full", 0.0),
+                ("disk is full", 1.0),
+            ] {
+                first.train_at(log, at);
+            }
+            first.set_label(2, Some(String::from("noise")));
+            let mut second = Drain::default().with_window(60);
+            for (log, at) in [
+                ("user bob logged in", 0.0),
+                ("user carol logged in", 1.0),
+                ("disk is full", 2.0),
+            ] {
+                second.train_at(log, at);
             }
+            second.set_label(1, Some(String::from("login")));
+            second.set_label(2, Some(String::from("full")));
+            let (disk_rate, other_disk_rate) = (
+                first.clusters_by_id()[1].rate_at(2.0),
+                second.clusters_by_id()[1].rate_at(2.0),
+            );
 
-            if !cur_node.key_to_child_node.contains_key(token) {
-                if !has_number(token) {
-                    if cur_node.key_to_child_node.contains_key(&self.param_str) {
-                        if cur_node.key_to_child_node.len() < self.max_children {
-                            let new_node = Node::default();
-                            cur_node.key_to_child_node.insert(token.clone(), new_node);
-                            cur_node = cur_node.key_to_child_node.get_mut(token).unwrap();
-                        } else {
-                            cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
-                        }
-                    } else if cur_node.key_to_child_node.len() + 1 < self.max_children {
-                        let new_node = Node::default();
-                        cur_node.key_to_child_node.insert(token.clone(), new_node);
-                        cur_node = cur_node.key_to_child_node.get_mut(token).unwrap();
-                    } else if cur_node.key_to_child_node.len() + 1 == self.max_children {
-                        let new_node = Node::default();
-                        cur_node
-                            .key_to_child_node
-                            .insert(self.param_str.clone(), new_node);
-                        cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
-                    } else {
-                        cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
-                    }
-                } else if !cur_node.key_to_child_node.contains_key(&self.param_str) {
-                    let new_node = Node::default();
-                    cur_node
-                        .key_to_child_node
-                        .insert(self.param_str.clone(), new_node);
-                    cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
-                } else {
-                    cur_node = cur_node.key_to_child_node.get_mut(&self.param_str).unwrap();
-                }
-            } else {
-                cur_node = cur_node.key_to_child_node.get_mut(token).unwrap();
+            let id_map = first.merge(second);
+            assert_eq!(id_map, BTreeMap::from([(1, 1), (2, 2)]));
+            assert_eq!(
+                templates(&first),
+                vec![
+                    (String::from("user <*> logged in"), 3),
+                    (String::from("disk is full"), 3),
+                ]
+            );
+            let clusters = first.clusters_by_id();
+            assert_eq!(clusters[0].label(), Some("login"));
+            assert_eq!(clusters[1].label(), Some("noise"));
+            assert_eq!(clusters[0].window_counts, VecDeque::from([(0, 2), (1, 1)]));
+            assert_eq!(
+                clusters[1].window_counts,
+                VecDeque::from([(0, 1), (1, 1), (2, 1)])
+            );
+            assert_eq!(clusters[1].last_arrival, Some(2.0));
+            assert!((clusters[1].rate() - (disk_rate + other_disk_rate)).abs() < 1e-9);
+        }
+
+        #[test]
+        fn test_reset_sizes() {
+            let mut first = Drain::default();
+            first.train("disk is full");
+            let mut second = Drain::default();
+            for log in ["disk is full", "kernel panic"] {
+                second.train(log);
             }
+            second.reset_sizes();
 
-            current_depth += 1;
+            first.merge(second);
+            assert_eq!(
+                templates(&first),
+                vec![
+                    (String::from("disk is full"), 1),
+                    (String::from("kernel panic"), 0),
+                ]
+            );
+            assert_eq!(first.total_lines(), 1);
         }
     }
 
-    fn create_template(&self, seq1: &[String], seq2: &[String]) -> Vec<String> {
-        let mut new_template_tokens = Vec::new();
-        for (token1, token2) in seq1.iter().zip(seq2.iter()) {
-            if token1 == token2 {
-                new_template_tokens.push(token1);
-            } else {
-                new_template_tokens.push(&self.param_str);
+    mod match_all {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("open file foo ok");
+            drain.train("open socket bar failed");
+            assert_eq!(drain.len(), 2);
+
+            let matches: Vec<(usize, f32)> = drain
+                .match_all("open file bar failed", 0.5)
+                .into_iter()
+                .map(|(cluster, sim)| (cluster.cluster_id, sim))
+                .collect();
+            assert_eq!(matches, vec![(2, 0.75), (1, 0.5)]);
+
+            let matches = drain.match_all("open file bar failed", 0.6);
+            assert_eq!(matches.len(), 1);
+            assert_eq!(matches[0].0.to_string(), "open socket bar failed");
+
+            assert!(drain.match_all("close file foo ok", 0.5).is_empty());
+            assert_eq!(drain.clusters_by_id()[0].size, 1);
+        }
+    }
+
+    mod match_log {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("user alice logged in");
+            drain.train("user bob logged in");
+            let before = drain.clusters_by_id()[0].clone();
+
+            let cluster = drain.match_log("user carol logged in").unwrap();
+            assert_eq!(cluster.to_string(), "user <*> logged in");
+            assert!(drain.match_log("kernel panic").is_none());
+            assert!(drain.match_log("disk is full").is_none());
+            assert_eq!(drain.unmatched_count(), 2);
+            assert_eq!(drain.total_lines(), 2);
+            assert_eq!(drain.clusters_by_id()[0], &before);
+
+            drain.reset_sizes();
+            assert_eq!(drain.unmatched_count(), 0);
+        }
+
+        #[test]
+        fn test_trailing_penalty() {
+            let mut drain = Drain {
+                sim_th: 0.6,
+                ..Default::default()
+            }
+            .with_mixed_lengths(true)
+            .with_trailing_penalty(0.5)
+            .unwrap();
+            drain.train("a b c");
+
+            // `a b` and the two trailing positions at half weight give (2 + 1) / 4.
+            let cluster = drain.match_log("a b x d e").unwrap();
+            assert_eq!(cluster.to_string(), "a b c");
+            assert_eq!(drain.unmatched_count(), 0);
+        }
+
+        #[test]
+        fn test_include_params() {
+            let mut drain = Drain {
+                sim_th: 0.5,
+                ..Default::default()
+            };
+            drain.train("open a b file");
+            drain.train("open x y file");
+            drain
+                .apply_config(DrainConfig {
+                    sim_th: 0.6,
+                    ..drain.config()
+                })
+                .unwrap();
+
+            // Only 2 of 4 tokens are the same, but the others are wildcards.
+            let drain = &drain;
+            let cluster = drain.match_log("open p q file").unwrap();
+            assert_eq!(cluster.to_string(), "open <*> <*> file");
+            assert_eq!(cluster.size, 2);
+            assert_eq!(drain.len(), 1);
+            assert_eq!(drain.unmatched_count(), 0);
+
+            let tokens = drain.tokenize("open p q file");
+            assert_eq!(
+                drain
+                    .match_tokens(&tokens)
+                    .map(|cluster| cluster.cluster_id),
+                Some(cluster.cluster_id)
+            );
+            assert!(drain
+                .match_tokens(&drain.tokenize("disk is full"))
+                .is_none());
+            assert_eq!(drain.unmatched_count(), 1);
+        }
+    }
+
+    mod extract_params {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for log in [
+                "connected to 10.0.0.1 port 80",
+                "connected to 10.0.0.2 port 8080",
+                "disk is full",
+            ] {
+                drain.train(log);
             }
+            assert_eq!(
+                drain.extract_params("connected to 10.0.0.3 port 443"),
+                Some(vec!["10.0.0.3".to_string(), "443".to_string()])
+            );
+            assert_eq!(drain.extract_params("disk is full"), Some(vec![]));
+            assert_eq!(drain.extract_params("kernel panic"), None);
+        }
+
+        #[test]
+        fn test_consecutive() {
+            let mut drain = Drain::default();
+            drain.train("copied 10 MB in 2 s");
+            drain.train("copied 3 GB in 7 s");
+            assert_eq!(
+                drain.extract_params("copied 512 KB in 1 s"),
+                Some(vec!["512".to_string(), "KB".to_string(), "1".to_string()])
+            );
+        }
+
+        #[test]
+        fn test_mixed_lengths() {
+            let mut drain = Drain::default().with_mixed_lengths(true);
+            drain.train("user alice logged in");
+            drain.train("user bob logged in");
+            assert_eq!(
+                drain.extract_params("user carol logged in"),
+                Some(vec!["carol".to_string()])
+            );
+            assert_eq!(drain.extract_params("user carol logged in now"), None);
         }
-        new_template_tokens.iter().map(|s| s.to_string()).collect()
     }
-}
 
-fn has_number(s: &str) -> bool {
-    s.chars().any(|c| c.is_numeric())
-}
+    #[cfg(feature = "regex")]
+    mod to_regex {
+        use super::*;
 
-fn tokenize(log_message: &str) -> Vec<String> {
-    log_message
-        .split_whitespace()
-        .map(|s| s.to_string())
-        .collect()
-}
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            drain.train("GET /index.html took 3.5 ms");
+            let cluster = drain.train("GET /a*b.html took 12 ms");
+            assert_eq!(cluster.to_string(), "GET <*> took <*> ms");
+            let regex = drain.to_regex(&cluster, DEFAULT_PARAM_PATTERN).unwrap();
+            assert_eq!(regex.as_str(), r"^GET (\S+) took (\S+) ms$");
+            let captures = regex.captures("GET /x.html took 7 ms").unwrap();
+            assert_eq!(&captures[1], "/x.html");
+            assert_eq!(&captures[2], "7");
+            assert!(!regex.is_match("GET /x.html took 7 ms ago"));
+        }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    mod train {
+        #[test]
+        fn test_escape() {
+            let mut drain = Drain::default();
+            let cluster = drain.train("loaded a.b* (x) in 1s");
+            let regex = drain.to_regex(&cluster, DEFAULT_PARAM_PATTERN).unwrap();
+            assert!(regex.is_match("loaded a.b* (x) in 1s"));
+            assert!(!regex.is_match("loaded aXbbb (x) in 1s"));
+        }
+
+        #[test]
+        fn test_param_pattern() {
+            let mut drain = Drain::default();
+            drain.train("retry 1 of 3");
+            let cluster = drain.train("retry 2 of 3");
+            let regex = drain.to_regex(&cluster, r"\d+|x").unwrap();
+            assert!(regex.is_match("retry x of 3"));
+            assert!(!regex.is_match("retry x of 3 of 3"));
+            assert!(!regex.is_match("retry y of 3"));
+            assert!(drain.to_regex(&cluster, "(").is_err());
+        }
+
+        #[test]
+        fn test_param_str() {
+            let mut drain = Drain::builder().param_str("<V>").build().unwrap();
+            drain.train("connected to 10.0.0.1");
+            let cluster = drain.train("connected to 10.0.0.2");
+            assert_eq!(cluster.to_string(), "connected to <V>");
+            let regex = drain.to_regex(&cluster, DEFAULT_PARAM_PATTERN).unwrap();
+            assert_eq!(
+                &regex.captures("connected to 10.0.0.3").unwrap()[1],
+                "10.0.0.3"
+            );
+
+            let mut drain = Drain::default()
+                .with_token_class(TokenClass::Hex, String::from("<HEX>"))
+                .unwrap();
+            drain.train("fault at 0xDEAD");
+            let cluster = drain.train("fault at 0xBEEF");
+            assert_eq!(cluster.to_string(), "fault at <HEX>");
+            let regex = drain.to_regex(&cluster, DEFAULT_PARAM_PATTERN).unwrap();
+            assert!(regex.is_match("fault at 0xCAFE"));
+        }
+
+        #[test]
+        fn test_delimiters() {
+            let mut drain = Drain::default().with_delimiters(&[' ', '=']);
+            drain.train("key1=a key2=b");
+            let cluster = drain.train("key1=c key2=d");
+            let regex = drain.to_regex(&cluster, "[^ =]+").unwrap();
+            let captures = regex.captures("key1=e key2=f").unwrap();
+            assert_eq!((&captures[1], &captures[2]), ("e", "f"));
+            assert!(regex.is_match("key1= e key2=f"));
+            assert!(!regex.is_match("key1=ekey2=f"));
+
+            // `ab` is a single token, unlike `a b`.
+            let cluster = drain.train("a b");
+            let regex = drain.to_regex(&cluster, DEFAULT_PARAM_PATTERN).unwrap();
+            assert!(regex.is_match("a b"));
+            assert!(!regex.is_match("ab"));
+
+            let delimiter = regex::Regex::new(r"[\s,]+").unwrap();
+            let mut drain = Drain::default().with_delimiter_regex(delimiter).unwrap();
+            drain.train("user alice, id 1");
+            let cluster = drain.train("user bob, id 2");
+            let regex = drain.to_regex(&cluster, r"\w+").unwrap();
+            assert!(regex.is_match("user carol, id 3"));
+        }
+    }
+
+    mod coverage {
         use super::*;
 
         #[test]
         fn test() {
-            let logs = vec![
-                "connected to 10.0.0.1",
-                "connected to 10.0.0.2",
-                "connected to 10.0.0.3",
-                "Hex number 0xDEADBEAF",
-                "Hex number 0x10000",
-                "user davidoh logged in",
-                "user eranr logged in",
-            ];
             let mut drain = Drain::default();
-            for log in logs {
+            for log in ["user alice logged in", "user bob logged in", "disk is full"] {
                 drain.train(log);
             }
-            let mut clusters = drain.clusters();
-            clusters.sort_by_key(|c| c.cluster_id);
+            assert_eq!(drain.coverage(Vec::<String>::new()).ratio(), None);
+
+            let report = drain.coverage([
+                "user carol logged in",
+                "user dave logged in",
+                "disk is full",
+                "kernel panic",
+            ]);
             assert_eq!(
-                clusters,
-                vec![
-                    &LogCluster {
-                        log_template_tokens: vec![
-                            String::from("connected"),
-                            String::from("to"),
-                            String::from("<*>"),
-                        ],
-                        cluster_id: 1,
-                        size: 3,
-                    },
-                    &LogCluster {
-                        log_template_tokens: vec![
-                            String::from("Hex"),
-                            String::from("number"),
-                            String::from("<*>"),
-                        ],
-                        cluster_id: 2,
-                        size: 2,
-                    },
-                    &LogCluster {
-                        log_template_tokens: vec![
-                            String::from("user"),
-                            String::from("<*>"),
-                            String::from("logged"),
-                            String::from("in"),
-                        ],
-                        cluster_id: 3,
-                        size: 2,
-                    },
-                ]
+                report,
+                CoverageReport {
+                    matched: 3,
+                    unmatched: 1,
+                    matches: BTreeMap::from([(1, 2), (2, 1)]),
+                }
             );
+            assert_eq!(report.ratio(), Some(0.75));
+            assert_eq!(drain.total_lines(), 3);
+            assert_eq!(drain.clusters_by_id()[0].size, 2);
+        }
+    }
+
+    mod set_label {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            let cluster = drain.train("user alice logged in");
+            drain.train("disk is full");
+            assert!(drain.set_label(cluster.cluster_id, Some("known noise".to_string())));
+            assert!(!drain.set_label(42, Some("missing".to_string())));
+            assert_eq!(drain.clusters()[0].to_string(), "disk is full");
+
+            let cluster = drain.train("user bob logged in");
+            assert_eq!(cluster.label(), Some("known noise"));
+            assert_eq!(cluster.to_string(), "user <*> logged in");
+
+            drain.set_label(cluster.cluster_id, None);
+            assert_eq!(drain.clusters()[0].label(), None);
+        }
+    }
+
+    mod template_length_range {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            assert_eq!(drain.template_length_range(), None);
+            drain.train("disk is full");
+            assert_eq!(drain.template_length_range(), Some((3, 3)));
+            drain.train("user alice logged in");
+            drain.train("restarting");
+            drain.train("connection reset by peer after 30s");
+            assert_eq!(drain.template_length_range(), Some((1, 6)));
         }
     }
 }
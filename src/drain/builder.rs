@@ -0,0 +1,396 @@
+use std::{fmt, sync::Arc};
+
+#[cfg(feature = "regex")]
+use super::ShapeScope;
+use super::{
+    Drain, DrainConfig, DrainError, IdStrategy, Normalization, PunctuationPolicy, TieBreak,
+    TokenClass, TokenPredicate, WildcardRuns,
+};
+
+/// Chainable construction of a `Drain`, covering every setting.
+/// This is the way to configure a `Drain` beyond the five settings of `Drain::new`;
+/// settings left out keep their defaults, and invalid values are reported by `build`.
+///
+/// ```
+/// use logu::drain::DrainBuilder;
+///
+/// let drain = DrainBuilder::new().sim_th(0.5).max_children(50).build().unwrap();
+/// assert_eq!(drain.config().sim_th, 0.5);
+/// ```
+#[derive(Clone, Default)]
+pub struct DrainBuilder {
+    config: DrainConfig,
+    case_insensitive: Option<TokenPredicate>,
+    #[cfg(feature = "regex")]
+    delimiter: Option<regex::Regex>,
+    #[cfg(feature = "regex")]
+    shape_rules: Vec<(regex::Regex, ShapeScope)>,
+    #[cfg(feature = "regex")]
+    masks: Vec<(regex::Regex, String)>,
+}
+
+impl fmt::Debug for DrainBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DrainBuilder")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DrainBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Evict the least recently used clusters beyond this number, `None` for no limit.
+    pub fn max_clusters(mut self, max_clusters: Option<usize>) -> Self {
+        self.config.max_clusters = max_clusters;
+        self
+    }
+
+    /// Depth of the prefix tree below the token-count level, counting the leaf.
+    pub fn max_node_depth(mut self, max_node_depth: usize) -> Self {
+        self.config.max_node_depth = max_node_depth;
+        self
+    }
+
+    /// Similarity required to join a cluster, within `0.0..=1.0`.
+    pub fn sim_th(mut self, sim_th: f32) -> Self {
+        self.config.sim_th = sim_th;
+        self
+    }
+
+    /// See `Drain::with_update_th`.
+    pub fn update_th(mut self, update_th: Option<f32>) -> Self {
+        self.config.update_th = update_th;
+        self
+    }
+
+    /// Children of a prefix tree node, at least 1.
+    pub fn max_children(mut self, max_children: usize) -> Self {
+        self.config.max_children = max_children;
+        self
+    }
+
+    /// Wildcard of the templates, non-empty and without whitespace.
+    pub fn param_str(mut self, param_str: impl Into<String>) -> Self {
+        self.config.param_str = param_str.into();
+        self
+    }
+
+    /// See `Drain::with_max_tokens`.
+    pub fn max_tokens(mut self, max_tokens: Option<usize>) -> Self {
+        self.config.max_tokens = max_tokens;
+        self
+    }
+
+    /// See `Drain::with_min_distinct_values`.
+    pub fn min_distinct_values(mut self, min_distinct_values: Option<usize>) -> Self {
+        self.config.min_distinct_values = min_distinct_values;
+        self
+    }
+
+    /// See `Drain::with_typed_params`.
+    pub fn typed_params(mut self, typed_params: bool) -> Self {
+        self.config.typed_params = typed_params;
+        self
+    }
+
+    /// See `Drain::with_tab_delimited`.
+    pub fn tab_delimited(mut self, tab_delimited: bool) -> Self {
+        self.config.tab_delimited = tab_delimited;
+        self
+    }
+
+    /// See `Drain::with_punctuation`.
+    pub fn punctuation(mut self, punctuation: PunctuationPolicy) -> Self {
+        self.config.punctuation = punctuation;
+        self
+    }
+
+    /// See `Drain::with_max_total_tokens`.
+    pub fn max_total_tokens(mut self, max_total_tokens: Option<usize>) -> Self {
+        self.config.max_total_tokens = max_total_tokens;
+        self
+    }
+
+    /// See `Drain::with_separator`.
+    pub fn separator(mut self, separator: impl Into<String>) -> Self {
+        self.config.separator = separator.into();
+        self
+    }
+
+    /// See `Drain::with_id_strategy`.
+    pub fn id_strategy(mut self, id_strategy: IdStrategy) -> Self {
+        self.config.id_strategy = id_strategy;
+        self
+    }
+
+    /// See `Drain::with_tie_break`.
+    pub fn tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.config.tie_break = tie_break;
+        self
+    }
+
+    /// See `Drain::with_wildcard_runs`.
+    pub fn wildcard_runs(mut self, wildcard_runs: WildcardRuns) -> Self {
+        self.config.wildcard_runs = wildcard_runs;
+        self
+    }
+
+    /// See `Drain::with_facility`.
+    pub fn facility(mut self, facility: bool) -> Self {
+        self.config.facility = facility;
+        self
+    }
+
+    /// See `Drain::with_window`; `None` or `Some(0)` for no window.
+    pub fn window_secs(mut self, window_secs: Option<u64>) -> Self {
+        self.config.window_secs = window_secs;
+        self
+    }
+
+    /// See `Drain::with_normalization`.
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.config.normalization = normalization;
+        self
+    }
+
+    /// See `Drain::with_max_line_bytes`.
+    pub fn max_line_bytes(mut self, max_line_bytes: Option<usize>) -> Self {
+        self.config.max_line_bytes = max_line_bytes;
+        self
+    }
+
+    /// See `Drain::with_mixed_lengths`.
+    pub fn mixed_lengths(mut self, mixed_lengths: bool) -> Self {
+        self.config.mixed_lengths = mixed_lengths;
+        self
+    }
+
+    /// See `Drain::with_trailing_penalty`.
+    pub fn trailing_penalty(mut self, trailing_penalty: Option<f32>) -> Self {
+        self.config.trailing_penalty = trailing_penalty;
+        self
+    }
+
+    /// See `Drain::with_dedup_tokens`.
+    pub fn dedup_tokens(mut self, dedup_tokens: bool) -> Self {
+        self.config.dedup_tokens = dedup_tokens;
+        self
+    }
+
+    /// Characters splitting log messages into tokens instead of whitespace,
+    /// see `Drain::with_delimiters`.
+    pub fn delimiters(mut self, delimiters: &[char]) -> Self {
+        self.config.delimiters = Some(delimiters.to_vec());
+        self
+    }
+
+    /// See `Drain::with_token_class`. Giving a class again replaces its wildcard.
+    pub fn token_class(mut self, class: TokenClass, wildcard: impl Into<String>) -> Self {
+        self.config.token_classes.retain(|(c, _)| *c != class);
+        self.config.token_classes.push((class, wildcard.into()));
+        self
+    }
+
+    /// See `Drain::with_case_insensitive`.
+    pub fn case_insensitive<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.case_insensitive = Some(Arc::new(predicate));
+        self
+    }
+
+    /// See `Drain::with_delimiter_regex`.
+    #[cfg(feature = "regex")]
+    pub fn delimiter_regex(mut self, delimiter: regex::Regex) -> Self {
+        self.delimiter = Some(delimiter);
+        self
+    }
+
+    /// Add a rule of `Drain::with_shape_rule`.
+    #[cfg(feature = "regex")]
+    pub fn shape_rule(mut self, regex: regex::Regex, scope: ShapeScope) -> Self {
+        self.shape_rules.push((regex, scope));
+        self
+    }
+
+    /// Replacements applied before tokenizing, see `Drain::with_masks`.
+    #[cfg(feature = "regex")]
+    pub fn masks(mut self, masks: Vec<(regex::Regex, String)>) -> Self {
+        self.masks = masks;
+        self
+    }
+
+    /// Create the `Drain`, failing on the values the `with_*` setters reject.
+    pub fn build(self) -> Result<Drain, DrainError> {
+        let mut drain = Drain::default();
+        drain.apply_config(self.config)?;
+        drain.case_insensitive = self.case_insensitive;
+        #[cfg(feature = "regex")]
+        {
+            if let Some(delimiter) = self.delimiter {
+                drain = drain.with_delimiter_regex(delimiter)?;
+            }
+            drain.shape_rules = self.shape_rules;
+            drain = drain.with_masks(self.masks);
+        }
+        Ok(drain)
+    }
+}
+
+impl Drain {
+    /// Start a `DrainBuilder` with the default settings.
+    pub fn builder() -> DrainBuilder {
+        DrainBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod build {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::builder()
+                .max_clusters(Some(2))
+                .max_node_depth(3)
+                .sim_th(0.5)
+                .max_children(10)
+                .param_str("<#>")
+                .build()
+                .unwrap();
+            assert_eq!(
+                drain.config(),
+                DrainConfig {
+                    max_clusters: Some(2),
+                    max_node_depth: 3,
+                    sim_th: 0.5,
+                    max_children: 10,
+                    param_str: String::from("<#>"),
+                    ..DrainConfig::default()
+                }
+            );
+            drain.train("connected to 10.0.0.1");
+            assert_eq!(
+                drain.train("connected to 10.0.0.2").to_string(),
+                "connected to <#>"
+            );
+
+            assert_eq!(
+                DrainBuilder::new().build().unwrap().config(),
+                Drain::default().config()
+            );
+        }
+
+        #[test]
+        fn test_options() {
+            let mut drain = Drain::builder()
+                .update_th(Some(0.6))
+                .typed_params(true)
+                .punctuation(PunctuationPolicy::Strip)
+                .separator("\t")
+                .mixed_lengths(true)
+                .trailing_penalty(Some(0.5))
+                .token_class(TokenClass::Hex, "<X>")
+                .token_class(TokenClass::Hex, "<HEX>")
+                .case_insensitive(|token| token.starts_with("0x"))
+                .build()
+                .unwrap();
+            let config = drain.config();
+            assert_eq!(config.update_th, Some(0.6));
+            assert!(config.typed_params && config.mixed_lengths);
+            assert_eq!(config.punctuation, PunctuationPolicy::Strip);
+            assert_eq!(config.trailing_penalty, Some(0.5));
+            assert_eq!(
+                config.token_classes,
+                vec![(TokenClass::Hex, String::from("<HEX>"))]
+            );
+            drain.train("read 10 bytes, fault at 0xDEAD");
+            let cluster = drain.train("read 42 bytes, fault at 0xdead");
+            assert_eq!(
+                drain.template(&cluster),
+                "read\t<NUM>\tbytes\tfault\tat\t0xDEAD"
+            );
+        }
+
+        #[cfg(feature = "regex")]
+        #[test]
+        fn test_delimiter_regex() {
+            let mut drain = Drain::builder()
+                .delimiter_regex(regex::Regex::new(r"[\s,]+").unwrap())
+                .build()
+                .unwrap();
+            assert_eq!(
+                drain.train("user=alice,action=login").to_string(),
+                "user=alice action=login"
+            );
+            assert!(Drain::builder()
+                .delimiter_regex(regex::Regex::new(r"\s*").unwrap())
+                .build()
+                .is_err());
+        }
+
+        #[cfg(feature = "regex")]
+        #[test]
+        fn test_masks() {
+            let mut drain = Drain::builder()
+                .masks(vec![(
+                    regex::Regex::new(r"\d+\.\d+\.\d+\.\d+").unwrap(),
+                    String::from("<IP>"),
+                )])
+                .build()
+                .unwrap();
+            assert_eq!(
+                drain.train("connected to 10.0.0.1").to_string(),
+                "connected to <IP>"
+            );
+        }
+
+        #[test]
+        fn test_delimiters() {
+            let mut drain = Drain::builder().delimiters(&[' ', '=']).build().unwrap();
+            drain.train("key1=a key2=b");
+            assert_eq!(
+                drain.train("key1=c key2=d").to_string(),
+                "key1= <*> key2= <*>"
+            );
+        }
+
+        #[test]
+        fn test_invalid() {
+            assert_eq!(
+                Drain::builder().sim_th(1.5).build().err(),
+                Some(DrainError::InvalidSimThreshold(1.5))
+            );
+            assert_eq!(
+                Drain::builder().max_children(0).build().err(),
+                Some(DrainError::ZeroMaxChildren)
+            );
+            assert_eq!(
+                Drain::builder().param_str("a b").build().err(),
+                Some(DrainError::InvalidParamStr(String::from("a b")))
+            );
+            assert_eq!(
+                Drain::builder()
+                    .sim_th(0.5)
+                    .update_th(Some(0.3))
+                    .build()
+                    .err(),
+                Some(DrainError::InvalidUpdateThreshold(0.3))
+            );
+            assert_eq!(
+                Drain::builder()
+                    .token_class(TokenClass::Hex, "<*>")
+                    .build()
+                    .err(),
+                Some(DrainError::InvalidClassWildcard(String::from("<*>")))
+            );
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use super::{
+    Drain, DrainError, IdStrategy, Normalization, PunctuationPolicy, TieBreak, TokenClass,
+    WildcardRuns, DEFAULT_MAX_CHILDREN, DEFAULT_MAX_NODE_DEPTH, DEFAULT_PARAM_STR, DEFAULT_SIM_TH,
+};
+
+/// Settings of a `Drain`, to inspect or change them as a unit.
+/// See the fields of `Drain` for their meaning.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DrainConfig {
+    pub max_clusters: Option<usize>,
+    pub max_node_depth: usize,
+    pub sim_th: f32,
+    pub update_th: Option<f32>,
+    pub max_children: usize,
+    pub param_str: String,
+    pub max_tokens: Option<usize>,
+    pub min_distinct_values: Option<usize>,
+    pub typed_params: bool,
+    pub tab_delimited: bool,
+    pub punctuation: PunctuationPolicy,
+    pub max_total_tokens: Option<usize>,
+    pub separator: String,
+    pub id_strategy: IdStrategy,
+    pub tie_break: TieBreak,
+    pub wildcard_runs: WildcardRuns,
+    pub facility: bool,
+    pub window_secs: Option<u64>,
+    pub normalization: Normalization,
+    pub max_line_bytes: Option<usize>,
+    pub mixed_lengths: bool,
+    pub trailing_penalty: Option<f32>,
+    pub dedup_tokens: bool,
+    pub delimiters: Option<Vec<char>>,
+    pub token_classes: Vec<(TokenClass, String)>,
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        Self {
+            max_clusters: None,
+            max_node_depth: DEFAULT_MAX_NODE_DEPTH,
+            sim_th: DEFAULT_SIM_TH,
+            update_th: None,
+            max_children: DEFAULT_MAX_CHILDREN,
+            param_str: DEFAULT_PARAM_STR.to_string(),
+            max_tokens: None,
+            min_distinct_values: None,
+            typed_params: false,
+            tab_delimited: false,
+            punctuation: PunctuationPolicy::Keep,
+            max_total_tokens: None,
+            separator: String::from(" "),
+            id_strategy: IdStrategy::Counter,
+            tie_break: TieBreak::MoreParams,
+            wildcard_runs: WildcardRuns::Keep,
+            facility: false,
+            window_secs: None,
+            normalization: Normalization::default(),
+            max_line_bytes: None,
+            mixed_lengths: false,
+            trailing_penalty: None,
+            dedup_tokens: false,
+            delimiters: None,
+            token_classes: Vec::new(),
+        }
+    }
+}
+
+impl DrainConfig {
+    /// Check the values rejected by `Drain::new`, `Drain::with_update_th`,
+    /// `Drain::with_trailing_penalty` and `Drain::with_token_class`,
+    /// also when loading a model.
+    pub(super) fn validate(&self) -> Result<(), DrainError> {
+        if !(0.0..=1.0).contains(&self.sim_th) {
+            return Err(DrainError::InvalidSimThreshold(self.sim_th));
+        }
+        if let Some(update_th) = self.update_th {
+            if !(self.sim_th..=1.0).contains(&update_th) {
+                return Err(DrainError::InvalidUpdateThreshold(update_th));
+            }
+        }
+        if self.param_str.is_empty() || self.param_str.contains(char::is_whitespace) {
+            return Err(DrainError::InvalidParamStr(self.param_str.clone()));
+        }
+        if self.max_children == 0 {
+            return Err(DrainError::ZeroMaxChildren);
+        }
+        if self.max_clusters == Some(0) {
+            return Err(DrainError::ZeroMaxClusters);
+        }
+        if let Some(penalty) = self
+            .trailing_penalty
+            .filter(|penalty| !(0.0..=1.0).contains(penalty))
+        {
+            return Err(DrainError::InvalidTrailingPenalty(penalty));
+        }
+        if let Some((_, wildcard)) = self.token_classes.iter().find(|(_, wildcard)| {
+            wildcard.is_empty()
+                || wildcard.contains(char::is_whitespace)
+                || *wildcard == self.param_str
+        }) {
+            return Err(DrainError::InvalidClassWildcard(wildcard.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl Drain {
+    /// Return the current settings.
+    pub fn config(&self) -> DrainConfig {
+        let cap = self.id_to_cluster.cap().get();
+        DrainConfig {
+            max_clusters: (cap != usize::MAX).then_some(cap),
+            max_node_depth: self.max_node_depth,
+            sim_th: self.sim_th,
+            update_th: self.update_th,
+            max_children: self.max_children,
+            param_str: self.param_str.clone(),
+            max_tokens: self.max_tokens,
+            min_distinct_values: self.min_distinct_values,
+            typed_params: self.typed_params,
+            tab_delimited: self.tab_delimited,
+            punctuation: self.punctuation,
+            max_total_tokens: self.max_total_tokens,
+            separator: self.separator.clone(),
+            id_strategy: self.id_strategy,
+            tie_break: self.tie_break,
+            wildcard_runs: self.wildcard_runs,
+            facility: self.facility,
+            window_secs: self.window_secs,
+            normalization: self.normalization,
+            max_line_bytes: self.max_line_bytes,
+            mixed_lengths: self.mixed_lengths,
+            trailing_penalty: self.trailing_penalty,
+            dedup_tokens: self.dedup_tokens,
+            delimiters: self.delimiters.clone(),
+            token_classes: self.token_classes.clone(),
+        }
+    }
+
+    /// Replace the settings with `config`, leaving them unchanged if it is invalid.
+    ///
+    /// Changes apply to the following log messages only; existing templates are kept.
+    /// Lower `max_clusters` or `max_total_tokens` evict the least recently used clusters
+    /// right away. Call `rebuild` afterwards for tree settings such as `max_node_depth`,
+    /// `max_children` or `mixed_lengths` to apply to the existing clusters as well.
+    pub fn apply_config(&mut self, config: DrainConfig) -> Result<(), DrainError> {
+        config.validate()?;
+
+        self.set_max_clusters(config.max_clusters)?;
+        self.max_node_depth = config.max_node_depth;
+        self.sim_th = config.sim_th;
+        self.update_th = config.update_th;
+        self.max_children = config.max_children;
+        self.param_str = config.param_str;
+        self.max_tokens = config.max_tokens;
+        self.min_distinct_values = config.min_distinct_values;
+        self.typed_params = config.typed_params;
+        self.tab_delimited = config.tab_delimited;
+        self.punctuation = config.punctuation;
+        self.max_total_tokens = config.max_total_tokens;
+        self.separator = config.separator;
+        self.id_strategy = config.id_strategy;
+        self.tie_break = config.tie_break;
+        self.wildcard_runs = config.wildcard_runs;
+        self.facility = config.facility;
+        self.window_secs = config.window_secs.filter(|window_secs| *window_secs > 0);
+        self.normalization = config.normalization;
+        self.max_line_bytes = config.max_line_bytes;
+        self.mixed_lengths = config.mixed_lengths;
+        self.trailing_penalty = config.trailing_penalty;
+        self.dedup_tokens = config.dedup_tokens;
+        self.delimiters = config
+            .delimiters
+            .filter(|delimiters| !delimiters.is_empty());
+        self.token_classes = config.token_classes;
+        self.evict_over_token_budget();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod apply_config {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            assert_eq!(drain.config(), DrainConfig::default());
+
+            let config = DrainConfig {
+                max_clusters: Some(2),
+                max_node_depth: 3,
+                sim_th: 0.5,
+                update_th: Some(0.8),
+                max_children: 10,
+                param_str: String::from("<#>"),
+                max_tokens: Some(20),
+                min_distinct_values: Some(2),
+                typed_params: true,
+                tab_delimited: true,
+                punctuation: PunctuationPolicy::Strip,
+                max_total_tokens: Some(100),
+                separator: String::from("\t"),
+                id_strategy: IdStrategy::TemplateHash,
+                tie_break: TieBreak::SmallerId,
+                wildcard_runs: WildcardRuns::Count,
+                facility: true,
+                window_secs: Some(300),
+                normalization: Normalization::ALL,
+                max_line_bytes: Some(4096),
+                mixed_lengths: true,
+                trailing_penalty: Some(0.5),
+                dedup_tokens: true,
+                delimiters: Some(vec![' ', '=']),
+                token_classes: vec![(TokenClass::Hex, String::from("<HEX>"))],
+            };
+            drain.apply_config(config.clone()).unwrap();
+            assert_eq!(drain.config(), config);
+        }
+
+        #[test]
+        fn test_evict() {
+            let mut drain = Drain::default();
+            for log in ["disk is full", "user alice logged in", "connection reset"] {
+                drain.train(log);
+            }
+            drain
+                .apply_config(DrainConfig {
+                    max_clusters: Some(2),
+                    ..drain.config()
+                })
+                .unwrap();
+            let templates: Vec<String> = drain
+                .clusters_by_id()
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect();
+            assert_eq!(templates, vec!["user alice logged in", "connection reset"]);
+            assert_eq!(drain.total_tokens, 6);
+        }
+
+        #[test]
+        fn test_invalid() {
+            let mut drain = Drain::default();
+            let config = DrainConfig {
+                sim_th: 0.5,
+                update_th: Some(0.3),
+                ..DrainConfig::default()
+            };
+            assert_eq!(
+                drain.apply_config(config),
+                Err(DrainError::InvalidUpdateThreshold(0.3))
+            );
+            assert_eq!(drain.config(), DrainConfig::default());
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use thiserror::Error;
+
+/// Errors returned when constructing or loading a `Drain`.
+#[derive(Debug, Error, PartialEq)]
+pub enum DrainError {
+    #[error("sim_th must be within 0.0..=1.0, but got {0}")]
+    InvalidSimThreshold(f32),
+    #[error("param_str must be non-empty and contain no whitespace, but got {0:?}")]
+    InvalidParamStr(String),
+    #[error("max_children must be at least 1")]
+    ZeroMaxChildren,
+    #[error("max_clusters must be at least 1")]
+    ZeroMaxClusters,
+    #[error("update_th must be within sim_th..=1.0, but got {0}")]
+    InvalidUpdateThreshold(f32),
+    #[error("delimiter regex must not match the empty string, but got {0:?}")]
+    EmptyDelimiter(String),
+    #[error("class wildcard must be non-empty, contain no whitespace and differ from param_str, but got {0:?}")]
+    InvalidClassWildcard(String),
+    #[error("trailing_penalty must be within 0.0..=1.0, but got {0}")]
+    InvalidTrailingPenalty(f32),
+    #[error("invalid model: {0}")]
+    InvalidModel(String),
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    num::NonZeroUsize,
+    sync::atomic::AtomicUsize,
+};
+
+use lru::LruCache;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{
+    Drain, DrainError, IdStrategy, LogCluster, Node, Normalization, PunctuationPolicy, TieBreak,
+    TokenClass, WildcardRuns,
+};
+
+/// Borrowed view of `Drain` used for serialization.
+///
+/// Clusters are stored from the least to the most recently used,
+/// so that re-inserting them in order restores the LRU order.
+#[derive(Serialize)]
+struct DrainRef<'a> {
+    clusters: Vec<&'a LogCluster>,
+    max_clusters: Option<usize>,
+    max_node_depth: usize,
+    sim_th: f32,
+    update_th: Option<f32>,
+    max_children: usize,
+    cluster_counter: usize,
+    root: &'a Node,
+    param_str: &'a str,
+    max_tokens: Option<usize>,
+    min_distinct_values: Option<usize>,
+    typed_params: bool,
+    tab_delimited: bool,
+    punctuation: PunctuationPolicy,
+    max_total_tokens: Option<usize>,
+    separator: &'a str,
+    id_strategy: IdStrategy,
+    tie_break: TieBreak,
+    wildcard_runs: WildcardRuns,
+    total_lines: usize,
+    facility: bool,
+    window_secs: Option<u64>,
+    normalization: Normalization,
+    max_line_bytes: Option<usize>,
+    mixed_lengths: bool,
+    trailing_penalty: Option<f32>,
+    dedup_tokens: bool,
+    delimiters: Option<Vec<char>>,
+    token_classes: Vec<(TokenClass, String)>,
+}
+
+#[derive(Deserialize)]
+struct DrainRepr {
+    clusters: Vec<LogCluster>,
+    max_clusters: Option<usize>,
+    max_node_depth: usize,
+    sim_th: f32,
+    #[serde(default)]
+    update_th: Option<f32>,
+    max_children: usize,
+    cluster_counter: usize,
+    root: Node,
+    param_str: String,
+    max_tokens: Option<usize>,
+    min_distinct_values: Option<usize>,
+    #[serde(default)]
+    typed_params: bool,
+    #[serde(default)]
+    tab_delimited: bool,
+    #[serde(default)]
+    punctuation: PunctuationPolicy,
+    max_total_tokens: Option<usize>,
+    #[serde(default = "default_separator")]
+    separator: String,
+    #[serde(default)]
+    id_strategy: IdStrategy,
+    #[serde(default)]
+    tie_break: TieBreak,
+    #[serde(default)]
+    wildcard_runs: WildcardRuns,
+    #[serde(default)]
+    total_lines: Option<usize>,
+    #[serde(default)]
+    facility: bool,
+    #[serde(default)]
+    window_secs: Option<u64>,
+    #[serde(default)]
+    normalization: Normalization,
+    #[serde(default)]
+    max_line_bytes: Option<usize>,
+    #[serde(default)]
+    mixed_lengths: bool,
+    #[serde(default)]
+    trailing_penalty: Option<f32>,
+    #[serde(default)]
+    dedup_tokens: bool,
+    #[serde(default)]
+    delimiters: Option<Vec<char>>,
+    #[serde(default)]
+    token_classes: Vec<(TokenClass, String)>,
+}
+
+fn default_separator() -> String {
+    String::from(" ")
+}
+
+impl Serialize for Drain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let cap = self.id_to_cluster.cap().get();
+        DrainRef {
+            clusters: self.id_to_cluster.iter().rev().map(|(_, v)| v).collect(),
+            max_clusters: (cap != usize::MAX).then_some(cap),
+            max_node_depth: self.max_node_depth,
+            sim_th: self.sim_th,
+            update_th: self.update_th,
+            max_children: self.max_children,
+            cluster_counter: self.cluster_counter,
+            root: &self.root,
+            param_str: &self.param_str,
+            max_tokens: self.max_tokens,
+            min_distinct_values: self.min_distinct_values,
+            typed_params: self.typed_params,
+            tab_delimited: self.tab_delimited,
+            punctuation: self.punctuation,
+            max_total_tokens: self.max_total_tokens,
+            separator: &self.separator,
+            id_strategy: self.id_strategy,
+            tie_break: self.tie_break,
+            wildcard_runs: self.wildcard_runs,
+            total_lines: self.total_lines,
+            facility: self.facility,
+            window_secs: self.window_secs,
+            normalization: self.normalization,
+            max_line_bytes: self.max_line_bytes,
+            mixed_lengths: self.mixed_lengths,
+            trailing_penalty: self.trailing_penalty,
+            dedup_tokens: self.dedup_tokens,
+            delimiters: self.delimiters.clone(),
+            token_classes: self.token_classes.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Drain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = DrainRepr::deserialize(deserializer)?;
+        Drain::from_repr(repr).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Drain {
+    /// Restore the model, rejecting the settings `DrainBuilder::build` rejects.
+    fn from_repr(repr: DrainRepr) -> Result<Self, DrainError> {
+        let mut id_to_cluster = match repr.max_clusters {
+            Some(max_clusters) => {
+                LruCache::new(NonZeroUsize::new(max_clusters).ok_or(DrainError::ZeroMaxClusters)?)
+            }
+            None => LruCache::unbounded(),
+        };
+        // Never hand out an id that is already taken,
+        // even if the stored counter is behind the clusters.
+        let mut cluster_counter = repr.cluster_counter;
+        for cluster in repr.clusters {
+            cluster_counter = cluster_counter.max(cluster.cluster_id);
+            id_to_cluster.put(cluster.cluster_id, cluster);
+        }
+
+        let saturated_nodes = repr.root.count_saturated();
+        // Models saved before `total_lines` count the lines of their clusters.
+        let total_lines = repr
+            .total_lines
+            .unwrap_or_else(|| id_to_cluster.iter().map(|(_, cluster)| cluster.size).sum());
+        let total_tokens = id_to_cluster
+            .iter()
+            .map(|(_, cluster)| cluster.log_template_tokens.len())
+            .sum();
+
+        let mut drain = Self {
+            id_to_cluster,
+            max_node_depth: repr.max_node_depth,
+            sim_th: repr.sim_th,
+            update_th: repr.update_th,
+            max_children: repr.max_children,
+            cluster_counter,
+            root: repr.root,
+            single_token_clusters: HashMap::new(),
+            param_str: repr.param_str,
+            max_tokens: repr.max_tokens,
+            min_distinct_values: repr.min_distinct_values,
+            typed_params: repr.typed_params,
+            tab_delimited: repr.tab_delimited,
+            punctuation: repr.punctuation,
+            max_total_tokens: repr.max_total_tokens,
+            total_tokens,
+            saturated_nodes,
+            total_lines,
+            unmatched_count: AtomicUsize::new(0),
+            case_insensitive: None,
+            separator: repr.separator,
+            id_strategy: repr.id_strategy,
+            tie_break: repr.tie_break,
+            wildcard_runs: repr.wildcard_runs,
+            facility: repr.facility,
+            window_secs: repr.window_secs,
+            #[cfg(feature = "regex")]
+            delimiter: None,
+            normalization: repr.normalization,
+            max_line_bytes: repr.max_line_bytes,
+            mixed_lengths: repr.mixed_lengths,
+            trailing_penalty: repr.trailing_penalty,
+            dedup_tokens: repr.dedup_tokens,
+            delimiters: repr.delimiters,
+            token_classes: repr.token_classes,
+            #[cfg(feature = "regex")]
+            shape_rules: Vec::new(),
+            #[cfg(feature = "regex")]
+            masks: Vec::new(),
+        };
+        drain.config().validate()?;
+        drain.index_single_token_clusters();
+        Ok(drain)
+    }
+}
+
+impl Drain {
+    /// Write the model as JSON, to be restored by `load_from_reader`,
+    /// e.g. to reuse the templates learned from a large corpus across restarts.
+    pub fn save_to_writer<W: Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, self)
+    }
+
+    /// Read a model written by `save_to_writer`, with the same clusters,
+    /// ids, sizes and recency. The predicate of `with_case_insensitive`,
+    /// the regex of `with_delimiter_regex` and shape rules are not saved
+    /// and have to be set again.
+    /// Malformed JSON fails with `DrainError::InvalidModel`, and invalid settings
+    /// such as a hand-edited `sim_th` above 1.0 with the error of that setting.
+    pub fn load_from_reader<R: Read>(reader: R) -> Result<Self, DrainError> {
+        let repr: DrainRepr =
+            serde_json::from_reader(reader).map_err(|e| DrainError::InvalidModel(e.to_string()))?;
+        Drain::from_repr(repr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod load_from_reader {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for log in [
+                "connected to 10.0.0.1",
+                "connected to 10.0.0.2",
+                "user alice logged in",
+                "disk is full",
+            ] {
+                drain.train(log);
+            }
+            let mut json = Vec::new();
+            drain.save_to_writer(&mut json).unwrap();
+            let mut loaded = Drain::load_from_reader(json.as_slice()).unwrap();
+
+            let clusters = |drain: &Drain| -> Vec<(usize, String, usize)> {
+                drain
+                    .clusters()
+                    .iter()
+                    .map(|cluster| (cluster.cluster_id, cluster.to_string(), cluster.size))
+                    .collect()
+            };
+            assert_eq!(clusters(&loaded), clusters(&drain));
+            assert_eq!(loaded.train("connected to 10.0.0.3").cluster_id, 1);
+            assert_eq!(loaded.train("kernel panic").cluster_id, 4);
+
+            assert!(matches!(
+                Drain::load_from_reader(&b"{"[..]),
+                Err(DrainError::InvalidModel(_))
+            ));
+        }
+
+        #[test]
+        fn test_invalid_config() {
+            let mut json = serde_json::to_value(Drain::default()).unwrap();
+            json["sim_th"] = serde_json::json!(1.5);
+            assert_eq!(
+                Drain::load_from_reader(json.to_string().as_bytes()).err(),
+                Some(DrainError::InvalidSimThreshold(1.5))
+            );
+            json["sim_th"] = serde_json::json!(0.4);
+            json["max_clusters"] = serde_json::json!(0);
+            assert_eq!(
+                Drain::load_from_reader(json.to_string().as_bytes()).err(),
+                Some(DrainError::ZeroMaxClusters)
+            );
+            assert!(serde_json::from_value::<Drain>(json).is_err());
+        }
+
+        #[test]
+        fn test_case_insensitive() {
+            let is_level = |token: &str| token.eq_ignore_ascii_case("error");
+            let mut drain = Drain::default().with_case_insensitive(is_level);
+            let cluster = drain.train("ERROR");
+            let mut json = Vec::new();
+            drain.save_to_writer(&mut json).unwrap();
+
+            let mut loaded = Drain::load_from_reader(json.as_slice())
+                .unwrap()
+                .with_case_insensitive(is_level);
+            assert_eq!(
+                loaded.match_log("error").map(|cluster| cluster.cluster_id),
+                Some(cluster.cluster_id)
+            );
+            assert_eq!(loaded.train("Error").size, 2);
+            assert_eq!(loaded.len(), 1);
+        }
+    }
+
+    mod deserialize {
+        use super::*;
+
+        #[test]
+        fn test_cluster_counter() {
+            let mut drain = Drain::new(Some(10), 3, 0.5, 50, "<#>".to_string())
+                .unwrap()
+                .with_max_tokens(Some(20))
+                .with_min_distinct_values(Some(3))
+                .with_typed_params(true)
+                .with_tab_delimited(true)
+                .with_punctuation(PunctuationPolicy::Split)
+                .with_max_total_tokens(Some(1000))
+                .with_separator("\t");
+            drain.train("connected to 10.0.0.1");
+            drain.train("connected to 10.0.0.2");
+            drain.train("user alice logged in");
+            let prev_max = drain
+                .clusters()
+                .iter()
+                .map(|cluster| cluster.cluster_id)
+                .max()
+                .unwrap();
+
+            let json = serde_json::to_string(&drain).unwrap();
+            let mut loaded: Drain = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(loaded.cluster_counter, drain.cluster_counter);
+            assert_eq!(loaded.id_to_cluster.cap(), drain.id_to_cluster.cap());
+            assert_eq!(loaded.max_node_depth, 3);
+            assert_eq!(loaded.sim_th, 0.5);
+            assert_eq!(loaded.max_children, 50);
+            assert_eq!(loaded.param_str, "<#>");
+            assert_eq!(loaded.max_tokens, Some(20));
+            assert_eq!(loaded.min_distinct_values, Some(3));
+            assert!(loaded.typed_params);
+            assert!(loaded.tab_delimited);
+            assert_eq!(loaded.punctuation, PunctuationPolicy::Split);
+            assert_eq!(loaded.max_total_tokens, Some(1000));
+            assert_eq!(loaded.total_tokens, drain.total_tokens);
+            assert_eq!(loaded.saturated_nodes, drain.saturated_nodes);
+            assert_eq!(loaded.total_lines, 3);
+            assert_eq!(loaded.separator, "\t");
+
+            let cluster = loaded.train("disk full on /dev/sda1");
+            assert_eq!(cluster.cluster_id, prev_max + 1);
+        }
+    }
+}
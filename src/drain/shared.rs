@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use super::{Drain, LogCluster};
+
+/// `Drain` shared between tokio tasks.
+///
+/// Each call holds the lock only while it trains, so tasks feeding lines
+/// concurrently are serialized line by line and wait for each other in FIFO order.
+/// Training is CPU-bound and runs on the calling task: a single line is cheap,
+/// but large batches should go through `train_batch`, which trains on a blocking
+/// thread instead of stalling the async workers.
+#[derive(Clone, Default)]
+pub struct SharedDrain {
+    inner: Arc<Mutex<Drain>>,
+}
+
+impl From<Drain> for SharedDrain {
+    fn from(drain: Drain) -> Self {
+        Self::new(drain)
+    }
+}
+
+impl SharedDrain {
+    pub fn new(drain: Drain) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(drain)),
+        }
+    }
+
+    /// Wait for the lock and train on `line`, see `Drain::train`.
+    pub async fn train(&self, line: String) -> LogCluster {
+        self.inner.lock().await.train(line)
+    }
+
+    /// Train on `line` only if no other task holds the lock,
+    /// returning `None` instead of waiting otherwise.
+    pub fn try_train(&self, line: &str) -> Option<LogCluster> {
+        self.inner
+            .try_lock()
+            .ok()
+            .map(|mut drain| drain.train(line))
+    }
+
+    /// Train on `lines` in order on a blocking thread, holding the lock for the whole batch.
+    /// Must be called within a tokio runtime.
+    pub async fn train_batch(&self, lines: Vec<String>) -> Vec<LogCluster> {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || {
+            let mut drain = inner.blocking_lock();
+            lines.iter().map(|line| drain.train(line)).collect()
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+
+    /// Wait for the lock to read or configure the `Drain`.
+    /// Other tasks cannot train until the guard is dropped.
+    pub async fn lock(&self) -> MutexGuard<'_, Drain> {
+        self.inner.lock().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod train {
+        use super::*;
+
+        #[tokio::test]
+        async fn test() {
+            let drain = SharedDrain::default();
+            let tasks: Vec<_> = (1..=4)
+                .map(|i| {
+                    let drain = drain.clone();
+                    tokio::spawn(
+                        async move { drain.train(format!("connected to 10.0.0.{}", i)).await },
+                    )
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            let guard = drain.lock().await;
+            let clusters = guard.clusters_by_id();
+            assert_eq!(clusters.len(), 1);
+            assert_eq!(clusters[0].to_string(), "connected to <*>");
+            assert_eq!(clusters[0].size, 4);
+        }
+    }
+
+    mod try_train {
+        use super::*;
+
+        #[tokio::test]
+        async fn test() {
+            let drain = SharedDrain::default();
+            let guard = drain.lock().await;
+            assert!(drain.try_train("disk is full").is_none());
+            drop(guard);
+            assert_eq!(
+                drain.try_train("disk is full").unwrap().to_string(),
+                "disk is full"
+            );
+        }
+    }
+
+    mod train_batch {
+        use super::*;
+
+        #[tokio::test]
+        async fn test() {
+            let drain = SharedDrain::from(Drain::default());
+            let lines = vec![
+                String::from("user alice logged in"),
+                String::from("user bob logged in"),
+            ];
+            let templates: Vec<String> = drain
+                .train_batch(lines)
+                .await
+                .iter()
+                .map(|cluster| cluster.to_string())
+                .collect();
+            assert_eq!(
+                templates,
+                vec!["user alice logged in", "user <*> logged in"]
+            );
+        }
+    }
+}
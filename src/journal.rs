@@ -0,0 +1,97 @@
+use std::{io, process::Stdio};
+
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Extract the `MESSAGE` field of an entry printed by `journalctl --output=json`.
+/// It is a string, or an array of bytes if it is not valid UTF-8.
+fn message(entry: &[u8]) -> Option<Vec<u8>> {
+    let entry: serde_json::Value = serde_json::from_slice(entry).ok()?;
+    match entry.get("MESSAGE")? {
+        serde_json::Value::String(message) => Some(message.clone().into_bytes()),
+        serde_json::Value::Array(bytes) => bytes
+            .iter()
+            .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Follow the systemd journal from its current end, restricted to `units` if any,
+/// and return a channel receiving the `MESSAGE` of each new entry.
+///
+/// Entries are read from `journalctl`, which keeps up with rotation
+/// and is killed once `canceled`.
+pub fn follow(
+    units: &[String],
+    canceled: CancellationToken,
+) -> io::Result<mpsc::Receiver<Vec<u8>>> {
+    let mut command = Command::new("journalctl");
+    command.args(["--follow", "--lines=0", "--output=json"]);
+    for unit in units {
+        command.arg("--unit").arg(unit);
+    }
+    // Errors of journalctl cannot be shown over the TUI.
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::other("stdout of journalctl is not piped"))?;
+
+    let (tx, rx) = mpsc::channel(1024);
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(stdout).split(b'\n');
+        loop {
+            tokio::select! {
+                _ = canceled.cancelled() => break,
+                ret = reader.next_segment() => match ret {
+                    Ok(Some(entry)) => {
+                        // Entries without a message, e.g. some kernel records, are skipped.
+                        if let Some(message) = message(&entry) {
+                            if tx.send(message).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    _ => break,
+                },
+            }
+        }
+        // Nothing to do if it has already exited.
+        let _ = child.kill().await;
+    });
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod message {
+        use super::*;
+
+        #[test]
+        fn test() {
+            assert_eq!(
+                message(br#"{"_SYSTEMD_UNIT":"app.service","MESSAGE":"user alice logged in"}"#),
+                Some(b"user alice logged in".to_vec())
+            );
+            assert_eq!(
+                message(br#"{"MESSAGE":[117,115,101,114,32,255]}"#),
+                Some(b"user \xff".to_vec())
+            );
+            assert_eq!(message(br#"{"MESSAGE":null}"#), None);
+            assert_eq!(message(br#"{"PRIORITY":"6"}"#), None);
+            assert_eq!(message(b"not json"), None);
+        }
+    }
+}
@@ -1 +1,32 @@
+//! Log template mining with Drain, the engine of the `logu` binary.
+//!
+//! Build with `--no-default-features` to leave out the TUI dependencies.
+//!
+//! ```
+//! use logu::Drain;
+//!
+//! let mut drain = Drain::default();
+//! drain.train("connected to 10.0.0.1");
+//! let cluster = drain.train("connected to 10.0.0.2");
+//! assert_eq!(cluster.to_string(), "connected to <*>");
+//! ```
+//!
+//! Other settings are given through `Drain::builder`:
+//!
+//! ```
+//! use logu::drain::PunctuationPolicy;
+//!
+//! let drain = logu::Drain::builder()
+//!     .sim_th(0.5)
+//!     .typed_params(true)
+//!     .punctuation(PunctuationPolicy::Strip)
+//!     .build()
+//!     .unwrap();
+//! assert!(drain.config().typed_params);
+//! ```
+
 pub mod drain;
+pub use drain::{Drain, LogCluster};
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -1,24 +1,89 @@
-use std::io;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use futures::FutureExt;
 use promkit::{
     crossterm::{
         self, cursor,
-        event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers},
-        style,
+        event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+        style::{self, Attribute, Color, ContentStyle},
         terminal::{disable_raw_mode, enable_raw_mode},
     },
     grapheme::StyledGraphemes,
 };
+use regex::Regex;
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufReadExt, BufReader, Split, Stdin},
+    sync::mpsc,
     task::JoinHandle,
     time::{self, timeout, Duration},
 };
 use tokio_util::sync::CancellationToken;
 
-mod drain;
-use drain::Drain;
+use logu::drain::{
+    CoverageReport, Drain, IdStrategy, LogCluster, Normalization, PunctuationPolicy, TieBreak,
+    TokenClass, TrainInfo, TrainOutcome, WildcardRuns, DEFAULT_MAX_CHILDREN,
+    DEFAULT_MAX_NODE_DEPTH, DEFAULT_PARAM_STR, DEFAULT_SIM_TH, NUM_PARAM_STR,
+};
+
+mod mask;
+use mask::Mask;
+mod theme;
+use theme::{Theme, ThemeName};
+#[cfg(all(target_os = "linux", feature = "journald"))]
+mod journal;
+#[cfg(unix)]
+mod unix;
+mod watch;
+#[cfg(feature = "web")]
+mod web;
+
+/// Default of `--max-clusters`, bounded to keep memory in check on long streams.
+const DEFAULT_MAX_CLUSTERS: &str = "5000";
+
+/// Parse `--max-clusters`, where `0` and `none` mean unbounded.
+fn parse_max_clusters(s: &str) -> Result<Option<usize>, String> {
+    if s.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    let max_clusters: usize = s.parse().map_err(|e| format!("{}", e))?;
+    Ok((max_clusters != 0).then_some(max_clusters))
+}
+
+/// How to handle lines that are not valid UTF-8.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum InvalidUtf8 {
+    /// Replace invalid byte sequences with U+FFFD.
+    Lossy,
+    /// Drop the line entirely.
+    Skip,
+}
+
+/// Order of the clusters in the TUI.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Sort {
+    /// The most recently matched first.
+    Recent,
+    /// The largest first.
+    Size,
+    /// The highest current rate of lines first.
+    Rate,
+}
+
+/// How `--once` prints the templates.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Output {
+    /// `[SIZE] TEMPLATE` per line.
+    Text,
+    /// `id=ID size=SIZE template="TEMPLATE"` per line.
+    Logfmt,
+}
 
 #[derive(Parser)]
 #[command(name = "logu", version)]
@@ -39,9 +104,564 @@ pub struct Args {
     )]
     pub render_interval_millis: u64,
 
+    #[arg(
+        long = "full-clear",
+        default_value = "false",
+        help = "Clear the entire screen on every render.",
+        long_help = "By default only the rows that changed since the previous render
+        are rewritten. Use this for terminals that misbehave with partial updates."
+    )]
+    pub full_clear: bool,
+
+    #[arg(
+        long = "plain",
+        default_value = "false",
+        conflicts_with_all = [
+            "diff", "tree", "group", "annotate_json", "once", "output", "inference", "evaluate",
+        ],
+        help = "Print frames as plain text lines instead of drawing the TUI.",
+        long_help = "Whenever the clusters change, at most once per render interval,
+        print a `---` header row followed by `[SIZE] TEMPLATE` lines, without raw mode,
+        cursor movements or colors, so that the output can be appended to a file.
+        The last frame is printed at the end of the input."
+    )]
+    pub plain: bool,
+
+    #[arg(
+        long = "invalid-utf8",
+        value_enum,
+        default_value_t = InvalidUtf8::Lossy,
+        help = "How to handle lines that are not valid UTF-8.",
+        long_help = "`lossy` replaces invalid byte sequences with U+FFFD and trains on the line.
+        `skip` drops the line; the number of skipped lines is reported on exit."
+    )]
+    pub invalid_utf8: InvalidUtf8,
+
+    #[arg(
+        long = "max-line-bytes",
+        value_name = "BYTES",
+        default_value = None,
+        help = "Cut lines longer than BYTES right after reading them.",
+        long_help = "Guards against pathological input such as huge JSON blobs on a single line,
+        which would otherwise stall normalization, tokenizing and rendering.
+        The cut falls back to a UTF-8 character boundary,
+        and the number of cut lines is reported on exit."
+    )]
+    pub max_line_bytes: Option<usize>,
+
+    #[arg(
+        long = "sort",
+        value_enum,
+        default_value_t = Sort::Recent,
+        help = "Order of the clusters.",
+        long_help = "`recent` shows the most recently matched clusters first, `size` the largest,
+        and `rate` those receiving the most lines per second lately,
+        to catch a pattern spiking even if its total size is modest."
+    )]
+    pub sort: Sort,
+
+    #[arg(
+        long = "theme",
+        value_enum,
+        default_value_t = ThemeName::Dark,
+        help = "Color theme for rendering."
+    )]
+    pub theme: ThemeName,
+
+    #[arg(
+        long = "level-colors",
+        default_value = "false",
+        help = "Color clusters by the log level they contain.",
+        long_help = "Clusters containing one of `--error-levels` are rendered
+        in the error color of `--theme` (red for `dark`),
+        and those containing one of `--warn-levels` in its warning color (yellow for `dark`)."
+    )]
+    pub level_colors: bool,
+
+    #[arg(
+        long = "error-levels",
+        value_delimiter = ',',
+        default_value = "ERROR,FATAL,CRITICAL",
+        help = "Comma-separated level tokens rendered as errors with `--level-colors`."
+    )]
+    pub error_levels: Vec<String>,
+
+    #[arg(
+        long = "warn-levels",
+        value_delimiter = ',',
+        default_value = "WARN,WARNING",
+        help = "Comma-separated level tokens rendered as warnings with `--level-colors`."
+    )]
+    pub warn_levels: Vec<String>,
+
+    #[arg(
+        long = "multiline",
+        value_name = "START_REGEX",
+        value_parser = Regex::new,
+        help = "Join lines not matching START_REGEX to the previous entry.",
+        long_help = "Treat a line matching START_REGEX as the beginning of a log entry,
+        and append the following non-matching lines (e.g. stack traces) to it.
+        The entry is trained when the next start line arrives or reading times out."
+    )]
+    pub multiline: Option<Regex>,
+
+    #[arg(
+        long = "mask",
+        value_name = "REGEX -> REPLACEMENT",
+        help = "Replace matches of REGEX with REPLACEMENT before training. Can be repeated.",
+        long_help = "Masks are applied in the given order, e.g.
+        `--mask '(?P<ip>\\d+\\.\\d+\\.\\d+\\.\\d+) -> <IP>'`.
+        `NAME=REGEX` is short for `REGEX -> <NAME>` with NAME in upper case,
+        e.g. `--mask 'ip=\\d+\\.\\d+\\.\\d+\\.\\d+'`.
+        The values of named capture groups are reported with `--annotate-json`."
+    )]
+    pub masks: Vec<Mask>,
+
+    #[arg(
+        long = "masks-file",
+        value_name = "FILE",
+        default_value = None,
+        help = "Read more masks from FILE, one `REGEX -> REPLACEMENT` per line.",
+        long_help = "Masks of FILE are applied after those of `--mask`.
+        Blank lines and lines starting with `#` are ignored.
+        On SIGHUP, FILE is read again and the new masks apply to the following lines;
+        the existing templates are kept. If FILE fails to load, the previous masks stay.
+        Only the masks are reloaded; other settings such as `--sim-th` need a restart."
+    )]
+    pub masks_file: Option<PathBuf>,
+
+    #[arg(
+        long = "check-config",
+        value_name = "FILE",
+        default_value = None,
+        help = "Validate a masks file, print the problems found and exit.",
+        long_help = "Report lines of FILE that fail to parse, replacements colliding with
+        the wildcards of `--param-str`, `--typed-params` and `--token-class`
+        and overly broad patterns, without reading any log.
+        Exit with status 1 if FILE has errors, i.e. would fail with `--masks-file`."
+    )]
+    pub check_config: Option<PathBuf>,
+
+    /// Masks loaded from `masks_file`.
+    #[arg(skip)]
+    pub file_masks: Vec<Mask>,
+
+    #[arg(
+        long = "annotate-json",
+        default_value = "false",
+        conflicts_with_all = ["diff", "tree", "group", "watch_dir", "multiline", "invalid_utf8"],
+        help = "Print a JSON object per line with its cluster id and masked values.",
+        long_help = "Read stdin and print `{\"cluster_id\": ..., \"params\": {...}}` for each line,
+        where `params` holds the named capture groups of `--mask`. The TUI is not started.
+        Every line is annotated, so `--multiline` and `--invalid-utf8` cannot be given."
+    )]
+    pub annotate_json: bool,
+
+    #[arg(
+        long = "diff",
+        num_args = 2,
+        value_names = ["OLD", "NEW"],
+        help = "Compare the templates of two log files and print a report.",
+        long_help = "Train on OLD and NEW separately with the same parameters,
+        then print templates that only appear in NEW, only in OLD,
+        and those whose number of messages changed. The TUI is not started."
+    )]
+    pub diff: Option<Vec<PathBuf>>,
+
+    #[arg(
+        long = "refresh-on-change",
+        default_value = "false",
+        help = "Refresh only when a cluster appears, disappears or doubles in size.",
+        long_help = "By default the list is refreshed on every render tick if anything changed.
+        With this flag, the output is refreshed only when a reporting boundary is crossed:
+        a new cluster appears, a cluster is evicted, or a cluster size doubles
+        since the last refresh."
+    )]
+    pub refresh_on_change: bool,
+
+    #[arg(
+        long = "compression-ratio",
+        default_value = "false",
+        help = "Show the number of lines per cluster in a header row.",
+        long_help = "Reserve the first row for the total number of lines, the number of clusters
+        and their ratio. A ratio rising over time shows that templates absorb the lines,
+        while a ratio near 1 suggests the config does not collapse anything."
+    )]
+    pub compression_ratio: bool,
+
+    #[arg(
+        long = "sparkline",
+        default_value = "false",
+        help = "Show the lines per second over the last render ticks in a header row.",
+        long_help = "Reserve a row for a sparkline of block characters, one per render tick
+        over the last 60 ticks, scaled to the highest rate among them,
+        followed by the current rate. Spikes of log volume stand out at a glance."
+    )]
+    pub sparkline: bool,
+
+    #[arg(
+        long = "shutdown-timeout",
+        default_value = "500",
+        help = "Time to wait for the reader to stop after ctrl+c in milliseconds."
+    )]
+    pub shutdown_timeout_millis: u64,
+
+    #[arg(
+        long = "tree",
+        default_value = "false",
+        help = "Read stdin until EOF and print the prefix tree.",
+        long_help = "Print an indented view of the prefix tree built from stdin:
+        token-count buckets, the tokens below them, and the clusters at each leaf.
+        The TUI is not started."
+    )]
+    pub tree: bool,
+
+    #[arg(
+        long = "dot",
+        default_value = "false",
+        requires = "tree",
+        help = "Print the prefix tree of `--tree` as Graphviz DOT, e.g. for `dot -Tpng`."
+    )]
+    pub dot: bool,
+
+    #[arg(
+        long = "explain-line",
+        value_name = "LINE",
+        default_value = None,
+        conflicts_with_all = ["diff", "tree", "group", "annotate_json", "once", "output", "watch_dir"],
+        help = "Read stdin until EOF and print how LINE is matched against the clusters.",
+        long_help = "Print the tokens of LINE, the keys it follows in the prefix tree,
+        the similarity of each cluster compared at the leaf and the cluster it matches,
+        to understand why lines were grouped or not. LINE is not trained.
+        Use `--state-file` with empty stdin to explain against a saved model."
+    )]
+    pub explain_line: Option<String>,
+
+    #[arg(
+        long = "group",
+        default_value = "false",
+        conflicts_with_all = ["multiline", "invalid_utf8"],
+        help = "Read stdin until EOF and print each template followed by its lines.",
+        long_help = "Print a grouped report instead of starting the TUI:
+        each template is followed by the original lines that matched it.
+        Use `--group-max-lines` to bound the lines kept per template.
+        Every line is shown as read, so `--multiline` and `--invalid-utf8` cannot be given."
+    )]
+    pub group: bool,
+
+    #[arg(
+        long = "group-max-lines",
+        default_value = None,
+        requires = "group",
+        help = "Maximum number of lines kept per template with `--group`."
+    )]
+    pub group_max_lines: Option<usize>,
+
+    #[arg(
+        long = "idle-timeout",
+        value_name = "SECONDS",
+        default_value = None,
+        help = "Treat the input as finished when no line arrives for this duration.",
+        long_help = "Useful for stalled pipelines that never signal EOF.
+        With `--tree` and `--group` the result is printed,
+        and in the TUI training stops while the list keeps being shown."
+    )]
+    pub idle_timeout_secs: Option<u64>,
+
+    #[arg(
+        long = "inactive-after",
+        value_name = "SECONDS",
+        default_value = None,
+        help = "Hide clusters that have not matched any line for this duration.",
+        long_help = "Inactive clusters are kept in the model and keep counting,
+        but drop off the list. Use `--show-inactive` to dim them instead of hiding."
+    )]
+    pub inactive_after_secs: Option<u64>,
+
+    #[arg(
+        long = "show-inactive",
+        default_value = "false",
+        help = "Dim inactive clusters instead of hiding them with `--inactive-after`."
+    )]
+    pub show_inactive: bool,
+
+    #[arg(
+        long = "window",
+        value_name = "SECONDS",
+        default_value = None,
+        help = "Count only the lines of the last SECONDS in cluster sizes.",
+        long_help = "Older lines age out of the sizes in steps of SECONDS / 60,
+        and clusters without lines left in the window are removed,
+        so that the list reflects recent activity rather than the whole history."
+    )]
+    pub window_secs: Option<u64>,
+
+    #[arg(
+        long = "prune-below",
+        value_name = "SIZE",
+        default_value = None,
+        help = "Periodically remove the clusters with fewer than SIZE lines.",
+        long_help = "Clears singleton noise out of long-running sessions, every `--prune-every`.
+        Removed clusters are forgotten: their lines start new clusters if they come back."
+    )]
+    pub prune_below: Option<usize>,
+
+    #[arg(
+        long = "prune-every",
+        value_name = "SECONDS",
+        default_value = "60",
+        requires = "prune_below",
+        help = "Interval of `--prune-below`."
+    )]
+    pub prune_every_secs: u64,
+
+    #[arg(
+        long = "watch-dir",
+        value_name = "DIR",
+        default_value = None,
+        conflicts_with_all = ["diff", "tree", "group"],
+        help = "Follow the files in this directory instead of reading stdin.",
+        long_help = "Lines of all files matching `--glob` are clustered together.
+        Files present at startup are followed from their end,
+        and files created later, e.g. by log rotation, from their beginning."
+    )]
+    pub watch_dir: Option<PathBuf>,
+
+    #[arg(
+        long = "glob",
+        value_name = "PATTERN",
+        default_value = "*",
+        value_parser = glob::Pattern::new,
+        help = "File names to follow with `--watch-dir`."
+    )]
+    pub glob: glob::Pattern,
+
+    #[cfg(unix)]
+    #[arg(
+        long = "unix",
+        value_name = "PATH",
+        default_value = None,
+        conflicts_with_all = ["diff", "tree", "group", "annotate_json", "watch_dir"],
+        help = "Listen on a Unix domain socket at PATH instead of reading stdin.",
+        long_help = "Any number of local clients can connect and write logs,
+        newline-delimited unless `--framing` says otherwise, which are clustered together. The socket file is removed on exit."
+    )]
+    pub unix: Option<PathBuf>,
+
+    #[cfg(unix)]
+    #[arg(
+        long = "framing",
+        value_name = "FRAMING",
+        default_value = "newline",
+        requires = "unix",
+        help = "How `--unix` messages are delimited: newline, null or length-prefix[=BYTES].",
+        long_help = "`null` delimits messages with NUL bytes, so that they may contain newlines.
+        `length-prefix` precedes each message with its length as a big-endian unsigned integer
+        of BYTES bytes, 4 by default. A connection sending a malformed frame is closed."
+    )]
+    pub framing: unix::Framing,
+
+    #[cfg(all(target_os = "linux", feature = "journald"))]
+    #[arg(
+        long = "journal",
+        default_value = "false",
+        conflicts_with_all = [
+            "diff", "tree", "group", "annotate_json", "once", "output", "inference",
+            "evaluate", "explain_line", "watch_dir", "unix", "replay",
+        ],
+        help = "Follow the systemd journal instead of reading stdin.",
+        long_help = "The MESSAGE field of each new entry is clustered, as read from
+        `journalctl --follow --output=json`, without parsing its text output."
+    )]
+    pub journal: bool,
+
+    #[cfg(all(target_os = "linux", feature = "journald"))]
+    #[arg(
+        long = "unit",
+        value_name = "UNIT",
+        requires = "journal",
+        help = "Only follow the entries of UNIT with `--journal`; can be repeated."
+    )]
+    pub units: Vec<String>,
+
+    #[arg(
+        long = "replay",
+        value_name = "FILE",
+        default_value = None,
+        conflicts_with_all = ["diff", "watch_dir", "unix"],
+        help = "Read the lines recorded with `--record` from FILE instead of stdin.",
+        long_help = "The lines are fed in order, as fast as possible or at `--replay-rate`,
+        to reproduce the clusters of a recorded stream with the same flags."
+    )]
+    pub replay: Option<PathBuf>,
+
+    #[arg(
+        long = "replay-rate",
+        value_name = "LINES_PER_SEC",
+        default_value = None,
+        requires = "replay",
+        help = "Feed the lines of `--replay` to the TUI at this rate instead of all at once."
+    )]
+    pub replay_rate: Option<std::num::NonZeroU32>,
+
+    #[arg(
+        long = "state-file",
+        value_name = "FILE",
+        default_value = None,
+        help = "Load the model from FILE if it exists, and save it there on exit.",
+        long_help = "The Drain parameters stored in FILE take precedence over the flags.
+        The model is not saved when `--fail-on-new` is given,
+        so that the baseline is left untouched by checks."
+    )]
+    pub state_file: Option<PathBuf>,
+
+    #[arg(
+        long = "once",
+        default_value = "false",
+        conflicts_with_all = ["diff", "tree", "group", "annotate_json", "watch_dir"],
+        help = "Read stdin until EOF, print the templates and exit without the TUI."
+    )]
+    pub once: bool,
+
+    #[arg(
+        long = "output",
+        value_name = "FORMAT",
+        value_enum,
+        default_value = None,
+        conflicts_with_all = ["diff", "tree", "group", "annotate_json", "watch_dir"],
+        help = "Print the templates in FORMAT as `--once` does, without the TUI.",
+        long_help = "`text` prints `[SIZE] TEMPLATE` lines, the default of `--once`.
+        `logfmt` prints `id=ID size=SIZE template=\"TEMPLATE\"` lines
+        for logfmt-based systems, quoting values as needed."
+    )]
+    pub output: Option<Output>,
+
+    #[arg(
+        long = "quiet",
+        default_value = "false",
+        help = "Do not print the progress of `--once` to stderr.",
+        long_help = "By default, `--once` prints `processed N lines, M clusters` to stderr
+        every few seconds while stderr is a terminal, with a percentage when the size
+        of the input is known, i.e. with `--replay` or a file redirected to stdin."
+    )]
+    pub quiet: bool,
+
+    #[arg(
+        long = "fail-on-new",
+        default_value = "false",
+        requires = "once",
+        requires = "state_file",
+        help = "Exit with 1 if a cluster was created, printing its template to stderr.",
+        long_help = "Intended as a log regression check in CI: clusters created while reading
+        are compared against the baseline loaded from `--state-file`, which must exist.
+        Clusters evicted by `--max-clusters` afterwards are still reported."
+    )]
+    pub fail_on_new: bool,
+
+    #[arg(
+        long = "inference",
+        default_value = "false",
+        requires = "state_file",
+        conflicts_with_all = ["once", "output", "diff", "tree", "group", "annotate_json", "watch_dir"],
+        help = "Match stdin against the model of `--state-file` and print the unmatched count.",
+        long_help = "The model is frozen: lines are matched but never trained,
+        and the state file is left untouched. Lines matching no template are
+        the candidate anomalies, reported as `unmatched: N of M lines`."
+    )]
+    pub inference: bool,
+
+    #[arg(
+        long = "show-unmatched",
+        default_value = "false",
+        requires = "inference",
+        help = "Print the lines matching no template before the count of `--inference`."
+    )]
+    pub show_unmatched: bool,
+
+    #[arg(
+        long = "evaluate",
+        value_name = "FILE",
+        requires = "state_file",
+        conflicts_with_all = [
+            "once", "output", "inference", "diff", "tree", "group", "annotate_json", "watch_dir",
+        ],
+        help = "Match the lines of FILE against the model of `--state-file` and print the coverage.",
+        long_help = "For tuning `--sim-th` or masks against held-out logs: the model is
+        trained beforehand, e.g. with `--once --state-file`, and left untouched here.
+        Prints the share of lines matching a template, then the matches per template."
+    )]
+    pub evaluate: Option<PathBuf>,
+
+    #[arg(
+        long = "stats",
+        default_value = "false",
+        help = "Print statistics of the model to stderr on exit.",
+        long_help = "Report the number of clusters and of prefix tree nodes
+        that reached `--max-children`, with a warning if any did,
+        since distinct tokens below them are then merged into the wildcard."
+    )]
+    pub stats: bool,
+
+    #[cfg(feature = "web")]
+    #[arg(
+        long = "ws-addr",
+        value_name = "ADDR",
+        default_value = None,
+        help = "Serve cluster snapshots over WebSocket at ADDR, e.g. 127.0.0.1:8080.",
+        long_help = "Each client receives the full list of clusters as a JSON array of
+        `{\"cluster_id\", \"template\", \"size\"}` on connect,
+        then again whenever it changes, at most once per render interval."
+    )]
+    pub ws_addr: Option<std::net::SocketAddr>,
+
+    #[arg(
+        long = "frame-log",
+        value_name = "FILE",
+        default_value = None,
+        help = "Append the clusters shown by each render to FILE as a JSON line.",
+        long_help = "Each line is an array of `{\"cluster_id\", \"template\", \"size\"}`
+        in display order, written whenever the screen is redrawn.
+        Useful for tests and automation reacting to what is displayed."
+    )]
+    pub frame_log: Option<PathBuf>,
+
+    #[arg(
+        long = "audit",
+        value_name = "FILE",
+        default_value = None,
+        conflicts_with = "diff",
+        help = "Append each change of a template to FILE as a JSON line.",
+        long_help = "Each record is `{\"cluster_id\", \"old_template\", \"new_template\", \"line\"}`,
+        where `line` is the (normalized and masked) line that generalized the template.
+        Useful to understand why clusters were merged."
+    )]
+    pub audit: Option<PathBuf>,
+
+    #[arg(
+        long = "record",
+        value_name = "FILE",
+        default_value = None,
+        conflicts_with_all = ["diff", "tree", "group", "annotate_json"],
+        help = "Write each input line to FILE after normalization, to replay it with `--replay`.",
+        long_help = "FILE is overwritten. Lines are recorded before `--mask` and `--multiline`,
+        so that replaying them with the same flags reproduces the clusters,
+        e.g. to attach a problematic stream to a bug report."
+    )]
+    pub record: Option<PathBuf>,
+
     #[arg(long = "train-interval", default_value = "10")]
     pub train_interval_millis: u64,
 
+    #[arg(
+        long = "train-batch-size",
+        visible_alias = "train-batch-max",
+        default_value = "1",
+        help = "Maximum number of lines already read that are trained per train tick.",
+        long_help = "Raising it speeds up bulk input such as large files piped in.
+        A batch yields to the runtime every 256 lines so that rendering and ctrl+c stay responsive."
+    )]
+    pub train_batch_size: NonZeroUsize,
+
     #[arg(
         long = "cluster-size-th",
         default_value = "0",
@@ -52,156 +672,2660 @@ pub struct Args {
     // Drain related params
     #[arg(
         long = "max-clusters",
-        default_value = None,
+        default_value = DEFAULT_MAX_CLUSTERS,
+        value_parser = parse_max_clusters,
+        help = "Maximum number of clusters kept; `0` or `none` for unbounded.",
+        long_help = "The least recently used cluster is evicted when a new one exceeds the limit.
+        Bounded by default so that a long-running stream cannot grow without limit."
+    )]
+    // Spelled out so that clap parses the value as a whole instead of an optional flag.
+    pub max_clusters: std::option::Option<usize>,
+    #[arg(
+        long = "max-node-depth",
+        default_value_t = DEFAULT_MAX_NODE_DEPTH,
+        help = "Depth of the prefix tree; the first MAX_NODE_DEPTH - 1 tokens are used as keys.",
+        long_help = "`0` and `1` group logs by their number of tokens only,
+        `2` also by their first token, and so on.
+        This corresponds to `depth - 2` in Drain3."
     )]
-    pub max_clusters: Option<usize>,
-    #[arg(long = "max-node-depth", default_value = "2")]
     pub max_node_depth: usize,
-    #[arg(long = "sim-th", default_value = "0.4")]
+    #[arg(long = "sim-th", default_value_t = DEFAULT_SIM_TH)]
     pub sim_th: f32,
-    #[arg(long = "max-children", default_value = "100")]
+    #[arg(
+        long = "update-th",
+        help = "Similarity required for a match to update the template; lower matches only join the cluster."
+    )]
+    pub update_th: Option<f32>,
+    #[arg(long = "max-children", default_value_t = DEFAULT_MAX_CHILDREN)]
     pub max_children: usize,
-    #[arg(long = "param-str", default_value = "<*>")]
+    #[arg(long = "param-str", default_value = DEFAULT_PARAM_STR)]
     pub param_str: String,
-}
+    #[arg(
+        long = "max-tokens",
+        default_value = None,
+        help = "Truncate log messages to this number of tokens before training."
+    )]
+    pub max_tokens: Option<usize>,
+    #[arg(
+        long = "min-distinct-values",
+        default_value = None,
+        help = "Distinct values required at a position before it becomes a wildcard."
+    )]
+    pub min_distinct_values: Option<usize>,
+    #[arg(
+        long = "typed-params",
+        default_value = "false",
+        help = "Use <NUM> instead of --param-str where only numbers vary."
+    )]
+    pub typed_params: bool,
+    #[arg(
+        long = "keep-tabs",
+        default_value = "false",
+        help = "Treat tabs as the column separator instead of replacing them with spaces.",
+        long_help = "Lines containing tabs are split into tokens by tabs only,
+        so that columns of TSV-style logs containing spaces are kept as single tokens."
+    )]
+    pub keep_tabs: bool,
+    #[arg(
+        long = "max-total-tokens",
+        default_value = None,
+        help = "Evict least recently used clusters when the templates exceed this number of tokens in total."
+    )]
+    pub max_total_tokens: Option<usize>,
+    #[arg(
+        long = "punctuation",
+        default_value = "keep",
+        help = "How to treat punctuation ()[]{},;: in tokens: keep, strip or split.",
+        long_help = "`keep` leaves tokens as they are.
+        `strip` removes punctuation surrounding each token, e.g. `(id=5),` becomes `id=5`.
+        `split` makes each punctuation a token of its own, e.g. `error(42)` becomes `error ( 42 )`."
+    )]
+    pub punctuation: PunctuationPolicy,
+    #[arg(
+        long = "case-insensitive",
+        value_name = "REGEX",
+        value_parser = Regex::new,
+        help = "Compare tokens matching REGEX case-insensitively.",
+        long_help = "Templates keep the casing first seen, e.g. with
+        `--case-insensitive '^(?i)(0x[0-9a-f]+|error|warn)$'`
+        `0xAB` and `0xab` match, while other tokens stay case-sensitive."
+    )]
+    pub case_insensitive: Option<Regex>,
+    #[arg(
+        long = "delimiter-regex",
+        value_name = "REGEX",
+        value_parser = parse_delimiter_regex,
+        help = "Split lines into tokens by the matches of REGEX instead of whitespace.",
+        long_help = "For formats that whitespace does not split well,
+        e.g. `--delimiter-regex '[\\s,;]+'`. Empty tokens are dropped,
+        and a REGEX matching the empty string is rejected."
+    )]
+    pub delimiter_regex: Option<Regex>,
+    #[arg(
+        long = "id-strategy",
+        default_value = "counter",
+        help = "How cluster ids are assigned: counter or template-hash.",
+        long_help = "`counter` numbers clusters in order of creation.
+        `template-hash` derives ids from the first log of each cluster,
+        so that they are the same across runs; a collision takes the next free id."
+    )]
+    pub id_strategy: IdStrategy,
+    #[arg(
+        long = "tie-break",
+        default_value = "more-params",
+        help = "Cluster picked among equally similar ones: more-params, fewer-params, larger-cluster or smaller-id."
+    )]
+    pub tie_break: TieBreak,
+    #[arg(
+        long = "wildcard-runs",
+        default_value = "keep",
+        help = "How consecutive wildcards are shown: keep, collapse or count.",
+        long_help = "`keep` shows every wildcard, e.g. `a <*> <*> b`.
+        `collapse` shows a run of wildcards as one, e.g. `a <*> b`,
+        and `count` adds the length of the run, e.g. `a <*>{2} b`.
+        Only the display changes; lines are still matched token by token."
+    )]
+    pub wildcard_runs: WildcardRuns,
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-
-    enable_raw_mode()?;
-    // Avoid the rendering messy by disabling mouse scroll and fixing the row.
-    crossterm::execute!(
-        io::stdout(),
-        crossterm::event::EnableMouseCapture,
-        crossterm::cursor::Hide
-    )?;
+    #[arg(
+        long = "facility",
+        default_value = "false",
+        help = "Keep a leading `name:` token such as `sshd:` literal and never mix facilities.",
+        long_help = "Syslog-style lines like `sshd: ...` and `kernel: ...` are clustered
+        separately by their leading facility, regardless of `--sim-th`."
+    )]
+    pub facility: bool,
 
-    let canceler = CancellationToken::new();
+    #[arg(
+        long = "mixed-lengths",
+        default_value = "false",
+        help = "Let lines with different numbers of tokens share a cluster.",
+        long_help = "By default only lines with the same number of tokens are compared.
+        With this flag, matching starts at the first token, similarity is taken
+        over the longer line, and templates grow with wildcards to the longest line.
+        Suits logs whose length varies while their structure does not."
+    )]
+    pub mixed_lengths: bool,
 
-    let canceled = canceler.clone();
-    let draining: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
-        let render_interval = time::interval(Duration::from_millis(args.render_interval_millis));
-        let train_interval = time::interval(Duration::from_millis(args.train_interval_millis));
-        futures::pin_mut!(render_interval);
-        futures::pin_mut!(train_interval);
+    #[arg(
+        long = "trailing-penalty",
+        value_name = "PENALTY",
+        requires = "mixed_lengths",
+        help = "Weigh the extra trailing tokens of a longer line by PENALTY (0.0 to 1.0) each.",
+        long_help = "With `--mixed-lengths`, the tokens a line has beyond the shorter one
+        are matched like wildcards and count PENALTY each towards the similarity
+        instead of one dissimilar position, so that `connected to server now` can join
+        `connected to server`. 0.0 ignores them; 1.0 behaves like `--mixed-lengths` alone."
+    )]
+    pub trailing_penalty: Option<f32>,
 
-        let mut drain = Drain::new(
-            args.max_clusters,
-            args.max_node_depth,
-            args.sim_th,
-            args.max_children,
-            args.param_str,
-        )?;
+    #[arg(
+        long = "dedup-tokens",
+        default_value = "false",
+        help = "Collapse runs of identical consecutive tokens before clustering.",
+        long_help = "Lines such as `error error connection reset` are tokenized
+        as `error connection reset`, so that stuttering sources do not skew
+        the positional similarity of their lines."
+    )]
+    pub dedup_tokens: bool,
 
-        let mut reader = BufReader::new(tokio::io::stdin()).lines();
-        let mut prev = drain
-            .clusters()
-            .iter()
-            .map(|cluster| cluster.cluster_id)
-            .collect::<Vec<usize>>();
+    #[arg(
+        long = "token-class",
+        value_name = "CLASS[=WILDCARD]",
+        value_parser = parse_token_class,
+        help = "Give varying tokens of CLASS (decimal, hex or id) a wildcard of their own.",
+        long_help = "Can be repeated. `decimal` numbers become `<NUM>`, `0x`-prefixed `hex` numbers
+        `<HEX>`, and `id` tokens mixing letters and digits such as `req-ab12` `<ID>`,
+        unless WILDCARD is given. Such tokens are kept apart from other variable tokens,
+        and positions mixing classes fall back to `--param-str`."
+    )]
+    pub token_classes: Vec<(TokenClass, String)>,
+}
 
-        while !canceled.is_cancelled() {
-            tokio::select! {
-                _ = train_interval.tick() => {
-                    // Set a timeout to ensure non-blocking behavior,
-                    // especially responsive to user inputs like ctrl+c.
-                    // Continuously retry until cancellation to prevent loss of logs.
-                    let ret = timeout(
-                        Duration::from_millis(args.retrieval_timeout_millis),
-                        reader.next_line(),
-                    )
-                    .await;
-                    if ret.is_err() {
-                        continue;
-                    }
+/// Pick the color of the template based on the log level tokens it contains.
+/// Tokens are compared case-insensitively, ignoring surrounding punctuation
+/// such as `[ERROR]` or `WARN:`.
+fn level_color(
+    template: &str,
+    error_levels: &[String],
+    warn_levels: &[String],
+    theme: &Theme,
+) -> Option<Color> {
+    let contains = |levels: &[String]| {
+        template.split_whitespace().any(|token| {
+            let token = token.trim_matches(|c: char| !c.is_alphanumeric());
+            levels.iter().any(|level| level.eq_ignore_ascii_case(token))
+        })
+    };
 
-                    let ret = ret?;
+    if contains(error_levels) {
+        theme.error
+    } else if contains(warn_levels) {
+        theme.warn
+    } else {
+        None
+    }
+}
+
+/// Style the template token by token,
+/// using `wildcard` for wildcard tokens and `literal` for the others.
+fn styled_template(
+    template: &str,
+    wildcards: &[&str],
+    literal: Option<Color>,
+    wildcard: Option<Color>,
+) -> StyledGraphemes {
+    let style = |foreground_color| ContentStyle {
+        foreground_color,
+        ..Default::default()
+    };
+    let mut styled = Vec::new();
+    for (i, token) in template.split(' ').enumerate() {
+        if i > 0 {
+            styled.push(StyledGraphemes::from_str(" ", style(literal)));
+        }
+        // A run counted by `--wildcard-runs count` such as `<*>{2}` is a wildcard too.
+        let run = token
+            .strip_suffix('}')
+            .and_then(|token| token.rsplit_once('{'))
+            .map_or(token, |(wildcard, _)| wildcard);
+        let color = if wildcards.contains(&token) || wildcards.contains(&run) {
+            wildcard
+        } else {
+            literal
+        };
+        styled.push(StyledGraphemes::from_str(token, style(color)));
+    }
+    styled.into_iter().collect()
+}
+
+/// Sink of `--audit`, recording each change of template with the line causing it.
+#[derive(Default)]
+struct Audit(Option<fs::File>);
+
+impl Audit {
+    fn open(args: &Args) -> io::Result<Self> {
+        let file = args
+            .audit
+            .as_ref()
+            .map(|path| fs::OpenOptions::new().create(true).append(true).open(path))
+            .transpose()?;
+        Ok(Self(file))
+    }
+
+    /// Train on the entry, recording the change of its cluster's template if any.
+    fn train(&mut self, drain: &mut Drain, entry: &str) -> io::Result<TrainInfo> {
+        let info = drain.train_with_info(entry);
+        if let (Some(file), Some(previous)) = (self.0.as_mut(), &info.previous_template) {
+            let record = serde_json::json!({
+                "cluster_id": info.cluster_id,
+                "old_template": previous,
+                "new_template": info.template,
+                "line": entry,
+            });
+            writeln!(file, "{}", record)?;
+        }
+        Ok(info)
+    }
+}
+
+/// Sink of `--record`, keeping each normalized line for `--replay`.
+#[derive(Default)]
+struct Record(Option<fs::File>);
+
+impl Record {
+    fn create(args: &Args) -> io::Result<Self> {
+        Ok(Self(
+            args.record.as_ref().map(fs::File::create).transpose()?,
+        ))
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if let Some(file) = self.0.as_mut() {
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Train on the entry in the TUI, recording when its cluster matched.
+fn train(
+    drain: &mut Drain,
+    audit: &mut Audit,
+    last_seen: &mut HashMap<usize, time::Instant>,
+    entry: String,
+) -> io::Result<()> {
+    let info = audit.train(drain, &entry)?;
+    last_seen.insert(info.cluster_id, time::Instant::now());
+    drain.record_arrival(info.cluster_id, unix_secs());
+    Ok(())
+}
+
+/// Current time in seconds since the Unix epoch, for `LogCluster::rate`.
+fn unix_secs() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64())
+}
+
+/// Order `clusters`, given from the most recently used, according to `sort`.
+/// Ties keep their recency order.
+fn sort_clusters(clusters: &mut [&LogCluster], sort: Sort, now: f64) {
+    match sort {
+        Sort::Recent => {}
+        Sort::Size => clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.size)),
+        Sort::Rate => clusters.sort_by(|a, b| b.rate_at(now).total_cmp(&a.rate_at(now))),
+    }
+}
+
+/// Number of entries `train_batch` trains between yields to the runtime.
+const TRAIN_YIELD_EVERY: usize = 256;
+
+/// Train on `entries`, yielding to the runtime every `TRAIN_YIELD_EVERY` entries
+/// so that a large batch does not hold up rendering on the same worker.
+/// The remaining entries are dropped once `canceled`, e.g. on ctrl+c.
+async fn train_batch(
+    drain: &mut Drain,
+    audit: &mut Audit,
+    last_seen: &mut HashMap<usize, time::Instant>,
+    entries: Vec<String>,
+    canceled: &CancellationToken,
+) -> io::Result<()> {
+    for (i, entry) in entries.into_iter().enumerate() {
+        if i > 0 && i % TRAIN_YIELD_EVERY == 0 {
+            tokio::task::yield_now().await;
+            if canceled.is_cancelled() {
+                break;
+            }
+        }
+        train(drain, audit, last_seen, entry)?;
+    }
+    Ok(())
+}
+
+/// Decode and prepare a raw line, joining it to `pending` with `--multiline`.
+/// Return the entry complete to train, if any.
+fn ingest(
+    mut bytes: Vec<u8>,
+    pending: &mut Option<String>,
+    skipped: &mut usize,
+    truncated: &mut usize,
+    record: &mut Record,
+    args: &Args,
+) -> io::Result<Option<String>> {
+    if truncate_bytes(&mut bytes, args.max_line_bytes) {
+        *truncated += 1;
+    }
+    let line = match args.invalid_utf8 {
+        InvalidUtf8::Lossy => String::from_utf8_lossy(&bytes).into_owned(),
+        InvalidUtf8::Skip => match String::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(_) => {
+                *skipped += 1;
+                return Ok(None);
+            }
+        },
+    };
+    let line = normalize(&line, args);
+    record.write(&line)?;
+    let escaped = mask(line, args).0;
+    Ok(match &args.multiline {
+        Some(start) => match pending.as_mut() {
+            Some(entry) if !start.is_match(&escaped) => {
+                entry.push(' ');
+                entry.push_str(&escaped);
+                None
+            }
+            _ => pending.replace(escaped),
+        },
+        None => Some(escaped),
+    })
+}
+
+/// Report the lines dropped by `ingest` to stderr.
+fn report_dropped(skipped: usize, truncated: usize) {
+    if skipped > 0 {
+        eprintln!("Skipped {} line(s) with invalid UTF-8", skipped);
+    }
+    if truncated > 0 {
+        eprintln!(
+            "Truncated {} line(s) longer than --max-line-bytes",
+            truncated
+        );
+    }
+}
+
+/// State of `ingest` for the modes without the TUI, which read until EOF.
+struct Ingester<'a> {
+    pending: Option<String>,
+    skipped: usize,
+    truncated: usize,
+    record: Record,
+    args: &'a Args,
+}
+
+impl<'a> Ingester<'a> {
+    fn new(record: Record, args: &'a Args) -> Self {
+        Self {
+            pending: None,
+            skipped: 0,
+            truncated: 0,
+            record,
+            args,
+        }
+    }
+
+    /// Return the entry complete to train, if any, see `ingest`.
+    fn push(&mut self, bytes: Vec<u8>) -> io::Result<Option<String>> {
+        ingest(
+            bytes,
+            &mut self.pending,
+            &mut self.skipped,
+            &mut self.truncated,
+            &mut self.record,
+            self.args,
+        )
+    }
+
+    /// Return the last entry at EOF, reporting the dropped lines.
+    fn finish(&mut self) -> Option<String> {
+        report_dropped(self.skipped, self.truncated);
+        self.pending.take()
+    }
+}
+
+/// Check whether the cluster has not matched any line for `inactive_after`.
+fn is_inactive(
+    cluster: &LogCluster,
+    last_seen: &HashMap<usize, time::Instant>,
+    inactive_after: Option<Duration>,
+) -> bool {
+    match (inactive_after, last_seen.get(&cluster.cluster_id)) {
+        (Some(inactive_after), Some(at)) => at.elapsed() >= inactive_after,
+        _ => false,
+    }
+}
+
+/// Draw `lines` to `out` over the previous frame `prev_lines`.
+/// Only the rows that differ are rewritten and the rows no longer used are blanked out,
+/// unless `clear` asks to clear the screen and draw every row.
+fn render<W: Write>(
+    out: &mut W,
+    lines: &[StyledGraphemes],
+    prev_lines: &[StyledGraphemes],
+    clear: bool,
+) -> io::Result<()> {
+    let prev_lines = if clear {
+        crossterm::queue!(
+            out,
+            crossterm::terminal::Clear(crossterm::terminal::ClearType::All),
+            crossterm::terminal::Clear(crossterm::terminal::ClearType::Purge),
+        )?;
+        &[]
+    } else {
+        prev_lines
+    };
+
+    for (row, line) in lines.iter().enumerate() {
+        if prev_lines.get(row) != Some(line) {
+            crossterm::queue!(
+                out,
+                cursor::MoveTo(0, row as u16),
+                style::Print(line.styled_display()),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
+            )?;
+        }
+    }
+    for row in lines.len()..prev_lines.len() {
+        crossterm::queue!(
+            out,
+            cursor::MoveTo(0, row as u16),
+            crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine),
+        )?;
+    }
+    out.flush()
+}
+
+/// JSON of a cluster for `--frame-log` and `--ws-addr`,
+/// with its label only when it has one.
+fn cluster_json(drain: &Drain, cluster: &LogCluster) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "cluster_id": cluster.cluster_id,
+        "template": drain.template(cluster),
+        "size": cluster.size,
+    });
+    if let Some(label) = cluster.label() {
+        json["label"] = label.into();
+    }
+    json
+}
+
+/// Lines of a `--plain` frame after its header row: the clusters passing
+/// `--cluster-size-th` and `visible` in display order, as `[SIZE] TEMPLATE`.
+fn plain_frame(drain: &Drain, args: &Args, visible: impl Fn(&LogCluster) -> bool) -> Vec<String> {
+    let mut clusters = drain.clusters();
+    sort_clusters(&mut clusters, args.sort, unix_secs());
+    clusters
+        .into_iter()
+        .filter(|cluster| cluster.size > args.cluster_size_th && visible(cluster))
+        .map(|cluster| match cluster.label() {
+            Some(label) => format!("[{}] [{}] {}", cluster.size, label, drain.template(cluster)),
+            None => format!("[{}] {}", cluster.size, drain.template(cluster)),
+        })
+        .collect()
+}
+
+/// Print the `--plain` frame if it differs from `prev`, which it then replaces.
+fn print_plain(drain: &Drain, frame: Vec<String>, prev: &mut Vec<String>) -> io::Result<()> {
+    if frame == *prev {
+        return Ok(());
+    }
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "--- {}", compression_header(drain))?;
+    for line in &frame {
+        writeln!(stdout, "{}", line)?;
+    }
+    stdout.flush()?;
+    *prev = frame;
+    Ok(())
+}
+
+/// Select the clusters to display within `height` rows, in display order,
+/// along with the rows each of them takes as laid out by `layout`.
+/// Clusters not larger than `cluster_size_th` or rejected by `visible` are skipped,
+/// and the selection stops at the first cluster that does not fit.
+fn select_clusters<R>(
+    clusters: Vec<&LogCluster>,
+    cluster_size_th: usize,
+    height: usize,
+    visible: impl Fn(&LogCluster) -> bool,
+    mut layout: impl FnMut(&LogCluster) -> Vec<R>,
+) -> Vec<(&LogCluster, Vec<R>)> {
+    let mut selected = Vec::new();
+    let mut used = 0;
+    for cluster in clusters
+        .into_iter()
+        .filter(|cluster| cluster.size > cluster_size_th)
+        .filter(|cluster| visible(cluster))
+        .take(height)
+    {
+        let rows = layout(cluster);
+        if used + rows.len() > height {
+            break;
+        }
+        used += rows.len();
+        selected.push((cluster, rows));
+    }
+    selected
+}
+
+/// Number of render ticks shown by `--sparkline`.
+const SPARKLINE_TICKS: usize = 60;
+
+/// Blocks of `--sparkline`, from the lowest rate to the highest.
+const SPARKLINE_BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Rates of lines per second over the last `SPARKLINE_TICKS` render ticks,
+/// from the growth of `total_lines` between ticks.
+struct Sparkline {
+    rates: VecDeque<f64>,
+    total_lines: usize,
+    at: time::Instant,
+}
+
+impl Sparkline {
+    fn new(at: time::Instant) -> Self {
+        Self {
+            rates: VecDeque::with_capacity(SPARKLINE_TICKS),
+            total_lines: 0,
+            at,
+        }
+    }
+
+    /// Record the rate since the previous tick, dropping the oldest one if full.
+    fn tick(&mut self, total_lines: usize, at: time::Instant) {
+        let secs = at.duration_since(self.at).as_secs_f64();
+        // `total_lines` goes back to 0 when the sizes are reset.
+        let lines = total_lines.saturating_sub(self.total_lines);
+        if self.rates.len() == SPARKLINE_TICKS {
+            self.rates.pop_front();
+        }
+        self.rates
+            .push_back(if secs > 0.0 { lines as f64 / secs } else { 0.0 });
+        self.total_lines = total_lines;
+        self.at = at;
+    }
+
+    /// Header row, with the blocks scaled to the highest rate.
+    fn header(&self) -> String {
+        let max = self.rates.iter().copied().fold(0.0, f64::max);
+        let blocks: String = self
+            .rates
+            .iter()
+            .map(|rate| {
+                let level = if max > 0.0 {
+                    (rate / max * (SPARKLINE_BLOCKS.len() - 1) as f64).round() as usize
+                } else {
+                    0
+                };
+                SPARKLINE_BLOCKS[level]
+            })
+            .collect();
+        let current = self.rates.back().copied().unwrap_or_default();
+        format!("{} {:.0} lines/s", blocks, current)
+    }
+}
+
+/// Header row of `--compression-ratio`.
+fn compression_header(drain: &Drain) -> String {
+    let ratio = drain
+        .compression_ratio()
+        .map_or(String::from("-"), |ratio| format!("{:.2}", ratio));
+    let max_clusters = drain
+        .config()
+        .max_clusters
+        .map_or(String::new(), |max_clusters| format!("/{}", max_clusters));
+    format!(
+        "lines: {}  clusters: {}{}  ratio: {}",
+        drain.total_lines(),
+        drain.len(),
+        max_clusters,
+        ratio
+    )
+}
+
+/// Halve `max_clusters` with `-`, bounding it by the number of clusters
+/// if it is unlimited, or double it with `+`.
+fn resize_clusters(drain: &mut Drain, key: char) {
+    let max_clusters = drain.config().max_clusters;
+    let max_clusters = match (key, max_clusters) {
+        ('-', Some(max_clusters)) => max_clusters / 2,
+        ('-', None) => drain.len() / 2,
+        ('+', Some(max_clusters)) => max_clusters.saturating_mul(2),
+        _ => return,
+    };
+    // Halving stops at a single cluster, so the limit is never the rejected zero.
+    let _ = drain.set_max_clusters(Some(max_clusters.max(1)));
+}
+
+/// Check whether the clusters crossed a reporting boundary
+/// since the `reported` snapshot of cluster id to size was taken.
+fn crossed_boundary(reported: &HashMap<usize, usize>, drain: &Drain) -> bool {
+    let clusters = drain.clusters();
+    clusters.len() != reported.len()
+        || clusters
+            .iter()
+            .any(|cluster| match reported.get(&cluster.cluster_id) {
+                Some(size) => cluster.size >= size * 2,
+                None => true,
+            })
+}
+
+fn new_drain(args: &Args) -> anyhow::Result<Drain> {
+    if let Some(path) = args.state_file.as_ref().filter(|path| path.exists()) {
+        let drain = Drain::load_from_reader(io::BufReader::new(fs::File::open(path)?))?;
+        return with_unserialized(drain, args);
+    }
+    let mut builder = Drain::builder()
+        .max_clusters(args.max_clusters)
+        .max_node_depth(args.max_node_depth)
+        .sim_th(args.sim_th)
+        .update_th(args.update_th)
+        .max_children(args.max_children)
+        .param_str(args.param_str.clone())
+        .max_tokens(args.max_tokens)
+        .min_distinct_values(args.min_distinct_values)
+        .typed_params(args.typed_params)
+        .tab_delimited(args.keep_tabs)
+        .punctuation(args.punctuation)
+        .max_total_tokens(args.max_total_tokens)
+        .id_strategy(args.id_strategy)
+        .tie_break(args.tie_break)
+        .wildcard_runs(args.wildcard_runs)
+        .facility(args.facility)
+        .window_secs(args.window_secs)
+        .mixed_lengths(args.mixed_lengths)
+        .trailing_penalty(args.trailing_penalty)
+        .dedup_tokens(args.dedup_tokens);
+    for (class, wildcard) in &args.token_classes {
+        builder = builder.token_class(*class, wildcard.clone());
+    }
+    let drain = builder.build()?;
+    with_unserialized(drain, args)
+}
+
+/// Apply the options that are not saved with `--state-file`.
+fn with_unserialized(drain: Drain, args: &Args) -> anyhow::Result<Drain> {
+    let drain = match args.case_insensitive.clone() {
+        Some(regex) => drain.with_case_insensitive(move |token| regex.is_match(token)),
+        None => drain,
+    };
+    Ok(match args.delimiter_regex.clone() {
+        Some(regex) => drain.with_delimiter_regex(regex)?,
+        None => drain,
+    })
+}
+
+fn parse_token_class(s: &str) -> Result<(TokenClass, String), String> {
+    let (class, wildcard) = match s.split_once('=') {
+        Some((class, wildcard)) => (class.parse::<TokenClass>()?, wildcard.to_string()),
+        None => {
+            let class = s.parse::<TokenClass>()?;
+            (class, class.default_wildcard().to_string())
+        }
+    };
+    if wildcard.is_empty() || wildcard.contains(char::is_whitespace) {
+        return Err(format!(
+            "wildcard must be non-empty and contain no whitespace, got `{}`",
+            wildcard
+        ));
+    }
+    Ok((class, wildcard))
+}
+
+/// Parse `--delimiter-regex`, rejecting a regex matching the empty string
+/// since it would split between every character.
+fn parse_delimiter_regex(s: &str) -> Result<Regex, String> {
+    let regex = Regex::new(s).map_err(|e| e.to_string())?;
+    if regex.is_match("") {
+        return Err(String::from("must not match the empty string"));
+    }
+    Ok(regex)
+}
+
+/// Save the model to `path` for `--state-file`.
+fn save_state(drain: &Drain, path: &Path) -> anyhow::Result<()> {
+    let mut writer = io::BufWriter::new(fs::File::create(path)?);
+    drain.save_to_writer(&mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Interval between the progress lines of `--once`.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Progress of `--once` printed to stderr every `PROGRESS_INTERVAL`,
+/// unless `--quiet` is given or stderr is not a terminal.
+struct Progress {
+    enabled: bool,
+    /// Size of the input in bytes, if known.
+    size: Option<u64>,
+    /// Bytes read so far, approximated by the decoded lines.
+    read: u64,
+    lines: usize,
+    printed_at: std::time::Instant,
+}
+
+impl Progress {
+    fn new(args: &Args) -> Self {
+        Self {
+            enabled: !args.quiet && io::stderr().is_terminal(),
+            size: input_size(args),
+            read: 0,
+            lines: 0,
+            printed_at: std::time::Instant::now(),
+        }
+    }
+
+    /// Count a line of `len` bytes read, printing the progress if it is time to.
+    fn update(&mut self, len: usize, drain: &Drain) {
+        // Count the newline too.
+        self.read += len as u64 + 1;
+        self.lines += 1;
+        if self.enabled && self.printed_at.elapsed() >= PROGRESS_INTERVAL {
+            eprintln!("{}", self.message(drain.len()));
+            self.printed_at = std::time::Instant::now();
+        }
+    }
+
+    fn message(&self, clusters: usize) -> String {
+        let message = format!("processed {} lines, {} clusters", self.lines, clusters);
+        match self.size.filter(|size| *size > 0) {
+            Some(size) => format!("{} ({}%)", message, (self.read * 100 / size).min(100)),
+            None => message,
+        }
+    }
+}
+
+/// Size in bytes of the file `input_lines` reads, if it is a regular file.
+fn input_size(args: &Args) -> Option<u64> {
+    let metadata = match &args.replay {
+        Some(path) => fs::metadata(path).ok()?,
+        #[cfg(unix)]
+        None => {
+            use std::os::fd::AsFd;
+            fs::File::from(io::stdin().as_fd().try_clone_to_owned().ok()?)
+                .metadata()
+                .ok()?
+        }
+        #[cfg(not(unix))]
+        None => return None,
+    };
+    metadata.is_file().then_some(metadata.len())
+}
+
+/// Match stdin against the model of `--state-file` until EOF without training it,
+/// printing the unmatched lines with `--show-unmatched`, then their count.
+fn inference(args: &Args) -> anyhow::Result<()> {
+    if let Some(path) = args.state_file.as_ref().filter(|path| !path.exists()) {
+        anyhow::bail!("{}: no model to match against", path.display());
+    }
+    let drain = new_drain(args)?;
+    let mut stdout = io::stdout().lock();
+    let mut total = 0;
+    for line in input_lines(args)? {
+        let line = decode_lossy(line?, args.max_line_bytes);
+        total += 1;
+        if drain.match_log(prepare(&line, args).0).is_none() && args.show_unmatched {
+            writeln!(stdout, "{}", line)?;
+        }
+    }
+    writeln!(
+        stdout,
+        "unmatched: {} of {} lines",
+        drain.unmatched_count(),
+        total
+    )?;
+    Ok(())
+}
+
+/// Report of `--evaluate`: the share of matched lines, then `[MATCHES] TEMPLATE`
+/// for the templates matched at least once, the most matched first.
+fn coverage_report(drain: &Drain, report: &CoverageReport) -> Vec<String> {
+    let templates: HashMap<usize, String> = drain
+        .clusters()
+        .iter()
+        .map(|cluster| (cluster.cluster_id, drain.template(cluster)))
+        .collect();
+    let mut matches: Vec<(&usize, &usize)> = report.matches.iter().collect();
+    matches.sort_by(|(id1, count1), (id2, count2)| count2.cmp(count1).then(id1.cmp(id2)));
+
+    let ratio = report
+        .ratio()
+        .map_or(String::from("-"), |ratio| format!("{:.2}%", ratio * 100.0));
+    let mut lines = vec![
+        format!(
+            "matched: {} of {} lines ({})",
+            report.matched,
+            report.matched + report.unmatched,
+            ratio
+        ),
+        format!(
+            "templates matched: {} of {}",
+            report.matches.len(),
+            drain.len()
+        ),
+    ];
+    lines.extend(matches.into_iter().map(|(cluster_id, count)| {
+        let template = templates.get(cluster_id).map_or("", String::as_str);
+        format!("[{}] {}", count, template)
+    }));
+    lines
+}
+
+fn evaluate(path: &Path, args: &Args) -> anyhow::Result<()> {
+    if let Some(state) = args.state_file.as_ref().filter(|state| !state.exists()) {
+        anyhow::bail!("{}: no model to evaluate", state.display());
+    }
+    let drain = new_drain(args)?;
+    let lines = read_lines(io::BufReader::new(fs::File::open(path)?))
+        .map(|line| line.map(|line| prepare(&decode_lossy(line, args.max_line_bytes), args).0))
+        .collect::<io::Result<Vec<String>>>()?;
+    let report = drain.coverage(lines);
+
+    let mut stdout = io::stdout().lock();
+    for line in coverage_report(&drain, &report) {
+        writeln!(stdout, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Train on stdin until EOF and print the templates.
+/// Return whether a cluster was created while `--fail-on-new` is given,
+/// after printing the templates of those clusters to stderr.
+fn once(args: &Args) -> anyhow::Result<bool> {
+    if let Some(path) = args
+        .state_file
+        .as_ref()
+        .filter(|path| args.fail_on_new && !path.exists())
+    {
+        anyhow::bail!("{}: no baseline to compare against", path.display());
+    }
+    let mut drain = new_drain(args)?;
+    let mut audit = Audit::open(args)?;
+    let mut ingester = Ingester::new(Record::create(args)?, args);
+    let mut progress = Progress::new(args);
+    let mut created = Vec::new();
+    let mut train = |drain: &mut Drain, entry: String| -> io::Result<()> {
+        let info = audit.train(drain, &entry)?;
+        if info.outcome == TrainOutcome::Created {
+            created.push((info.cluster_id, info.template));
+        }
+        Ok(())
+    };
+    for line in input_lines(args)? {
+        let line = line?;
+        progress.update(line.len(), &drain);
+        if let Some(entry) = ingester.push(line)? {
+            train(&mut drain, entry)?;
+        }
+    }
+    if let Some(entry) = ingester.finish() {
+        train(&mut drain, entry)?;
+    }
+
+    let mut stdout = io::stdout().lock();
+    for cluster in drain.clusters_by_id() {
+        // Labels come from `--state-file`, as given in the TUI.
+        match (args.output.unwrap_or(Output::Text), cluster.label()) {
+            (Output::Text, None) => {
+                writeln!(stdout, "[{}] {}", cluster.size, drain.template(cluster))?
+            }
+            (Output::Text, Some(label)) => writeln!(
+                stdout,
+                "[{}] [{}] {}",
+                cluster.size,
+                label,
+                drain.template(cluster)
+            )?,
+            (Output::Logfmt, label) => {
+                write!(
+                    stdout,
+                    "id={} size={} template={}",
+                    cluster.cluster_id,
+                    cluster.size,
+                    logfmt_value(&drain.template(cluster))
+                )?;
+                if let Some(label) = label {
+                    write!(stdout, " label={}", logfmt_value(label))?;
+                }
+                writeln!(stdout)?;
+            }
+        }
+    }
+    if args.stats {
+        print_stats(&drain, args.max_children);
+    }
+
+    if args.fail_on_new {
+        // Report the current templates, which may have generalized since creation,
+        // and the templates recorded at creation for clusters evicted since.
+        let clusters: HashMap<usize, &LogCluster> = drain
+            .clusters()
+            .into_iter()
+            .map(|cluster| (cluster.cluster_id, cluster))
+            .collect();
+        for (cluster_id, template) in &created {
+            match clusters.get(cluster_id) {
+                Some(cluster) => eprintln!("new: [{}] {}", cluster.size, drain.template(cluster)),
+                None => eprintln!("new: [evicted] {}", template),
+            }
+        }
+        return Ok(!created.is_empty());
+    }
+    if let Some(path) = &args.state_file {
+        save_state(&drain, path)?;
+    }
+    Ok(false)
+}
+
+/// Quote `value` for logfmt if it is empty or contains spaces, `=`, `"` or control characters,
+/// escaping backslashes, quotes and control characters inside.
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value.contains(|c: char| c == ' ' || c == '=' || c == '"' || c.is_control())
+    {
+        return value.to_string();
+    }
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Cut `bytes` to `max_line_bytes`, backing off to a UTF-8 character boundary.
+/// Return whether they were cut.
+fn truncate_bytes(bytes: &mut Vec<u8>, max_line_bytes: Option<usize>) -> bool {
+    let Some(max_line_bytes) = max_line_bytes.filter(|max| bytes.len() > *max) else {
+        return false;
+    };
+    let mut end = max_line_bytes;
+    // Continuation bytes of a multi-byte character start with `10`.
+    while end > 0 && bytes[end] & 0b1100_0000 == 0b1000_0000 {
+        end -= 1;
+    }
+    bytes.truncate(end);
+    true
+}
+
+/// Read non-empty raw lines until EOF, without their trailing `\r`.
+fn read_lines<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<Vec<u8>>> {
+    reader
+        .split(b'\n')
+        .map(|bytes| {
+            bytes.map(|mut bytes| {
+                if bytes.last() == Some(&b'\r') {
+                    bytes.pop();
+                }
+                bytes
+            })
+        })
+        .filter(|bytes| !matches!(bytes, Ok(bytes) if bytes.is_empty()))
+}
+
+/// Decode a raw line lossily after cutting it to `max_line_bytes`.
+fn decode_lossy(mut bytes: Vec<u8>, max_line_bytes: Option<usize>) -> String {
+    truncate_bytes(&mut bytes, max_line_bytes);
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn idle_timeout(args: &Args) -> Option<Duration> {
+    args.idle_timeout_secs.map(Duration::from_secs)
+}
+
+/// Read non-empty raw lines from stdin until EOF,
+/// or until no line arrives within `idle_timeout`.
+fn stdin_lines(idle_timeout: Option<Duration>) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>>> {
+    match idle_timeout {
+        Some(idle_timeout) => {
+            // Read on another thread, since a blocking read cannot time out.
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                for line in read_lines(io::stdin().lock()) {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            });
+            Box::new(std::iter::from_fn(move || {
+                rx.recv_timeout(idle_timeout).ok()
+            }))
+        }
+        None => Box::new(read_lines(io::stdin().lock())),
+    }
+}
+
+/// Read non-empty raw lines from `--replay` if given, from stdin otherwise.
+fn input_lines(args: &Args) -> io::Result<Box<dyn Iterator<Item = io::Result<Vec<u8>>>>> {
+    match &args.replay {
+        Some(path) => Ok(Box::new(read_lines(io::BufReader::new(fs::File::open(
+            path,
+        )?)))),
+        None => Ok(stdin_lines(idle_timeout(args))),
+    }
+}
+
+/// Print statistics of the model to stderr.
+fn print_stats(drain: &Drain, max_children: usize) {
+    eprintln!("clusters: {}", drain.clusters().len());
+    eprintln!("saturated nodes: {}", drain.saturated_nodes());
+    if let Some((min, max)) = drain.template_length_range() {
+        eprintln!("template lengths: {}-{} tokens", min, max);
+    }
+    if drain.saturated_nodes() > 0 {
+        eprintln!(
+            "warning: {} node(s) reached --max-children {}; consider raising it",
+            drain.saturated_nodes(),
+            max_children
+        );
+    }
+    eprintln!("cluster sizes:");
+    for (bucket, count) in size_buckets(&drain.size_histogram()) {
+        eprintln!("  {}: {}", bucket, count);
+    }
+}
+
+/// Group a size histogram into the buckets 1, 2-10, 11-100, ...
+/// skipping the empty ones.
+fn size_buckets(histogram: &BTreeMap<usize, usize>) -> Vec<(String, usize)> {
+    let mut buckets: Vec<(String, usize)> = Vec::new();
+    let (mut lower, mut upper) = (1, 1);
+    for (&size, &count) in histogram {
+        while size > upper {
+            lower = upper + 1;
+            upper *= 10;
+        }
+        let bucket = if lower == upper {
+            lower.to_string()
+        } else {
+            format!("{}-{}", lower, upper)
+        };
+        match buckets.last_mut() {
+            Some((last, total)) if *last == bucket => *total += count,
+            _ => buckets.push((bucket, count)),
+        }
+    }
+    buckets
+}
+
+/// Print the issues of the masks file at `path` for `--check-config`,
+/// returning whether it is free of errors.
+fn check_config(path: &Path, args: &Args) -> anyhow::Result<bool> {
+    let issues = mask::check(&fs::read_to_string(path)?, &wildcards(args));
+    for issue in &issues {
+        println!("{}: {}", path.display(), issue);
+    }
+    let errors = issues.iter().filter(|issue| issue.error).count();
+    println!("{} error(s), {} warning(s)", errors, issues.len() - errors);
+    Ok(errors == 0)
+}
+
+/// Wildcards of the templates trained with the arguments: `--param-str`,
+/// `<NUM>` with `--typed-params` and those of `--token-class`.
+fn wildcards(args: &Args) -> Vec<&str> {
+    std::iter::once(args.param_str.as_str())
+        .chain(args.typed_params.then_some(NUM_PARAM_STR))
+        .chain(
+            args.token_classes
+                .iter()
+                .map(|(_, wildcard)| wildcard.as_str()),
+        )
+        .collect()
+}
+
+/// Normalize and mask a line, returning it with the masked values.
+fn prepare(line: &str, args: &Args) -> (String, BTreeMap<String, String>) {
+    mask(normalize(line, args), args)
+}
+
+/// Strip ANSI escapes and replace control characters, keeping tabs with `--keep-tabs`.
+fn normalize(line: &str, args: &Args) -> String {
+    Normalization::ALL.apply(line, args.keep_tabs)
+}
+
+/// Apply `--mask` and `--masks-file` to a normalized line.
+fn mask(line: String, args: &Args) -> (String, BTreeMap<String, String>) {
+    mask::apply(args.masks.iter().chain(&args.file_masks), line)
+}
+
+/// Train on every entry `ingest` assembles from the non-empty lines of the reader until EOF.
+fn train_all<I: Iterator<Item = io::Result<Vec<u8>>>>(
+    drain: &mut Drain,
+    audit: &mut Audit,
+    lines: I,
+    record: Record,
+    args: &Args,
+) -> anyhow::Result<()> {
+    let mut ingester = Ingester::new(record, args);
+    for line in lines {
+        if let Some(entry) = ingester.push(line?)? {
+            audit.train(drain, &entry)?;
+        }
+    }
+    if let Some(entry) = ingester.finish() {
+        audit.train(drain, &entry)?;
+    }
+    Ok(())
+}
+
+/// Train on stdin and print the cluster id and masked values of each line as JSON.
+fn annotate_json(args: &Args) -> anyhow::Result<()> {
+    let mut drain = new_drain(args)?;
+    let mut audit = Audit::open(args)?;
+    let mut stdout = io::stdout().lock();
+    for line in input_lines(args)? {
+        let (entry, params) = prepare(&decode_lossy(line?, args.max_line_bytes), args);
+        let info = audit.train(&mut drain, &entry)?;
+        let annotation = serde_json::json!({
+            "cluster_id": info.cluster_id,
+            "params": params,
+        });
+        writeln!(stdout, "{}", annotation)?;
+    }
+    if args.stats {
+        print_stats(&drain, args.max_children);
+    }
+    Ok(())
+}
+
+/// Train on stdin until EOF and print each template followed by its lines.
+fn group(args: &Args) -> anyhow::Result<()> {
+    let mut drain = new_drain(args)?;
+    let mut audit = Audit::open(args)?;
+    let mut members: HashMap<usize, Vec<String>> = HashMap::new();
+    for line in input_lines(args)? {
+        let line = decode_lossy(line?, args.max_line_bytes);
+        let info = audit.train(&mut drain, &prepare(&line, args).0)?;
+        let lines = members.entry(info.cluster_id).or_default();
+        if args.group_max_lines.is_none_or(|max| lines.len() < max) {
+            lines.push(line);
+        }
+    }
+
+    let clusters = drain.clusters_by_id();
+    let mut stdout = io::stdout().lock();
+    for cluster in clusters {
+        writeln!(stdout, "[{}] {}", cluster.size, drain.template(cluster))?;
+        let lines = members.remove(&cluster.cluster_id).unwrap_or_default();
+        for line in &lines {
+            writeln!(stdout, "    {}", line)?;
+        }
+        if cluster.size > lines.len() {
+            writeln!(stdout, "    ... ({} more)", cluster.size - lines.len())?;
+        }
+    }
+    if args.stats {
+        print_stats(&drain, args.max_children);
+    }
+    Ok(())
+}
+
+/// Train a new drain on the whole file and sum up sizes per template.
+fn templates_of(path: &Path, args: &Args) -> anyhow::Result<BTreeMap<String, usize>> {
+    let mut drain = new_drain(args)?;
+    train_all(
+        &mut drain,
+        &mut Audit::default(),
+        read_lines(io::BufReader::new(fs::File::open(path)?)),
+        Record::default(),
+        args,
+    )?;
+
+    let mut templates = BTreeMap::new();
+    for (template, size) in drain.summaries() {
+        *templates.entry(template).or_default() += size;
+    }
+    Ok(templates)
+}
+
+fn diff(old: &Path, new: &Path, args: &Args) -> anyhow::Result<()> {
+    let old = templates_of(old, args)?;
+    let new = templates_of(new, args)?;
+
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "Only in new:")?;
+    for (template, size) in new.iter().filter(|(t, _)| !old.contains_key(*t)) {
+        writeln!(stdout, "  {}\t{}", size, template)?;
+    }
+    writeln!(stdout, "Only in old:")?;
+    for (template, size) in old.iter().filter(|(t, _)| !new.contains_key(*t)) {
+        writeln!(stdout, "  {}\t{}", size, template)?;
+    }
+    writeln!(stdout, "Changed sizes:")?;
+    for (template, old_size, new_size) in new.iter().filter_map(|(t, new_size)| {
+        old.get(t)
+            .filter(|old_size| *old_size != new_size)
+            .map(|old_size| (t, old_size, new_size))
+    }) {
+        writeln!(stdout, "  {} -> {}\t{}", old_size, new_size, template)?;
+    }
+    Ok(())
+}
+
+/// Train on stdin until EOF and print how `line` is matched, see `Drain::explain_match`.
+fn explain(line: &str, args: &Args) -> anyhow::Result<()> {
+    let mut drain = new_drain(args)?;
+    let mut audit = Audit::open(args)?;
+    train_all(
+        &mut drain,
+        &mut audit,
+        input_lines(args)?,
+        Record::create(args)?,
+        args,
+    )?;
+    let explanation = drain.explain_match(&prepare(line, args).0);
+
+    let templates: HashMap<usize, String> = drain
+        .clusters()
+        .iter()
+        .map(|cluster| (cluster.cluster_id, drain.template(cluster)))
+        .collect();
+    let describe = |(cluster_id, sim): (usize, f32)| {
+        let template = templates.get(&cluster_id).map_or("", String::as_str);
+        format!("[{}] {:.2} {}", cluster_id, sim, template)
+    };
+
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "tokens: {:?}", explanation.tokens)?;
+    let route: Vec<String> = std::iter::once(explanation.bucket)
+        .chain(explanation.path)
+        .collect();
+    writeln!(stdout, "route: {}", route.join(" > "))?;
+    if !explanation.reached_leaf {
+        writeln!(stdout, "route ends: no clusters along this route")?;
+    }
+    for candidate in explanation.candidates {
+        writeln!(stdout, "candidate: {}", describe(candidate))?;
+    }
+    match explanation.matched {
+        Some(matched) => writeln!(stdout, "match: {}", describe(matched))?,
+        None => writeln!(
+            stdout,
+            "match: none reached sim-th {}, a new cluster would be created",
+            drain.config().sim_th
+        )?,
+    }
+    Ok(())
+}
+
+/// Indented view of the prefix tree printed by `--tree`.
+fn indented_tree(drain: &Drain) -> String {
+    let templates: HashMap<usize, String> = drain
+        .clusters()
+        .iter()
+        .map(|cluster| (cluster.cluster_id, drain.template(cluster)))
+        .collect();
+
+    let mut out = String::new();
+    drain.walk_tree(|depth, key, cluster_ids| {
+        let indent = "  ".repeat(depth);
+        out.push_str(&format!("{}{}\n", indent, key));
+        for cluster_id in cluster_ids {
+            if let Some(template) = templates.get(cluster_id) {
+                out.push_str(&format!("{}  [{}] {}\n", indent, cluster_id, template));
+            }
+        }
+    });
+    out
+}
+
+/// Train on stdin until EOF and print the prefix tree, as DOT with `--dot`.
+fn tree(args: &Args) -> anyhow::Result<()> {
+    let mut drain = new_drain(args)?;
+    let mut audit = Audit::open(args)?;
+    train_all(
+        &mut drain,
+        &mut audit,
+        input_lines(args)?,
+        Record::create(args)?,
+        args,
+    )?;
+
+    let out = if args.dot {
+        drain.to_dot()
+    } else {
+        indented_tree(&drain)
+    };
+    io::stdout().lock().write_all(out.as_bytes())?;
+    if args.stats {
+        print_stats(&drain, args.max_children);
+    }
+    Ok(())
+}
+
+/// Where the TUI reads raw lines from.
+enum Source {
+    Stdin(Split<BufReader<Stdin>>),
+    /// Lines gathered from several inputs by background tasks.
+    Channel(mpsc::Receiver<Vec<u8>>),
+    /// Lines of `--replay`, paced by `--replay-rate` if given.
+    Replay(Split<BufReader<tokio::fs::File>>, Option<time::Interval>),
+}
+
+impl Source {
+    /// Open the input selected by the arguments, stdin by default.
+    fn open(args: &Args, canceled: &CancellationToken) -> anyhow::Result<Self> {
+        if let Some(dir) = &args.watch_dir {
+            let rx = watch::watch_dir(dir, args.glob.clone(), canceled.clone())?;
+            return Ok(Source::Channel(rx));
+        }
+        #[cfg(unix)]
+        if let Some(path) = &args.unix {
+            return Ok(Source::Channel(unix::listen(
+                path,
+                args.framing,
+                canceled.clone(),
+            )?));
+        }
+        #[cfg(all(target_os = "linux", feature = "journald"))]
+        if args.journal {
+            return Ok(Source::Channel(journal::follow(
+                &args.units,
+                canceled.clone(),
+            )?));
+        }
+        if let Some(path) = &args.replay {
+            let file = std::fs::File::open(path)?;
+            let pace = args
+                .replay_rate
+                .map(|rate| time::interval(Duration::from_secs_f64(1.0 / f64::from(rate.get()))));
+            return Ok(Source::Replay(
+                BufReader::new(tokio::fs::File::from_std(file)).split(b'\n'),
+                pace,
+            ));
+        }
+        Ok(Source::Stdin(
+            BufReader::new(tokio::io::stdin()).split(b'\n'),
+        ))
+    }
+
+    async fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Source::Stdin(reader) => reader.next_segment().await,
+            Source::Channel(rx) => Ok(rx.recv().await),
+            Source::Replay(reader, pace) => {
+                if let Some(pace) = pace {
+                    pace.tick().await;
+                }
+                reader.next_segment().await
+            }
+        }
+    }
+}
+
+/// Interval to check for cancellation while waiting for a key.
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keys of the TUI besides ctrl+c, handled by `Labeling`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Key {
+    Up,
+    Down,
+    Enter,
+    Esc,
+    Backspace,
+    Char(char),
+}
+
+/// Block until ctrl+c is pressed or `canceled`, sending the other keys to `keys`.
+fn wait_for_ctrl_c(
+    canceled: CancellationToken,
+    keys: mpsc::UnboundedSender<Key>,
+) -> io::Result<()> {
+    while !canceled.is_cancelled() {
+        if !crossterm::event::poll(KEY_POLL_INTERVAL)? {
+            continue;
+        }
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = crossterm::event::read()?
+        else {
+            continue;
+        };
+        let key = match code {
+            KeyCode::Char('c') if modifiers == KeyModifiers::CONTROL => break,
+            KeyCode::Up => Key::Up,
+            KeyCode::Down => Key::Down,
+            KeyCode::Enter => Key::Enter,
+            KeyCode::Esc => Key::Esc,
+            KeyCode::Backspace => Key::Backspace,
+            KeyCode::Char(c)
+                if !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                Key::Char(c)
+            }
+            _ => continue,
+        };
+        // The draining task is gone when exiting, so the key does not matter.
+        let _ = keys.send(key);
+    }
+    Ok(())
+}
+
+/// Cluster selected in the TUI, and the label being typed for it.
+#[derive(Debug, Default)]
+struct Labeling {
+    selected: Option<usize>,
+    editing: Option<String>,
+}
+
+impl Labeling {
+    /// Move the selection among the displayed cluster ids `shown` with up and down,
+    /// or edit the label of the selected cluster, started and committed with enter.
+    /// An empty label clears it, and escape cancels the edit or the selection.
+    fn handle(&mut self, key: Key, shown: &[usize], drain: &mut Drain) {
+        if let Some(label) = self.editing.as_mut() {
+            match key {
+                Key::Char(c) => label.push(c),
+                Key::Backspace => {
+                    label.pop();
+                }
+                Key::Enter => {
+                    let label = self.editing.take().filter(|label| !label.trim().is_empty());
+                    if let Some(cluster_id) = self.selected {
+                        drain.set_label(cluster_id, label);
+                    }
+                }
+                Key::Esc => self.editing = None,
+                Key::Up | Key::Down => {}
+            }
+            return;
+        }
+
+        let position = self
+            .selected
+            .and_then(|cluster_id| shown.iter().position(|id| *id == cluster_id));
+        match key {
+            Key::Up => {
+                let i = position.map_or(0, |i| i.saturating_sub(1));
+                self.selected = shown.get(i).copied().or(self.selected);
+            }
+            Key::Down => {
+                let i = position.map_or(0, |i| (i + 1).min(shown.len().saturating_sub(1)));
+                self.selected = shown.get(i).copied().or(self.selected);
+            }
+            Key::Enter => {
+                if let Some(cluster_id) = self.selected {
+                    let label = drain
+                        .clusters_by_id()
+                        .into_iter()
+                        .find(|cluster| cluster.cluster_id == cluster_id)
+                        .and_then(|cluster| cluster.label().map(String::from));
+                    self.editing = Some(label.unwrap_or_default());
+                }
+            }
+            Key::Esc => self.selected = None,
+            Key::Backspace | Key::Char(_) => {}
+        }
+    }
+}
+
+/// Start handling SIGTERM, and SIGHUP unless `Hangup` reloads on it,
+/// and return a future resolving on either,
+/// so that process managers stop logu as cleanly as ctrl+c.
+#[cfg(unix)]
+fn terminated(args: &Args) -> io::Result<impl std::future::Future<Output = ()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = match args.masks_file {
+        Some(_) => None,
+        None => Some(signal(SignalKind::hangup())?),
+    };
+    Ok(async move {
+        match sighup.as_mut() {
+            Some(sighup) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = sighup.recv() => {}
+                }
+            }
+            None => {
+                sigterm.recv().await;
+            }
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn terminated(_args: &Args) -> io::Result<impl std::future::Future<Output = ()>> {
+    Ok(std::future::pending())
+}
+
+/// SIGHUP, asking to reload `--masks-file` if given.
+struct Hangup(#[cfg(unix)] Option<tokio::signal::unix::Signal>);
+
+impl Hangup {
+    /// Start handling SIGHUP instead of exiting on it with `--masks-file`.
+    fn new(args: &Args) -> io::Result<Self> {
+        #[cfg(unix)]
+        return Ok(Self(
+            args.masks_file
+                .as_ref()
+                .map(|_| tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()))
+                .transpose()?,
+        ));
+        #[cfg(not(unix))]
+        {
+            let _ = args;
+            Ok(Self())
+        }
+    }
+
+    /// Wait for the next SIGHUP, which never comes without `--masks-file`
+    /// or on other platforms.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = self.0.as_mut() {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    if let Some(path) = &args.check_config {
+        if !check_config(path, &args)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(path) = &args.masks_file {
+        args.file_masks = mask::load(path)?;
+    }
+
+    if let Some(paths) = &args.diff {
+        return diff(&paths[0], &paths[1], &args);
+    }
+    if args.tree {
+        return tree(&args);
+    }
+    if args.group {
+        return group(&args);
+    }
+    if let Some(line) = &args.explain_line {
+        return explain(line, &args);
+    }
+    if args.annotate_json {
+        return annotate_json(&args);
+    }
+    if args.inference {
+        return inference(&args);
+    }
+    if let Some(path) = &args.evaluate {
+        return evaluate(path, &args);
+    }
+    if args.once || args.output.is_some() {
+        if once(&args)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let canceler = CancellationToken::new();
+    let shutdown_timeout = Duration::from_millis(args.shutdown_timeout_millis);
+
+    // Read raw bytes so that a stray non-UTF-8 byte does not stop the stream.
+    let mut reader = Source::open(&args, &canceler)?;
+    #[cfg(unix)]
+    let socket = args.unix.clone();
+
+    // Latest snapshot of the clusters for `--ws-addr`.
+    #[cfg(feature = "web")]
+    let snapshots = match args.ws_addr {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            let (tx, rx) = tokio::sync::watch::channel(String::from("[]"));
+            web::serve(listener, rx, canceler.clone());
+            Some(tx)
+        }
+        None => None,
+    };
+
+    let mut frame_log = args
+        .frame_log
+        .as_ref()
+        .map(|path| fs::OpenOptions::new().create(true).append(true).open(path))
+        .transpose()?;
+
+    // Handle signals from now on, so that the terminal is restored on exit.
+    let terminated = terminated(&args)?;
+    let mut hangup = Hangup::new(&args)?;
+
+    let plain = args.plain;
+    if !plain {
+        enable_raw_mode()?;
+        // Avoid the rendering messy by disabling mouse scroll and fixing the row.
+        crossterm::execute!(
+            io::stdout(),
+            crossterm::event::EnableMouseCapture,
+            crossterm::cursor::Hide
+        )?;
+    }
+
+    let canceled = canceler.clone();
+    let (keys_tx, mut keys) = mpsc::unbounded_channel();
+    let (stats, max_children) = (args.stats, args.max_children);
+    let state_file = args.state_file.clone();
+    let draining: JoinHandle<anyhow::Result<(usize, usize, Drain)>> = tokio::spawn(async move {
+        let render_interval = time::interval(Duration::from_millis(args.render_interval_millis));
+        let train_interval = time::interval(Duration::from_millis(args.train_interval_millis));
+        futures::pin_mut!(render_interval);
+        futures::pin_mut!(train_interval);
+
+        let mut drain = new_drain(&args)?;
+        let mut audit = Audit::open(&args)?;
+        let mut record = Record::create(&args)?;
+
+        let mut skipped = 0;
+        let mut truncated = 0;
+        // Entry being assembled from multiple lines with `--multiline`.
+        let mut pending: Option<String> = None;
+        // Stop reading once no line arrives for `--idle-timeout`.
+        let idle_timeout = idle_timeout(&args);
+        let mut last_line_at = time::Instant::now();
+        let mut idle = false;
+        // When each cluster last matched a line, for `--inactive-after`.
+        let mut last_seen: HashMap<usize, time::Instant> = HashMap::new();
+        let inactive_after = args.inactive_after_secs.map(Duration::from_secs);
+        let prune_every = Duration::from_secs(args.prune_every_secs);
+        let mut pruned_at = time::Instant::now();
+        let mut prev_lines: Vec<StyledGraphemes> = Vec::new();
+        let theme = Theme::from(args.theme);
+        let mut prev_terminal_size = (0, 0);
+        // Cluster sizes at the last refresh, used with `--refresh-on-change`.
+        let mut reported: HashMap<usize, usize> = HashMap::new();
+        let mut labeling = Labeling::default();
+        // Cluster ids displayed in the last frame, in display order.
+        let mut shown: Vec<usize> = Vec::new();
+        // Whether a key changed what to display since the last frame.
+        let mut keyed = false;
+        let mut sparkline = Sparkline::new(time::Instant::now());
+        // Last frame printed with `--plain`.
+        let mut plain_lines: Vec<String> = Vec::new();
+
+        while !canceled.is_cancelled() {
+            tokio::select! {
+                _ = train_interval.tick(), if !idle => {
+                    // Set a timeout to ensure non-blocking behavior,
+                    // especially responsive to user inputs like ctrl+c.
+                    // Continuously retry until cancellation to prevent loss of logs.
+                    let ret = timeout(
+                        Duration::from_millis(args.retrieval_timeout_millis),
+                        reader.next_line(),
+                    )
+                    .await;
+                    if ret.is_err() {
+                        if let Some(entry) = pending.take() {
+                            train(&mut drain, &mut audit, &mut last_seen, entry)?;
+                        }
+                        idle = idle_timeout.is_some_and(|d| last_line_at.elapsed() >= d);
+                        continue;
+                    }
+                    last_line_at = time::Instant::now();
+
+                    // Take the lines already available, up to `--train-batch-size`,
+                    // without waiting for more.
+                    let mut entries = Vec::new();
+                    let mut ret = ret?;
+                    let mut read = 1;
+                    let eof = loop {
+                        match ret {
+                            Ok(Some(bytes)) => {
+                                if let Some(entry) =
+                                    ingest(
+                                        bytes,
+                                        &mut pending,
+                                        &mut skipped,
+                                        &mut truncated,
+                                        &mut record,
+                                        &args,
+                                    )?
+                                {
+                                    entries.push(entry);
+                                }
+                            }
+                            _ => break true,
+                        }
+                        if read >= args.train_batch_size.get() {
+                            break false;
+                        }
+                        match reader.next_line().now_or_never() {
+                            Some(next) => ret = next,
+                            None => break false,
+                        }
+                        read += 1;
+                    };
+                    train_batch(&mut drain, &mut audit, &mut last_seen, entries, &canceled).await?;
 
-                    match ret {
-                        Ok(Some(line)) => {
-                            let escaped = strip_ansi_escapes::strip_str(line.replace(['\n', '\t'], " "));
-                            drain.train(escaped);
+                    if eof {
+                        if let Some(entry) = pending.take() {
+                            train(&mut drain, &mut audit, &mut last_seen, entry)?;
+                        }
+                        break;
+                    }
+                }
+                Some(key) = keys.recv() => {
+                    match key {
+                        Key::Char(c @ ('-' | '+')) if labeling.editing.is_none() => {
+                            resize_clusters(&mut drain, c);
+                            let live: HashSet<usize> = drain
+                                .clusters()
+                                .iter()
+                                .map(|cluster| cluster.cluster_id)
+                                .collect();
+                            last_seen.retain(|cluster_id, _| live.contains(cluster_id));
                         }
-                        _ => break,
+                        key => labeling.handle(key, &shown, &mut drain),
+                    }
+                    keyed = true;
+                }
+                _ = hangup.recv() => {
+                    // Errors cannot be shown over the TUI, so keep the previous masks.
+                    if let Some(masks) = args.masks_file.as_ref().and_then(|path| mask::load(path).ok()) {
+                        args.file_masks = masks;
                     }
                 }
                 _ = render_interval.tick() => {
-                    if prev
-                        != drain
-                            .clusters()
-                            .iter()
-                            .map(|cluster| cluster.cluster_id)
-                            .collect::<Vec<usize>>()
+                    for cluster_id in drain.expire(unix_secs()) {
+                        last_seen.remove(&cluster_id);
+                    }
+                    if let Some(min_size) = args.prune_below {
+                        if pruned_at.elapsed() >= prune_every {
+                            if drain.prune(min_size) > 0 {
+                                let live: HashSet<usize> = drain
+                                    .clusters()
+                                    .iter()
+                                    .map(|cluster| cluster.cluster_id)
+                                    .collect();
+                                last_seen.retain(|cluster_id, _| live.contains(cluster_id));
+                            }
+                            pruned_at = time::Instant::now();
+                        }
+                    }
+                    #[cfg(feature = "web")]
+                    if let Some(snapshots) = &snapshots {
+                        let snapshot = serde_json::Value::from(
+                            drain.clusters_by_id().into_iter().map(|cluster| cluster_json(&drain, cluster)).collect::<Vec<_>>(),
+                        )
+                        .to_string();
+                        snapshots.send_if_modified(|current| {
+                            let modified = *current != snapshot;
+                            *current = snapshot;
+                            modified
+                        });
+                    }
+
+                    sparkline.tick(drain.total_lines(), time::Instant::now());
+                    if args.plain {
+                        let frame = plain_frame(&drain, &args, |cluster| {
+                            args.show_inactive || !is_inactive(cluster, &last_seen, inactive_after)
+                        });
+                        print_plain(&drain, frame, &mut plain_lines)?;
+                        continue;
+                    }
+                    let terminal_size = crossterm::terminal::size()?;
+
+                    if args.refresh_on_change
+                        && !keyed
+                        && !args.sparkline
+                        && prev_terminal_size == terminal_size
+                        && !crossed_boundary(&reported, &drain)
                     {
-                        let terminal_size = crossterm::terminal::size()?;
-                        crossterm::execute!(
-                            io::stdout(),
-                            crossterm::terminal::Clear(crossterm::terminal::ClearType::All),
-                            crossterm::terminal::Clear(crossterm::terminal::ClearType::Purge),
-                            cursor::MoveTo(0, 0),
-                        )?;
-
-                        let mut total_rows = 0;
-                        for cluster in drain
+                        continue;
+                    }
+                    if args.refresh_on_change {
+                        reported = drain
                             .clusters()
                             .iter()
-                            .filter(|cluster| cluster.size > args.cluster_size_th)
-                            .take(terminal_size.1 as usize)
-                        {
-                            let styled = StyledGraphemes::from(cluster.to_string());
-                            let rows = styled
-                                .matrixify(terminal_size.0 as usize, terminal_size.1 as usize, 0)
-                                .0;
-
-                            if total_rows + rows.len() > terminal_size.1 as usize {
-                                break;
-                            }
-
-                            crossterm::execute!(
-                                io::stdout(),
-                                style::Print(cluster),
-                                cursor::MoveToNextLine(1),
-                            )?;
+                            .map(|cluster| (cluster.cluster_id, cluster.size))
+                            .collect();
+                    }
 
-                            total_rows += rows.len();
+                    // Keep the header on a single row, cut at the terminal width.
+                    let header: Vec<StyledGraphemes> = args
+                        .compression_ratio
+                        .then(|| compression_header(&drain))
+                        .into_iter()
+                        .chain(args.sparkline.then(|| sparkline.header()))
+                        .chain(labeling.editing.as_ref().map(|label| format!("label: {}", label)))
+                        .filter_map(|header| {
+                            StyledGraphemes::from_str(header, ContentStyle::default())
+                                .apply_attribute(Attribute::Bold)
+                                .matrixify(terminal_size.0 as usize, 1, 0)
+                                .0
+                                .into_iter()
+                                .next()
+                        })
+                        .collect();
+                    let wildcards: Vec<&str> = [args.param_str.as_str(), NUM_PARAM_STR]
+                        .into_iter()
+                        .chain(drain.token_classes().iter().map(|(_, wildcard)| wildcard.as_str()))
+                        .collect();
+                    let mut clusters = drain.clusters();
+                    sort_clusters(&mut clusters, args.sort, unix_secs());
+                    let selected = select_clusters(
+                        clusters,
+                        args.cluster_size_th,
+                        (terminal_size.1 as usize).saturating_sub(header.len()),
+                        |cluster| {
+                            args.show_inactive || !is_inactive(cluster, &last_seen, inactive_after)
+                        },
+                        |cluster| {
+                        let template = drain.template(cluster);
+                        let literal = if args.level_colors {
+                            level_color(&template, &args.error_levels, &args.warn_levels, &theme)
+                                .or(theme.literal)
+                        } else {
+                            theme.literal
+                        };
+                        let mut styled = styled_template(
+                            &template,
+                            &wildcards,
+                            literal,
+                            theme.wildcard,
+                        );
+                        if let Some(label) = cluster.label() {
+                            let label = StyledGraphemes::from_str(
+                                format!("[{}] ", label),
+                                ContentStyle::default(),
+                            )
+                            .apply_attribute(Attribute::Bold);
+                            styled = [label, styled].into_iter().collect();
                         }
-                        prev = drain
-                            .clusters()
+                        if is_inactive(cluster, &last_seen, inactive_after) {
+                            styled = styled.apply_attribute(Attribute::Dim);
+                        }
+                        if labeling.selected == Some(cluster.cluster_id) {
+                            styled = styled.apply_attribute(Attribute::Reverse);
+                        }
+                        styled
+                            .matrixify(terminal_size.0 as usize, terminal_size.1 as usize, 0)
+                            .0
+                        },
+                    );
+                    let lines: Vec<StyledGraphemes> = header
+                        .into_iter()
+                        .chain(selected.iter().flat_map(|(_, rows)| rows.iter().cloned()))
+                        .collect();
+
+                    shown = selected.iter().map(|(cluster, _)| cluster.cluster_id).collect();
+                    keyed = false;
+
+                    if prev_lines == lines && prev_terminal_size == terminal_size {
+                        continue;
+                    }
+
+                    render(
+                        &mut io::stdout().lock(),
+                        &lines,
+                        &prev_lines,
+                        args.full_clear || prev_terminal_size != terminal_size,
+                    )?;
+
+                    if let Some(frame_log) = frame_log.as_mut() {
+                        let frame: Vec<_> = selected
                             .iter()
-                            .map(|cluster| cluster.cluster_id)
-                            .collect::<Vec<usize>>();
+                            .map(|(cluster, _)| cluster_json(&drain, cluster))
+                            .collect();
+                        writeln!(frame_log, "{}", serde_json::Value::from(frame))?;
                     }
+
+                    prev_lines = lines;
+                    prev_terminal_size = terminal_size;
                 }
             }
         }
-        Ok(())
+        if args.plain && !canceled.is_cancelled() {
+            let frame = plain_frame(&drain, &args, |cluster| {
+                args.show_inactive || !is_inactive(cluster, &last_seen, inactive_after)
+            });
+            print_plain(&drain, frame, &mut plain_lines)?;
+            // Nothing is left to show, so exit as `--once` does.
+            canceled.cancel();
+        }
+        Ok((skipped, truncated, drain))
     });
 
-    loop {
-        let event = crossterm::event::read()?;
-        #[allow(clippy::single_match)]
-        match event {
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('c'),
-                modifiers: KeyModifiers::CONTROL,
-                kind: KeyEventKind::Press,
-                state: KeyEventState::NONE,
-            }) => {
-                break;
-            }
-            _ => {}
+    if plain {
+        // Without raw mode, ctrl+c arrives as SIGINT.
+        tokio::select! {
+            ret = tokio::signal::ctrl_c() => ret?,
+            _ = terminated => {}
+            _ = canceler.cancelled() => {}
+        }
+    } else {
+        // Read keys on a blocking thread, which stops polling once canceled.
+        let keys = tokio::task::spawn_blocking({
+            let canceled = canceler.clone();
+            move || wait_for_ctrl_c(canceled, keys_tx)
+        });
+        tokio::select! {
+            ret = keys => ret??,
+            _ = terminated => {}
         }
     }
 
     canceler.cancel();
-    draining.await??;
+    // Do not let a stuck read delay the exit,
+    // and restore the terminal whatever the task returned.
+    let abort_handle = draining.abort_handle();
+    let ret = timeout(shutdown_timeout, draining).await;
+    #[cfg(unix)]
+    if let Some(path) = &socket {
+        unix::remove(path);
+    }
 
-    disable_raw_mode()?;
-    crossterm::execute!(
-        io::stdout(),
-        crossterm::event::DisableMouseCapture,
-        crossterm::cursor::Show
-    )?;
-    Ok(())
+    if !plain {
+        disable_raw_mode()?;
+        crossterm::execute!(
+            io::stdout(),
+            crossterm::event::DisableMouseCapture,
+            crossterm::cursor::Show
+        )?;
+    }
+
+    match ret {
+        Ok(ret) => {
+            let (skipped, truncated, drain) = ret??;
+            report_dropped(skipped, truncated);
+            if stats {
+                print_stats(&drain, max_children);
+            }
+            if let Some(path) = &state_file {
+                save_state(&drain, path)?;
+            }
+            Ok(())
+        }
+        Err(_) => {
+            abort_handle.abort();
+            // The runtime waits for the blocking read of stdin on drop,
+            // so exit the process directly.
+            std::process::exit(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod args {
+        use super::*;
+
+        #[test]
+        fn test_default() {
+            let args = Args::try_parse_from(["logu"]).unwrap();
+            assert_eq!(args.max_node_depth, DEFAULT_MAX_NODE_DEPTH);
+            assert_eq!(args.sim_th, DEFAULT_SIM_TH);
+            assert_eq!(args.max_children, DEFAULT_MAX_CHILDREN);
+            assert_eq!(args.param_str, DEFAULT_PARAM_STR);
+            assert_eq!(args.max_clusters, Some(5000));
+        }
+
+        #[test]
+        fn test_unbounded_max_clusters() {
+            for value in ["0", "none"] {
+                let args = Args::try_parse_from(["logu", "--max-clusters", value]).unwrap();
+                assert_eq!(args.max_clusters, None);
+            }
+            let args = Args::try_parse_from(["logu", "--max-clusters", "10"]).unwrap();
+            assert_eq!(args.max_clusters, Some(10));
+            assert!(Args::try_parse_from(["logu", "--max-clusters", "many"]).is_err());
+        }
+
+        #[test]
+        fn test_inference_requires_state_file() {
+            assert!(Args::try_parse_from(["logu", "--inference"]).is_err());
+            assert!(Args::try_parse_from(["logu", "--show-unmatched"]).is_err());
+            assert!(Args::try_parse_from([
+                "logu",
+                "--inference",
+                "--state-file",
+                "model.json",
+                "--show-unmatched"
+            ])
+            .is_ok());
+        }
+
+        #[test]
+        fn test_token_class() {
+            let args =
+                Args::try_parse_from(["logu", "--token-class", "hex", "--token-class", "id=<REQ>"])
+                    .unwrap();
+            assert_eq!(
+                args.token_classes,
+                vec![
+                    (TokenClass::Hex, String::from("<HEX>")),
+                    (TokenClass::Id, String::from("<REQ>"))
+                ]
+            );
+            assert!(Args::try_parse_from(["logu", "--token-class", "octal"]).is_err());
+            assert!(Args::try_parse_from(["logu", "--token-class", "hex="]).is_err());
+        }
+
+        #[test]
+        fn test_trailing_penalty_requires_mixed_lengths() {
+            assert!(Args::try_parse_from(["logu", "--trailing-penalty", "0.5"]).is_err());
+            let args =
+                Args::try_parse_from(["logu", "--mixed-lengths", "--trailing-penalty", "0.5"])
+                    .unwrap();
+            assert_eq!(args.trailing_penalty, Some(0.5));
+        }
+
+        #[test]
+        fn test_train_batch_max() {
+            let args = Args::try_parse_from(["logu", "--train-batch-max", "64"]).unwrap();
+            assert_eq!(args.train_batch_size.get(), 64);
+            assert!(Args::try_parse_from(["logu", "--train-batch-max", "0"]).is_err());
+        }
+
+        #[test]
+        fn test_prune_every_requires_prune_below() {
+            assert!(Args::try_parse_from(["logu"]).is_ok());
+            assert!(Args::try_parse_from(["logu", "--prune-every", "10"]).is_err());
+            let args = Args::try_parse_from(["logu", "--prune-below", "2", "--prune-every", "10"])
+                .unwrap();
+            assert_eq!((args.prune_below, args.prune_every_secs), (Some(2), 10));
+        }
+
+        #[test]
+        fn test_fail_on_new_requires_once() {
+            assert!(Args::try_parse_from(["logu", "--fail-on-new"]).is_err());
+            assert!(Args::try_parse_from(["logu", "--once", "--fail-on-new"]).is_err());
+            assert!(Args::try_parse_from([
+                "logu",
+                "--once",
+                "--state-file",
+                "baseline.json",
+                "--fail-on-new"
+            ])
+            .is_ok());
+        }
+
+        #[test]
+        fn test_group_flags() {
+            assert!(Args::try_parse_from(["logu", "--group-max-lines", "3"]).is_err());
+            assert!(Args::try_parse_from(["logu", "--group", "--group-max-lines", "3"]).is_ok());
+            assert!(Args::try_parse_from(["logu", "--group", "--multiline", "^ERROR"]).is_err());
+            assert!(
+                Args::try_parse_from(["logu", "--annotate-json", "--invalid-utf8", "skip"])
+                    .is_err()
+            );
+        }
+
+        #[test]
+        fn test_delimiter_regex() {
+            let args = Args::try_parse_from(["logu", "--delimiter-regex", "[,;]+"]).unwrap();
+            assert_eq!(args.delimiter_regex.unwrap().as_str(), "[,;]+");
+            assert!(Args::try_parse_from(["logu", "--delimiter-regex", ",*"]).is_err());
+            assert!(Args::try_parse_from(["logu", "--delimiter-regex", "("]).is_err());
+        }
+    }
+
+    mod wildcards {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let args = Args::try_parse_from(["logu"]).unwrap();
+            assert_eq!(wildcards(&args), vec![DEFAULT_PARAM_STR]);
+            let args =
+                Args::try_parse_from(["logu", "--typed-params", "--token-class", "hex"]).unwrap();
+            assert_eq!(wildcards(&args), vec![DEFAULT_PARAM_STR, "<NUM>", "<HEX>"]);
+        }
+    }
+
+    mod select_clusters {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for line in [
+                "user alice logged in",
+                "user bob logged in",
+                "disk full",
+                "connection reset by peer",
+            ] {
+                drain.train(line);
+            }
+            let templates = |selected: &[(&LogCluster, Vec<()>)]| {
+                selected
+                    .iter()
+                    .map(|(cluster, _)| cluster.to_string())
+                    .collect::<Vec<_>>()
+            };
+
+            // Most recently used first, one row each.
+            let selected = select_clusters(drain.clusters(), 0, 10, |_| true, |_| vec![()]);
+            assert_eq!(
+                templates(&selected),
+                vec![
+                    "connection reset by peer",
+                    "disk full",
+                    "user <*> logged in"
+                ]
+            );
+
+            // Small and invisible clusters are skipped.
+            let selected = select_clusters(drain.clusters(), 1, 10, |_| true, |_| vec![()]);
+            assert_eq!(templates(&selected), vec!["user <*> logged in"]);
+            let selected = select_clusters(
+                drain.clusters(),
+                0,
+                10,
+                |cluster| cluster.to_string() != "disk full",
+                |_| vec![()],
+            );
+            assert_eq!(
+                templates(&selected),
+                vec!["connection reset by peer", "user <*> logged in"]
+            );
+
+            // Stop at the first cluster that does not fit, with 10 columns per row.
+            let selected = select_clusters(
+                drain.clusters(),
+                0,
+                4,
+                |_| true,
+                |cluster| vec![(); cluster.to_string().len().div_ceil(10)],
+            );
+            assert_eq!(
+                templates(&selected),
+                vec!["connection reset by peer", "disk full"]
+            );
+        }
+    }
+
+    mod render {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let line = |s: &str| StyledGraphemes::from_str(s, ContentStyle::default());
+            let prev = vec![line("kept"), line("old"), line("dropped")];
+            let lines = vec![line("kept"), line("new")];
+
+            let mut out = Vec::new();
+            render(&mut out, &lines, &prev, false).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(!out.contains("kept"));
+            assert!(out.contains("new"));
+            // Move to the second row, and blank out the third one.
+            assert!(out.contains("\x1b[2;1H"));
+            assert!(out.contains("\x1b[3;1H\x1b[2K"));
+
+            let mut out = Vec::new();
+            render(&mut out, &lines, &prev, true).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with("\x1b[2J"));
+            assert!(out.contains("kept") && out.contains("new"));
+            assert!(!out.contains("\x1b[3;1H"));
+        }
+    }
+
+    mod size_buckets {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let histogram = BTreeMap::from([(1, 5), (2, 1), (10, 2), (11, 1), (1500, 1)]);
+            assert_eq!(
+                size_buckets(&histogram),
+                vec![
+                    ("1".to_string(), 5),
+                    ("2-10".to_string(), 3),
+                    ("11-100".to_string(), 1),
+                    ("1001-10000".to_string(), 1),
+                ]
+            );
+        }
+    }
+
+    mod ingest {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let args = Args::try_parse_from(["logu", "--multiline", "^ERROR"]).unwrap();
+            let (mut pending, mut skipped, mut truncated) = (None, 0, 0);
+            let mut record = Record::default();
+            let mut feed = |line: &[u8]| {
+                ingest(
+                    line.to_vec(),
+                    &mut pending,
+                    &mut skipped,
+                    &mut truncated,
+                    &mut record,
+                    &args,
+                )
+                .unwrap()
+            };
+            assert_eq!(feed(b"ERROR failed"), None);
+            assert_eq!(feed(b"  at main"), None);
+            assert_eq!(
+                feed(b"ERROR again"),
+                Some("ERROR failed   at main".to_string())
+            );
+
+            let args = Args::try_parse_from(["logu", "--invalid-utf8", "skip"]).unwrap();
+            let (mut pending, mut skipped, mut truncated) = (None, 0, 0);
+            assert_eq!(
+                ingest(
+                    b"\xffbad".to_vec(),
+                    &mut pending,
+                    &mut skipped,
+                    &mut truncated,
+                    &mut record,
+                    &args
+                )
+                .unwrap(),
+                None
+            );
+            assert_eq!(skipped, 1);
+
+            let args = Args::try_parse_from(["logu", "--max-line-bytes", "8"]).unwrap();
+            let (mut pending, mut skipped, mut truncated) = (None, 0, 0);
+            assert_eq!(
+                ingest(
+                    "user jjé logged in".as_bytes().to_vec(),
+                    &mut pending,
+                    &mut skipped,
+                    &mut truncated,
+                    &mut record,
+                    &args
+                )
+                .unwrap(),
+                Some("user jj".to_string())
+            );
+            assert_eq!(truncated, 1);
+        }
+    }
+
+    mod train_all {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let args =
+                Args::try_parse_from(["logu", "--multiline", "^ERROR", "--invalid-utf8", "skip"])
+                    .unwrap();
+            let mut drain = Drain::default();
+            let input = &b"ERROR a\n at x\n\xff\nERROR b\n at y\n"[..];
+            train_all(
+                &mut drain,
+                &mut Audit::default(),
+                read_lines(input),
+                Record::default(),
+                &args,
+            )
+            .unwrap();
+            assert_eq!(
+                drain.summaries(),
+                vec![(String::from("ERROR <*> at <*>"), 2)]
+            );
+        }
+    }
+
+    mod ingester {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let args =
+                Args::try_parse_from(["logu", "--multiline", "^ERROR", "--invalid-utf8", "skip"])
+                    .unwrap();
+            let mut ingester = Ingester::new(Record::default(), &args);
+            let mut entries = Vec::new();
+            for line in [&b"ERROR a"[..], b" at x", b"\xff", b"ERROR b", b" at y"] {
+                entries.extend(ingester.push(line.to_vec()).unwrap());
+            }
+            assert_eq!(entries, vec!["ERROR a  at x"]);
+            assert_eq!(ingester.skipped, 1);
+            assert_eq!(ingester.finish(), Some("ERROR b  at y".to_string()));
+            assert_eq!(ingester.finish(), None);
+        }
+    }
+
+    mod record {
+        use super::*;
+
+        /// Remove the file at the path on drop, even if an assertion fails.
+        struct TempFile(PathBuf);
+
+        impl Drop for TempFile {
+            fn drop(&mut self) {
+                let _ = fs::remove_file(&self.0);
+            }
+        }
+
+        #[test]
+        fn test_replay() {
+            let path =
+                TempFile(std::env::temp_dir().join(format!("logu-record-{}", std::process::id())));
+            let path_arg = path.0.to_str().unwrap();
+            let args =
+                Args::try_parse_from(["logu", "--record", path_arg, "--mask", r"\d+ -> <NUM>"])
+                    .unwrap();
+            let (mut pending, mut skipped, mut truncated) = (None, 0, 0);
+            let mut record = Record::create(&args).unwrap();
+            for line in [&b"\x1b[31mretry 1\x1b[0m"[..], b"retry\t2"] {
+                let entry = ingest(
+                    line.to_vec(),
+                    &mut pending,
+                    &mut skipped,
+                    &mut truncated,
+                    &mut record,
+                    &args,
+                );
+                assert!(entry.unwrap().unwrap().ends_with("<NUM>"));
+            }
+            drop(record);
+
+            let args = Args::try_parse_from(["logu", "--replay", path_arg]).unwrap();
+            let lines: Vec<Vec<u8>> = input_lines(&args).unwrap().map(Result::unwrap).collect();
+            assert_eq!(lines, vec![b"retry 1".to_vec(), b"retry 2".to_vec()]);
+        }
+    }
+
+    mod logfmt_value {
+        use super::*;
+
+        #[test]
+        fn test() {
+            assert_eq!(logfmt_value("login"), "login");
+            assert_eq!(logfmt_value(""), r#""""#);
+            assert_eq!(
+                logfmt_value("user <*> logged in"),
+                r#""user <*> logged in""#
+            );
+            assert_eq!(logfmt_value(r#"key="a\b""#), r#""key=\"a\\b\"""#);
+            assert_eq!(logfmt_value("a\tb"), r#""a\tb""#);
+        }
+    }
+
+    mod progress {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let args = Args::try_parse_from(["logu", "--once", "--quiet"]).unwrap();
+            let mut progress = Progress::new(&args);
+            assert!(!progress.enabled);
+            let mut drain = Drain::default();
+            drain.train("user alice logged in");
+            progress.update(19, &drain);
+            progress.size = None;
+            assert_eq!(
+                progress.message(drain.len()),
+                "processed 1 lines, 1 clusters"
+            );
+            progress.size = Some(40);
+            assert_eq!(
+                progress.message(drain.len()),
+                "processed 1 lines, 1 clusters (50%)"
+            );
+        }
+    }
+
+    mod labeling {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            let first = drain.train("user alice logged in").cluster_id;
+            let second = drain.train("disk is full").cluster_id;
+            let shown = [second, first];
+
+            let mut labeling = Labeling::default();
+            labeling.handle(Key::Enter, &shown, &mut drain);
+            assert_eq!(labeling.editing, None);
+            for key in [Key::Down, Key::Down, Key::Down, Key::Up, Key::Down] {
+                labeling.handle(key, &shown, &mut drain);
+            }
+            assert_eq!(labeling.selected, Some(first));
+
+            labeling.handle(Key::Enter, &shown, &mut drain);
+            for key in "noisy"
+                .chars()
+                .map(Key::Char)
+                .chain([Key::Backspace, Key::Enter])
+            {
+                labeling.handle(key, &shown, &mut drain);
+            }
+            assert_eq!(labeling.editing, None);
+            let label = |drain: &Drain| drain.clusters_by_id()[0].label().map(String::from);
+            assert_eq!(label(&drain).as_deref(), Some("nois"));
+
+            labeling.handle(Key::Enter, &shown, &mut drain);
+            assert_eq!(labeling.editing.as_deref(), Some("nois"));
+            labeling.handle(Key::Char('e'), &shown, &mut drain);
+            labeling.handle(Key::Esc, &shown, &mut drain);
+            assert_eq!(label(&drain).as_deref(), Some("nois"));
+
+            labeling.handle(Key::Enter, &shown, &mut drain);
+            for _ in 0..4 {
+                labeling.handle(Key::Backspace, &shown, &mut drain);
+            }
+            labeling.handle(Key::Enter, &shown, &mut drain);
+            assert_eq!(label(&drain), None);
+
+            labeling.handle(Key::Esc, &shown, &mut drain);
+            assert_eq!(labeling.selected, None);
+        }
+    }
+
+    mod sparkline {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let start = time::Instant::now();
+            let mut sparkline = Sparkline::new(start);
+            assert_eq!(sparkline.header(), " 0 lines/s");
+            for (i, total_lines) in [0, 10, 40, 80, 80, 0].into_iter().enumerate() {
+                sparkline.tick(total_lines, start + Duration::from_secs(i as u64 + 1));
+            }
+            assert_eq!(sparkline.header(), "▁▃▆█▁▁ 0 lines/s");
+
+            let at = start + Duration::from_secs(10);
+            for _ in 0..SPARKLINE_TICKS {
+                sparkline.tick(0, at);
+            }
+            sparkline.tick(5, at + Duration::from_millis(500));
+            let header = sparkline.header();
+            assert!(header.ends_with("█ 10 lines/s"), "{}", header);
+            assert_eq!(
+                header
+                    .chars()
+                    .filter(|c| SPARKLINE_BLOCKS.contains(c))
+                    .count(),
+                SPARKLINE_TICKS
+            );
+        }
+    }
+
+    mod plain_frame {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let args = Args::try_parse_from(["logu", "--plain", "--cluster-size-th", "1"]).unwrap();
+            let mut drain = Drain::default();
+            for log in ["user alice logged in", "user bob logged in", "disk is full"] {
+                drain.train(log);
+            }
+            drain.train("kernel panic");
+            drain.train("kernel panic");
+            drain.set_label(3, Some(String::from("known")));
+            assert_eq!(
+                plain_frame(&drain, &args, |_| true),
+                vec!["[2] [known] kernel panic", "[2] user <*> logged in"]
+            );
+            assert_eq!(
+                plain_frame(&drain, &args, |cluster| cluster.cluster_id == 1),
+                vec!["[2] user <*> logged in"]
+            );
+        }
+
+        #[test]
+        fn test_wildcard_runs() {
+            let args = Args::try_parse_from(["logu", "--wildcard-runs", "count"]).unwrap();
+            let mut drain = new_drain(&args).unwrap();
+            for log in ["copied 10 MB in 2 s", "copied 3 GB in 7 s"] {
+                drain.train(log);
+            }
+            assert_eq!(
+                plain_frame(&drain, &args, |_| true),
+                vec!["[2] copied <*>{2} in <*> s"]
+            );
+            assert_eq!(
+                cluster_json(&drain, drain.clusters()[0])["template"],
+                "copied <*>{2} in <*> s"
+            );
+        }
+    }
+
+    mod coverage_report {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for log in [
+                "user alice logged in",
+                "user bob logged in",
+                "disk is full",
+                "oops",
+            ] {
+                drain.train(log);
+            }
+            let report = drain.coverage([
+                "disk is full",
+                "user carol logged in",
+                "user dave logged in",
+                "boom now",
+            ]);
+            assert_eq!(
+                coverage_report(&drain, &report),
+                vec![
+                    "matched: 3 of 4 lines (75.00%)",
+                    "templates matched: 2 of 3",
+                    "[2] user <*> logged in",
+                    "[1] disk is full",
+                ]
+            );
+            assert!(Args::try_parse_from(["logu", "--evaluate", "held-out.log"]).is_err());
+        }
+    }
+
+    mod compression_header {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            assert_eq!(
+                compression_header(&drain),
+                "lines: 0  clusters: 0  ratio: -"
+            );
+            for line in ["user alice logged in", "user bob logged in", "disk is full"] {
+                drain.train(line);
+            }
+            assert_eq!(
+                compression_header(&drain),
+                "lines: 3  clusters: 2  ratio: 1.50"
+            );
+            drain.set_max_clusters(Some(8)).unwrap();
+            assert_eq!(
+                compression_header(&drain),
+                "lines: 3  clusters: 2/8  ratio: 1.50"
+            );
+        }
+    }
+
+    mod resize_clusters {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for line in ["disk is full", "user alice logged in", "connection reset"] {
+                drain.train(line);
+            }
+            resize_clusters(&mut drain, '+');
+            assert_eq!(drain.config().max_clusters, None);
+            resize_clusters(&mut drain, '-');
+            assert_eq!(drain.config().max_clusters, Some(1));
+            assert_eq!(drain.clusters_by_id()[0].to_string(), "connection reset");
+            resize_clusters(&mut drain, '-');
+            assert_eq!(drain.config().max_clusters, Some(1));
+            resize_clusters(&mut drain, '+');
+            assert_eq!(drain.config().max_clusters, Some(2));
+        }
+    }
+
+    mod sort_clusters {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let mut drain = Drain::default();
+            for _ in 0..3 {
+                drain.train_at("user alice logged in", 0.0);
+            }
+            drain.train_at("disk is full", 100.0);
+            drain.train_at("disk is full", 100.0);
+            drain.train_at("connection reset", 100.0);
+
+            let sorted = |sort| {
+                let mut clusters = drain.clusters();
+                sort_clusters(&mut clusters, sort, 100.0);
+                clusters
+                    .iter()
+                    .map(|cluster| cluster.to_string())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                sorted(Sort::Recent),
+                vec!["connection reset", "disk is full", "user alice logged in"]
+            );
+            assert_eq!(
+                sorted(Sort::Size),
+                vec!["user alice logged in", "disk is full", "connection reset"]
+            );
+            assert_eq!(
+                sorted(Sort::Rate),
+                vec!["disk is full", "connection reset", "user alice logged in"]
+            );
+        }
+    }
 }
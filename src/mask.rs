@@ -0,0 +1,261 @@
+use std::{collections::BTreeMap, fmt, fs, path::Path, str::FromStr};
+
+use regex::{NoExpand, Regex};
+
+/// Unrelated tokens that only an overly broad mask matches as a whole.
+const ARBITRARY_TOKENS: [&str; 4] = ["user", "ERROR", "/var/log/app.log", "42"];
+
+/// Replace the matches of `regex` with a fixed token before training,
+/// parsed from `REGEX -> REPLACEMENT`, or from `NAME=REGEX` replacing with `<NAME>`
+/// in upper case, e.g. `ip=\d+\.\d+\.\d+\.\d+` with `<IP>`.
+#[derive(Clone, Debug)]
+pub struct Mask {
+    regex: Regex,
+    replacement: String,
+}
+
+impl FromStr for Mask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let named = || {
+            let (name, regex) = s.split_once('=')?;
+            let named =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            named.then(|| (regex, format!("<{}>", name.to_ascii_uppercase())))
+        };
+        let (regex, replacement) = match s.rsplit_once(" -> ") {
+            Some((regex, replacement)) => (regex, replacement.to_string()),
+            None => named().ok_or_else(|| {
+                format!(
+                    "expected `REGEX -> REPLACEMENT` or `NAME=REGEX`, got `{}`",
+                    s
+                )
+            })?,
+        };
+        if replacement.is_empty() || replacement.contains(char::is_whitespace) {
+            return Err(format!(
+                "replacement must be a non-empty token without whitespace, got `{}`",
+                replacement
+            ));
+        }
+        Ok(Self {
+            regex: Regex::new(regex).map_err(|e| e.to_string())?,
+            replacement,
+        })
+    }
+}
+
+/// Problem found in a masks file by `check`.
+#[derive(Debug, PartialEq)]
+pub struct Issue {
+    pub line: usize,
+    /// Whether the file cannot be loaded at all, rather than being suspicious.
+    pub error: bool,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = if self.error { "error" } else { "warning" };
+        write!(f, "line {}: {}: {}", self.line, level, self.message)
+    }
+}
+
+/// Validate the content of a masks file without stopping at the first problem:
+/// lines that fail to parse, replacements colliding with one of `wildcards`
+/// and patterns so broad that they match arbitrary words.
+pub fn check(content: &str, wildcards: &[&str]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut issue = |error, message| {
+            issues.push(Issue {
+                line: i + 1,
+                error,
+                message,
+            })
+        };
+        let mask = match line.parse::<Mask>() {
+            Ok(mask) => mask,
+            Err(e) => {
+                issue(true, e);
+                continue;
+            }
+        };
+        if wildcards.contains(&mask.replacement.as_str()) {
+            issue(
+                false,
+                format!(
+                    "replacement `{}` collides with a wildcard of the templates",
+                    mask.replacement
+                ),
+            );
+        }
+        if mask.regex.is_match("") {
+            issue(false, String::from("pattern matches the empty string"));
+        } else if ARBITRARY_TOKENS.iter().all(|token| {
+            mask.regex
+                .find(token)
+                .is_some_and(|m| m.as_str().len() == token.len())
+        }) {
+            issue(false, String::from("pattern matches arbitrary words"));
+        }
+    }
+    issues
+}
+
+/// Read masks from `path`, one `REGEX -> REPLACEMENT` per line.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Mask>> {
+    let mut masks = Vec::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mask = line
+            .parse()
+            .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), i + 1, e))?;
+        masks.push(mask);
+    }
+    Ok(masks)
+}
+
+/// Apply `masks` in order and return the masked line
+/// with the values of the named capture groups that matched.
+/// When a name matches more than once, the last value is kept.
+pub fn apply<'a>(
+    masks: impl IntoIterator<Item = &'a Mask>,
+    line: String,
+) -> (String, BTreeMap<String, String>) {
+    let mut params = BTreeMap::new();
+    let mut line = line;
+    for mask in masks {
+        for captures in mask.regex.captures_iter(&line) {
+            for name in mask.regex.capture_names().flatten() {
+                if let Some(value) = captures.name(name) {
+                    params.insert(name.to_string(), value.as_str().to_string());
+                }
+            }
+        }
+        line = mask
+            .regex
+            .replace_all(&line, NoExpand(&mask.replacement))
+            .into_owned();
+    }
+    (line, params)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod apply {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let masks: Vec<Mask> = vec![
+                r"(?P<ip>\d+\.\d+\.\d+\.\d+) -> <IP>".parse().unwrap(),
+                r"port (?P<port>\d+) -> port=<PORT>".parse().unwrap(),
+            ];
+            let (line, params) = apply(&masks, "connected to 10.0.0.1 port 8080".to_string());
+            assert_eq!(line, "connected to <IP> port=<PORT>");
+            assert_eq!(
+                params,
+                BTreeMap::from([
+                    ("ip".to_string(), "10.0.0.1".to_string()),
+                    ("port".to_string(), "8080".to_string()),
+                ])
+            );
+        }
+
+        #[test]
+        fn test_chained() {
+            let first: Vec<Mask> = vec![r"\d+ -> <NUM>".parse().unwrap()];
+            let second: Vec<Mask> = vec![r"<NUM>s -> <DURATION>".parse().unwrap()];
+            let (line, _) = apply(first.iter().chain(&second), "took 30s".to_string());
+            assert_eq!(line, "took <DURATION>");
+        }
+
+        #[test]
+        fn test_named() {
+            let masks: Vec<Mask> = vec![r"ip=\d+\.\d+\.\d+\.\d+".parse().unwrap()];
+            let (line, _) = apply(&masks, "connected to 10.0.0.1".to_string());
+            assert_eq!(line, "connected to <IP>");
+            assert!("=\\d+".parse::<Mask>().is_err());
+            assert!("ip addr=\\d+".parse::<Mask>().is_err());
+        }
+
+        #[test]
+        fn test_invalid() {
+            assert!("no arrow".parse::<Mask>().is_err());
+            assert!(r"\d+ -> ".parse::<Mask>().is_err());
+            assert!(r"( -> <X>".parse::<Mask>().is_err());
+        }
+    }
+
+    mod load {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let path = std::env::temp_dir().join(format!("logu-masks-{}", std::process::id()));
+            fs::write(&path, "# addresses\n\n\\d+\\.\\d+\\.\\d+\\.\\d+ -> <IP>\n").unwrap();
+            let masks = load(&path).unwrap();
+            assert_eq!(apply(&masks, "from 10.0.0.1".to_string()).0, "from <IP>");
+
+            fs::write(&path, "\\d+ -> <NUM>\nno arrow\n").unwrap();
+            let err = load(&path).unwrap_err().to_string();
+            assert!(err.contains(":2: expected"), "{}", err);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    mod check {
+        use super::*;
+
+        #[test]
+        fn test() {
+            let content = "# comment
+\\d+ -> <NUM>
+( -> <X>
+\\S+ -> <WORD>
+x* -> <X>
+\\d+\\.\\d+ -> <*>
+[a-f0-9]{8} -> <ID>
+";
+            let issues: Vec<String> = check(content, &["<*>"])
+                .iter()
+                .map(|issue| issue.to_string())
+                .collect();
+            assert_eq!(issues.len(), 4, "{:?}", issues);
+            assert!(issues[0].starts_with("line 3: error: "));
+            assert_eq!(
+                issues[1],
+                "line 4: warning: pattern matches arbitrary words"
+            );
+            assert_eq!(
+                issues[2],
+                "line 5: warning: pattern matches the empty string"
+            );
+            assert!(issues[3].starts_with("line 6: warning: replacement `<*>`"));
+        }
+
+        #[test]
+        fn test_wildcards() {
+            let content = "\\d+ -> <NUM>\n[a-f0-9]{8} -> <ID>\n";
+            let issues: Vec<String> = check(content, &["<*>", "<NUM>", "<ID>"])
+                .iter()
+                .map(|issue| issue.to_string())
+                .collect();
+            assert_eq!(issues.len(), 2, "{:?}", issues);
+            assert!(issues[0].starts_with("line 1: warning: replacement `<NUM>`"));
+            assert!(issues[1].starts_with("line 2: warning: replacement `<ID>`"));
+        }
+    }
+}
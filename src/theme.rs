@@ -0,0 +1,49 @@
+use clap::ValueEnum;
+use promkit::crossterm::style::Color;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ThemeName {
+    Dark,
+    Light,
+    /// No colors at all.
+    Mono,
+}
+
+/// Colors consulted while rendering clusters.
+/// `None` leaves the terminal's default color.
+#[derive(Clone, Copy, Debug)]
+pub struct Theme {
+    /// Literal tokens of a template.
+    pub literal: Option<Color>,
+    /// Wildcard tokens (`--param-str` and `<NUM>`).
+    pub wildcard: Option<Color>,
+    /// Templates containing an error level with `--level-colors`.
+    pub error: Option<Color>,
+    /// Templates containing a warning level with `--level-colors`.
+    pub warn: Option<Color>,
+}
+
+impl From<ThemeName> for Theme {
+    fn from(name: ThemeName) -> Self {
+        match name {
+            ThemeName::Dark => Self {
+                literal: None,
+                wildcard: Some(Color::DarkGrey),
+                error: Some(Color::Red),
+                warn: Some(Color::Yellow),
+            },
+            ThemeName::Light => Self {
+                literal: None,
+                wildcard: Some(Color::Grey),
+                error: Some(Color::DarkRed),
+                warn: Some(Color::DarkYellow),
+            },
+            ThemeName::Mono => Self {
+                literal: None,
+                wildcard: None,
+                error: None,
+                warn: None,
+            },
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::{io, path::Path, str::FromStr};
+
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::mpsc,
+};
+use tokio_util::{codec::LengthDelimitedCodec, sync::CancellationToken};
+
+/// Size of the length prefix of `length-prefix` without `=BYTES`.
+const DEFAULT_PREFIX_BYTES: usize = 4;
+
+/// How the messages of a connection are delimited,
+/// parsed from `newline`, `null` or `length-prefix[=BYTES]`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// Newline-delimited lines.
+    Newline,
+    /// NUL-delimited messages, which may contain newlines.
+    Null,
+    /// Messages preceded by their length in bytes,
+    /// as a big-endian unsigned integer of this many bytes (1 to 8).
+    LengthPrefix(usize),
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "newline" => Ok(Self::Newline),
+            None if s == "null" => Ok(Self::Null),
+            None if s == "length-prefix" => Ok(Self::LengthPrefix(DEFAULT_PREFIX_BYTES)),
+            Some(("length-prefix", bytes)) => match bytes.parse() {
+                Ok(bytes @ 1..=8) => Ok(Self::LengthPrefix(bytes)),
+                _ => Err(format!(
+                    "prefix size must be 1 to 8 bytes, but got {:?}",
+                    bytes
+                )),
+            },
+            _ => Err(format!(
+                "expected one of newline, null, length-prefix[=BYTES], but got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Send each segment of the connection ending with `delimiter` until it is closed.
+async fn read_segments(
+    stream: UnixStream,
+    delimiter: u8,
+    tx: mpsc::Sender<Vec<u8>>,
+    canceled: CancellationToken,
+) {
+    let mut reader = BufReader::new(stream).split(delimiter);
+    loop {
+        tokio::select! {
+            _ = canceled.cancelled() => return,
+            ret = reader.next_segment() => match ret {
+                Ok(Some(line)) => {
+                    if tx.send(line).await.is_err() {
+                        return;
+                    }
+                }
+                _ => return,
+            },
+        }
+    }
+}
+
+/// Send each length-prefixed frame of the connection until it is closed,
+/// or a frame is malformed or too large.
+async fn read_frames(
+    stream: UnixStream,
+    prefix_bytes: usize,
+    tx: mpsc::Sender<Vec<u8>>,
+    canceled: CancellationToken,
+) {
+    let mut frames = LengthDelimitedCodec::builder()
+        .length_field_length(prefix_bytes)
+        .new_read(stream);
+    loop {
+        tokio::select! {
+            _ = canceled.cancelled() => return,
+            ret = frames.next() => match ret {
+                Some(Ok(frame)) => {
+                    if tx.send(frame.to_vec()).await.is_err() {
+                        return;
+                    }
+                }
+                _ => return,
+            },
+        }
+    }
+}
+
+/// Listen on a Unix domain socket at `path`
+/// and return a channel receiving the messages of all connections, delimited by `framing`.
+///
+/// The socket file is not removed here; see `remove`.
+pub fn listen(
+    path: &Path,
+    framing: Framing,
+    canceled: CancellationToken,
+) -> io::Result<mpsc::Receiver<Vec<u8>>> {
+    let listener = UnixListener::bind(path)?;
+    let (tx, rx) = mpsc::channel(1024);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = canceled.cancelled() => break,
+                ret = listener.accept() => {
+                    // A failed accept only concerns that connection.
+                    if let Ok((stream, _)) = ret {
+                        let (tx, canceled) = (tx.clone(), canceled.clone());
+                        match framing {
+                            Framing::Newline => {
+                                tokio::spawn(read_segments(stream, b'\n', tx, canceled))
+                            }
+                            Framing::Null => tokio::spawn(read_segments(stream, b'\0', tx, canceled)),
+                            Framing::LengthPrefix(prefix_bytes) => {
+                                tokio::spawn(read_frames(stream, prefix_bytes, tx, canceled))
+                            }
+                        };
+                    }
+                }
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// Remove the socket file created by `listen`.
+pub fn remove(path: &Path) {
+    // Nothing to do if it is already gone.
+    let _ = std::fs::remove_file(path);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod listen {
+        use tokio::{
+            io::AsyncWriteExt,
+            time::{timeout, Duration},
+        };
+
+        use super::*;
+
+        #[tokio::test]
+        async fn test() {
+            let path = std::env::temp_dir().join(format!("logu-{}.sock", std::process::id()));
+            let canceler = CancellationToken::new();
+            let mut rx = listen(&path, Framing::Newline, canceler.clone()).unwrap();
+
+            let mut first = UnixStream::connect(&path).await.unwrap();
+            let mut second = UnixStream::connect(&path).await.unwrap();
+            first.write_all(b"from first\n").await.unwrap();
+            second.write_all(b"from second\n").await.unwrap();
+
+            let mut lines = Vec::new();
+            for _ in 0..2 {
+                let line = timeout(Duration::from_secs(5), rx.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                lines.push(String::from_utf8(line).unwrap());
+            }
+            lines.sort();
+            assert_eq!(lines, vec!["from first", "from second"]);
+
+            canceler.cancel();
+            remove(&path);
+            assert!(!path.exists());
+        }
+
+        #[tokio::test]
+        async fn test_framing() {
+            for (framing, input) in [
+                (Framing::Null, &b"first\nline\0second\0"[..]),
+                (
+                    Framing::LengthPrefix(2),
+                    &b"\x00\x0afirst\nline\x00\x06second"[..],
+                ),
+            ] {
+                let path = std::env::temp_dir().join(format!(
+                    "logu-{}-{:?}.sock",
+                    std::process::id(),
+                    framing
+                ));
+                let canceler = CancellationToken::new();
+                let mut rx = listen(&path, framing, canceler.clone()).unwrap();
+
+                let mut stream = UnixStream::connect(&path).await.unwrap();
+                stream.write_all(input).await.unwrap();
+                for expected in ["first\nline", "second"] {
+                    let message = timeout(Duration::from_secs(5), rx.recv())
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(String::from_utf8(message).unwrap(), expected);
+                }
+
+                canceler.cancel();
+                remove(&path);
+            }
+        }
+    }
+
+    mod framing {
+        use super::*;
+
+        #[test]
+        fn test_from_str() {
+            assert_eq!("null".parse(), Ok(Framing::Null));
+            assert_eq!("length-prefix".parse(), Ok(Framing::LengthPrefix(4)));
+            assert_eq!("length-prefix=2".parse(), Ok(Framing::LengthPrefix(2)));
+            assert!("length-prefix=9".parse::<Framing>().is_err());
+            assert!("newline=1".parse::<Framing>().is_err());
+        }
+    }
+}
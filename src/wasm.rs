@@ -0,0 +1,72 @@
+//! WebAssembly bindings for `Drain`.
+//!
+//! Build with `--no-default-features --features wasm`
+//! so that the TUI and async dependencies are left out.
+//!
+//! ```js
+//! const drain = new WasmDrain(undefined, 2, 0.4, 100, "<*>");
+//! drain.train("connected to 10.0.0.1"); // "connected to 10.0.0.1"
+//! drain.train("connected to 10.0.0.2"); // "connected to <*>"
+//! drain.clusters_json(); // '[{"cluster_id":1,"template":"connected to <*>","size":2}]'
+//! ```
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::drain::Drain;
+
+#[derive(Serialize)]
+struct ClusterJson {
+    cluster_id: usize,
+    template: String,
+    size: usize,
+}
+
+#[wasm_bindgen]
+pub struct WasmDrain {
+    drain: Drain,
+}
+
+#[wasm_bindgen]
+impl WasmDrain {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        max_clusters: Option<usize>,
+        max_node_depth: usize,
+        sim_th: f32,
+        max_children: usize,
+        param_str: String,
+    ) -> Result<WasmDrain, JsError> {
+        let drain = Drain::new(
+            max_clusters,
+            max_node_depth,
+            sim_th,
+            max_children,
+            param_str,
+        )
+        .map_err(|e| JsError::new(&e.to_string()))?;
+        Ok(Self { drain })
+    }
+
+    /// Train on the log message and return the template of its cluster.
+    pub fn train(&mut self, log_message: &str) -> String {
+        self.drain.train(log_message).to_string()
+    }
+
+    /// Return the clusters as a JSON array of
+    /// `{"cluster_id": number, "template": string, "size": number}`.
+    pub fn clusters_json(&self) -> String {
+        let clusters: Vec<ClusterJson> = self
+            .drain
+            .clusters_by_id()
+            .into_iter()
+            .map(|cluster| ClusterJson {
+                cluster_id: cluster.cluster_id,
+                template: cluster.to_string(),
+                size: cluster.size,
+            })
+            .collect();
+        // Serializing plain strings and numbers cannot fail.
+        serde_json::to_string(&clusters).unwrap()
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    mem,
+    path::{Path, PathBuf},
+};
+
+use glob::Pattern;
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncSeekExt, BufReader},
+    sync::mpsc,
+    time::{self, Duration},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Interval to check a file for new lines once its end is reached.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the file name of `path` matches `pattern`.
+fn matches(pattern: &Pattern, path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| pattern.matches(name))
+}
+
+/// Follow `path` and send each complete line without the trailing newline.
+///
+/// Reading starts at the end of the file with `from_end`,
+/// otherwise at its beginning. A file that shrinks is assumed
+/// to be truncated and is read again from the beginning.
+async fn tail(
+    path: PathBuf,
+    from_end: bool,
+    tx: mpsc::Sender<Vec<u8>>,
+    canceled: CancellationToken,
+) -> std::io::Result<()> {
+    let mut file = File::open(&path).await?;
+    let mut pos = if from_end {
+        file.seek(SeekFrom::End(0)).await?
+    } else {
+        0
+    };
+    let mut reader = BufReader::new(file);
+    // A partial line is kept until the rest of it is written.
+    let mut buf = Vec::new();
+
+    loop {
+        let n = reader.read_until(b'\n', &mut buf).await?;
+        pos += n as u64;
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+            if tx.send(mem::take(&mut buf)).await.is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+
+        if n == 0 && tokio::fs::metadata(&path).await?.len() < pos {
+            reader.seek(SeekFrom::Start(0)).await?;
+            pos = 0;
+            buf.clear();
+        }
+        tokio::select! {
+            _ = canceled.cancelled() => return Ok(()),
+            _ = time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// Tail every file in `dir` whose name matches `pattern`
+/// and return a channel receiving their lines.
+///
+/// Files present now are followed from their end,
+/// files created later from their beginning.
+/// A file stops being followed once it is removed or renamed away.
+pub fn watch_dir(
+    dir: &Path,
+    pattern: Pattern,
+    canceled: CancellationToken,
+) -> anyhow::Result<mpsc::Receiver<Vec<u8>>> {
+    let (tx, rx) = mpsc::channel(1024);
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            let _ = event_tx.send(event);
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    let mut tailing: HashMap<PathBuf, CancellationToken> = HashMap::new();
+    let root = canceled.clone();
+    let spawn_tail =
+        move |tailing: &mut HashMap<PathBuf, CancellationToken>, path: PathBuf, from_end| {
+            let token = root.child_token();
+            tailing.insert(path.clone(), token.clone());
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                // The file may be gone before it is opened; the watcher handles the rest.
+                let _ = tail(path, from_end, tx, token).await;
+            });
+        };
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && matches(&pattern, &path) {
+            spawn_tail(&mut tailing, path, true);
+        }
+    }
+
+    tokio::spawn(async move {
+        // Keep the watcher alive as long as events are handled.
+        let _watcher = watcher;
+        loop {
+            tokio::select! {
+                _ = canceled.cancelled() => break,
+                Some(event) = event_rx.recv() => {
+                    for path in event.paths {
+                        if !matches(&pattern, &path) {
+                            continue;
+                        }
+                        // A file created again under a followed name, e.g. after rotation,
+                        // is a new file and is read from its beginning.
+                        let created = matches!(event.kind, EventKind::Create(_));
+                        if path.is_file() {
+                            if created || !tailing.contains_key(&path) {
+                                if let Some(token) = tailing.remove(&path) {
+                                    token.cancel();
+                                }
+                                spawn_tail(&mut tailing, path, false);
+                            }
+                        } else if let Some(token) = tailing.remove(&path) {
+                            token.cancel();
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod watch_dir {
+        use std::{fs, io::Write};
+
+        use super::*;
+
+        async fn recv(rx: &mut mpsc::Receiver<Vec<u8>>) -> String {
+            let line = time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            String::from_utf8(line).unwrap()
+        }
+
+        #[tokio::test]
+        async fn test() {
+            let dir = std::env::temp_dir().join(format!("logu-watch-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("a.log"), "old line\n").unwrap();
+
+            let canceler = CancellationToken::new();
+            let mut rx = watch_dir(&dir, Pattern::new("*.log").unwrap(), canceler.clone()).unwrap();
+            // Let the tailing task reach the end of the existing file.
+            time::sleep(Duration::from_millis(200)).await;
+
+            let mut file = fs::OpenOptions::new()
+                .append(true)
+                .open(dir.join("a.log"))
+                .unwrap();
+            file.write_all(b"appended line\n").unwrap();
+            assert_eq!(recv(&mut rx).await, "appended line");
+
+            fs::write(dir.join("ignored.txt"), "ignored line\n").unwrap();
+            fs::write(dir.join("b.log"), "created line\n").unwrap();
+            assert_eq!(recv(&mut rx).await, "created line");
+
+            canceler.cancel();
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+/// Send the current snapshot, then every new one until the client leaves.
+async fn serve_client(
+    stream: TcpStream,
+    mut snapshots: watch::Receiver<String>,
+    canceled: CancellationToken,
+) -> tokio_tungstenite::tungstenite::Result<()> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+    let snapshot = snapshots.borrow_and_update().clone();
+    ws.send(Message::text(snapshot)).await?;
+    loop {
+        tokio::select! {
+            _ = canceled.cancelled() => break,
+            ret = snapshots.changed() => {
+                if ret.is_err() {
+                    break;
+                }
+                let snapshot = snapshots.borrow_and_update().clone();
+                ws.send(Message::text(snapshot)).await?;
+            }
+            // Incoming messages are ignored; only watch for the client leaving.
+            msg = ws.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    ws.close(None).await
+}
+
+/// Broadcast the JSON snapshots published on `snapshots`
+/// to every WebSocket client connecting to `listener`.
+pub fn serve(
+    listener: TcpListener,
+    snapshots: watch::Receiver<String>,
+    canceled: CancellationToken,
+) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = canceled.cancelled() => break,
+                ret = listener.accept() => {
+                    // A failed accept only concerns that connection.
+                    if let Ok((stream, _)) = ret {
+                        let snapshots = snapshots.clone();
+                        let canceled = canceled.clone();
+                        tokio::spawn(async move {
+                            // A broken connection only ends that client.
+                            let _ = serve_client(stream, snapshots, canceled).await;
+                        });
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod serve {
+        use tokio::time::{timeout, Duration};
+        use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+        use super::*;
+
+        async fn next(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> String {
+            timeout(Duration::from_secs(5), ws.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap()
+                .into_text()
+                .unwrap()
+                .to_string()
+        }
+
+        #[tokio::test]
+        async fn test() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (tx, rx) = watch::channel(String::from("[]"));
+            let canceler = CancellationToken::new();
+            serve(listener, rx, canceler.clone());
+
+            let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+                .await
+                .unwrap();
+            assert_eq!(next(&mut ws).await, "[]");
+
+            tx.send(String::from(r#"[{"cluster_id":1}]"#)).unwrap();
+            assert_eq!(next(&mut ws).await, r#"[{"cluster_id":1}]"#);
+            canceler.cancel();
+        }
+    }
+}
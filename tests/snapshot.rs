@@ -0,0 +1,27 @@
+//! Snapshot tests running `Drain` over checked-in log samples.
+//!
+//! Changes to the clustering behavior show up as snapshot diffs.
+//! Review them with `cargo insta review` (or rerun with `INSTA_UPDATE=always`).
+
+use std::fs;
+
+use logu::drain::Drain;
+
+fn templates(path: &str) -> String {
+    let mut drain = Drain::default();
+    for line in fs::read_to_string(path).unwrap().lines() {
+        drain.train(line);
+    }
+
+    drain
+        .clusters_by_id()
+        .iter()
+        .map(|cluster| format!("{:>4} {}", cluster.size, cluster))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[test]
+fn sample() {
+    insta::assert_snapshot!(templates("tests/data/sample.log"));
+}